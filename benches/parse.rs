@@ -0,0 +1,56 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Compares the cost of parsing lines into an owned `Message` with parsing
+//! them in place into a `MessageRef`. Run with `cargo bench --bench parse`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize,
+                Criterion};
+
+use foxy_ircd::{Message, MessageRef, ParseMode};
+
+/// A mix of the sort of lines clients send.
+const LINES: &[&[u8]] = &[
+    b"PING :irc.example.com",
+    b"PRIVMSG #den :hello, everyone",
+    b"@time=2020-01-01T00:00:00.000Z :fox!~u@host PRIVMSG #den :hi",
+    b"MODE #den +ov fox vixen",
+    b"JOIN #den,#burrow key",
+    b"NOTICE  vixen  :double  spaced ",
+    b"FOO 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 :16",
+];
+
+fn parse(c: &mut Criterion) {
+    let lines: Vec<Vec<u8>> = LINES.iter().map(|x| x.to_vec()).collect();
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("Message", |b| b.iter_batched_ref(
+        || lines.clone(),
+        |lines| for line in lines.iter() {
+            black_box(Message::parse(line, ParseMode::Lenient));
+        },
+        BatchSize::SmallInput));
+    group.bench_function("MessageRef", |b| b.iter_batched_ref(
+        || lines.clone(),
+        |lines| for line in lines.iter_mut() {
+            black_box(MessageRef::parse(line, ParseMode::Lenient));
+        },
+        BatchSize::SmallInput));
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
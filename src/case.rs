@@ -15,19 +15,225 @@
  * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
  */
 
+//! Case folding. IRC compares nicknames and channel names without regard to
+//! case, but what counts as "case" depends on the `CASEMAPPING` in effect.
+//! The `upcase` and `downcase` functions are for protocol tokens, such as
+//! command names, which are always ASCII. Names go through a `CaseMapping`.
+
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    fmt::{Debug, Display, Formatter},
+    hash::{Hash, Hasher},
+};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::*;
+
 /// Upcase a byte.
 ///
-/// Note: We use the "ascii" case mapping.
+/// Note: This uses the "ascii" case mapping. Use a `CaseMapping` for names.
 pub fn upcase(b: u8) -> u8 {
-    if b >= b'a' && b <= b'z' { b & !0x20 }
+    if b.is_ascii_lowercase() { b & !0x20 }
     else { b }
 }
 
 /// Downcase a byte.
 ///
-/// Note: We use the "ascii" case mapping.
+/// Note: This uses the "ascii" case mapping. Use a `CaseMapping` for names.
 pub fn downcase(b: u8) -> u8 {
-    if b >= b'A' && b <= b'Z' { b | 0x20 }
+    if b.is_ascii_uppercase() { b | 0x20 }
     else { b }
 }
 
+/// The ways of deciding whether two names are "the same", as advertised in
+/// the `CASEMAPPING` token of `RPL_ISUPPORT`.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum CaseMapping {
+    /// Only `A-Z` and `a-z` are equivalent.
+    #[default]
+    Ascii,
+    /// As `Ascii`, but `[]\~` are also the uppercase forms of `{}|^`. This
+    /// is what RFC 1459 said, and what most networks use.
+    Rfc1459,
+    /// As `Rfc1459`, but without `~` and `^`.
+    StrictRfc1459,
+    /// Names are UTF-8, and fold the way PRECIS (RFC 8265) folds usernames:
+    /// fullwidth forms are narrowed, everything is lowercased with full
+    /// Unicode rules, and the result is put into Normalization Form C. Bytes
+    /// that aren't valid UTF-8 fold as in `Ascii`.
+    Precis,
+}
+
+/// Map a fullwidth form to its ordinary equivalent. This is the "width
+/// mapping" step of PRECIS.
+pub fn narrow(c: char) -> char {
+    match c as u32 {
+        x @ 0xFF01 ..= 0xFF5E
+            => std::char::from_u32(x - 0xFF01 + 0x21).unwrap(),
+        0xFFE0 => '\u{00A2}',
+        0xFFE1 => '\u{00A3}',
+        0xFFE2 => '\u{00AC}',
+        0xFFE3 => '\u{00AF}',
+        0xFFE4 => '\u{00A6}',
+        0xFFE5 => '\u{00A5}',
+        0xFFE6 => '\u{20A9}',
+        _ => c,
+    }
+}
+
+impl CaseMapping {
+    /// Look up a mapping by its `CASEMAPPING` name.
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            "rfc7613" | "precis" => Some(CaseMapping::Precis),
+            _ => None,
+        }
+    }
+    /// The name of this mapping, as advertised in `CASEMAPPING`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459",
+            CaseMapping::Precis => "rfc7613",
+        }
+    }
+    /// Add the `CASEMAPPING` token to `RPL_ISUPPORT`.
+    pub fn advertise(&self, isupport: &mut ISupport) {
+        isupport.set("CASEMAPPING", Some(self.name().as_bytes()));
+    }
+    /// Fold a single byte. For `Precis`, this only folds ASCII; use `fold`
+    /// for whole names.
+    pub fn fold_byte(&self, b: u8) -> u8 {
+        match (self, b) {
+            (CaseMapping::Rfc1459, b'~') => b'^',
+            (CaseMapping::Rfc1459, b'[')
+            | (CaseMapping::StrictRfc1459, b'[') => b'{',
+            (CaseMapping::Rfc1459, b']')
+            | (CaseMapping::StrictRfc1459, b']') => b'}',
+            (CaseMapping::Rfc1459, b'\\')
+            | (CaseMapping::StrictRfc1459, b'\\') => b'|',
+            _ => downcase(b),
+        }
+    }
+    /// Fold a name. Two names are the same name if they fold the same.
+    pub fn fold<'a>(&self, name: &'a [u8]) -> Cow<'a, [u8]> {
+        if *self == CaseMapping::Precis && !name.is_ascii() {
+            if let Ok(name) = std::str::from_utf8(name) {
+                let folded: String = name.chars().map(narrow)
+                    .flat_map(char::to_lowercase).nfc().collect();
+                return Cow::Owned(folded.into_bytes())
+            }
+        }
+        if name.iter().all(|x| self.fold_byte(*x) == *x) {
+            Cow::Borrowed(name)
+        }
+        else {
+            Cow::Owned(name.iter().map(|x| self.fold_byte(*x)).collect())
+        }
+    }
+    /// Are these the same name?
+    pub fn eq(&self, a: &[u8], b: &[u8]) -> bool {
+        self.fold(a) == self.fold(b)
+    }
+}
+
+/// A nickname, channel name, or other name that compares without regard to
+/// case. `Eq`, `Ord` and `Hash` all use the folded form, so a `Name` can key
+/// a registry directly. All the `Name`s in a registry must use the same
+/// `CaseMapping`.
+#[derive(Clone)]
+pub struct Name {
+    raw: Box<[u8]>,
+    folded: Box<[u8]>,
+}
+
+impl Name {
+    pub fn new(raw: &[u8], mapping: CaseMapping) -> Name {
+        Name {
+            folded: mapping.fold(raw).into_owned().into_boxed_slice(),
+            raw: raw.into(),
+        }
+    }
+    /// The name as it was given, case intact.
+    pub fn as_bytes(&self) -> &[u8] { &self.raw }
+    /// The folded form of the name.
+    pub fn folded(&self) -> &[u8] { &self.folded }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool { self.folded == other.folded }
+}
+
+impl Eq for Name {}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering { self.folded.cmp(&other.folded) }
+}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, h: &mut H) { self.folded.hash(h) }
+}
+
+impl Debug for Name {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Debug::fmt(&String::from_utf8_lossy(&self.raw), fmt)
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Display::fmt(&String::from_utf8_lossy(&self.raw), fmt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    #[test]
+    fn mappings() {
+        use CaseMapping::*;
+        let cases: &[(CaseMapping, &str, &str, bool)] = &[
+            (Ascii, "FoxBot", "foxbot", true),
+            (Ascii, "Fox[m]", "fox{m}", false),
+            (Rfc1459, "Fox[m]", "fox{m}", true),
+            (Rfc1459, "a\\b~", "A|B^", true),
+            (StrictRfc1459, "Fox[m]", "fox{m}", true),
+            (StrictRfc1459, "a~", "a^", false),
+            (Precis, "Ärger", "ärger", true),
+            (Precis, "ＦＯＸ", "fox", true),
+            // precomposed vs combining diaeresis
+            (Precis, "A\u{308}", "\u{E4}", true),
+            (Precis, "Fox[m]", "fox{m}", false),
+            (Ascii, "Ärger", "ärger", false),
+        ];
+        for &(mapping, a, b, expected) in cases {
+            assert_eq!(mapping.eq(a.as_bytes(), b.as_bytes()), expected,
+                       "{:?}: {:?} vs {:?}", mapping, a, b);
+        }
+        for name in &["ascii", "rfc1459", "strict-rfc1459", "rfc7613"] {
+            assert_eq!(CaseMapping::from_name(name).unwrap().name(), *name);
+        }
+    }
+    #[test]
+    fn names() {
+        let mut registry = HashMap::new();
+        registry.insert(Name::new(b"[Fox]", CaseMapping::Rfc1459), 1);
+        let found = Name::new(b"{fOX}", CaseMapping::Rfc1459);
+        assert_eq!(registry.get(&found), Some(&1));
+        let (key, _) = registry.iter().next().unwrap();
+        assert_eq!(key.as_bytes(), b"[Fox]");
+        assert_eq!(key.folded(), b"{fox}");
+    }
+}
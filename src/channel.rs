@@ -0,0 +1,979 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Channels: who is in them, what their topics are, and the commands that
+//! join, leave, look at, and talk in them.
+//!
+//! `Channels` holds every channel on the server. Its command methods don't
+//! send anything themselves; they return an `Outbox` of messages for the
+//! caller to deliver, which includes relaying the command to the channel's
+//! members and any replies (or error numerics) for the client that sent it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use crate::*;
+use crate::mask::BanTarget;
+use numeric::*;
+
+mod list;
+pub use list::ListQuery;
+mod mode;
+pub use mode::*;
+mod prefix;
+pub use prefix::*;
+
+/// The longest topic we keep, in bytes. Longer topics are truncated.
+pub const MAX_TOPIC_LEN: usize = 390;
+
+/// Messages to send, each to the client with the given nick.
+pub type Outbox = Vec<(Name, Message)>;
+
+/// Add the tokens describing our channels to `RPL_ISUPPORT`.
+pub fn advertise(isupport: &mut ISupport, prefixes: &Prefixes) {
+    isupport.set("TOPICLEN", Some(MAX_TOPIC_LEN.to_string().as_bytes()));
+    isupport.set("ELIST", Some(ListQuery::ELIST));
+    mode::advertise(isupport, prefixes);
+}
+
+/// A channel's topic, and who set it when.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Topic {
+    pub text: Vec<u8>,
+    /// The `nick!user@host` of whoever set it.
+    pub setter: Vec<u8>,
+    /// When it was set, in seconds since the epoch.
+    pub time: u64,
+}
+
+/// What we know about a client in a particular channel.
+#[derive(Clone,Debug)]
+pub struct Member {
+    /// Who the member is.
+    pub user: User,
+    /// The prefix modes the member has, most powerful first.
+    modes: Vec<u8>,
+}
+
+impl Member {
+    pub fn new(user: User) -> Member {
+        Member { user, modes: Vec::new() }
+    }
+}
+
+/// A channel.
+#[derive(Clone,Debug)]
+pub struct Channel {
+    pub name: Name,
+    /// When it was created, in seconds since the epoch.
+    pub created: u64,
+    pub topic: Option<Topic>,
+    /// The members, by nick.
+    pub members: BTreeMap<Name, Member>,
+    pub modes: ChannelModes,
+}
+
+impl Channel {
+    fn new(name: Name, created: u64) -> Channel {
+        Channel {
+            name, created,
+            topic: None,
+            members: BTreeMap::new(),
+            modes: ChannelModes::default(),
+        }
+    }
+    pub fn is_member(&self, nick: &Name) -> bool {
+        self.members.contains_key(nick)
+    }
+    /// Can this client see that the channel exists? Secret (`+s`) channels
+    /// are hidden from non-members.
+    pub fn is_visible_to(&self, nick: &Name) -> bool {
+        !self.modes.is_set(b's') || self.is_member(nick)
+    }
+    /// Is this client banned, and not exempt? Mutes (`$m:`) don't count.
+    pub fn is_banned(&self, candidate: &Candidate) -> bool {
+        self.modes.list_matches(b'b', candidate).any(|x| !x.mask.is_mute())
+            && self.modes.list_matches(b'e', candidate).next().is_none()
+    }
+    /// Is this client banned or muted, and not exempt?
+    pub fn is_muted(&self, candidate: &Candidate) -> bool {
+        self.modes.list_matches(b'b', candidate).next().is_some()
+            && self.modes.list_matches(b'e', candidate).next().is_none()
+    }
+    /// If this client may not join, returns the error numeric and its text.
+    fn join_error(&self, candidate: &Candidate, key: Option<&[u8]>)
+                  -> Option<(u32, &'static [u8])> {
+        if self.is_banned(candidate) {
+            Some((ERR_BANNEDFROMCHAN, b"Cannot join channel (+b)"))
+        }
+        else if self.modes.is_set(b'i')
+        && self.modes.list_matches(b'I', candidate).next().is_none() {
+            Some((ERR_INVITEONLYCHAN, b"Cannot join channel (+i)"))
+        }
+        else if self.modes.key.is_some() && self.modes.key.as_deref() != key {
+            Some((ERR_BADCHANNELKEY, b"Cannot join channel (+k)"))
+        }
+        else if self.modes.limit
+            .map(|x| self.members.len() as u64 >= x).unwrap_or(false) {
+            Some((ERR_CHANNELISFULL, b"Cannot join channel (+l)"))
+        }
+        else { None }
+    }
+    /// Checks whether this client may send `text` to the channel. If not,
+    /// returns the text for `ERR_CANNOTSENDTOCHAN`.
+    pub fn check_send(&self, candidate: &Candidate, text: &[u8])
+                      -> Result<(), &'static str> {
+        let voiced = self.members.get(&candidate.user.nick)
+            .map(|x| x.is_voiced());
+        match voiced {
+            None if self.modes.is_set(b'n')
+                => Err("Cannot send to channel (no external messages)"),
+            Some(false) | None if self.modes.is_set(b'm')
+                => Err("Cannot send to channel (+m)"),
+            Some(false) | None if self.is_muted(candidate)
+                => Err("Cannot send to channel (you're banned)"),
+            _ if self.modes.is_set(b'C') && ctcp::is_non_action_ctcp(text)
+                => Err("Cannot send CTCP to channel (+C)"),
+            _ => Ok(()),
+        }
+    }
+    /// The channel type marker in `RPL_NAMREPLY`.
+    fn names_marker(&self) -> &'static [u8] {
+        if self.modes.is_set(b's') { b"@" }
+        else if self.modes.is_set(b'p') { b"*" }
+        else { b"=" }
+    }
+    /// Queue `message` for every member.
+    fn broadcast(&self, message: &Message, outbox: &mut Outbox) {
+        for nick in self.members.keys() {
+            outbox.push((nick.clone(), message.clone()));
+        }
+    }
+    /// Queue `RPL_TOPIC` and `RPL_TOPICWHOTIME`, or `RPL_NOTOPIC`.
+    fn topic_replies(&self, server: &[u8], user: &User, outbox: &mut Outbox)
+                     -> Result<(), &'static str> {
+        let nick = user.nick.as_bytes();
+        let name = self.name.as_bytes();
+        match self.topic.as_ref() {
+            None => {
+                outbox.push((user.nick.clone(),
+                             reply(server, RPL_NOTOPIC, nick,
+                                   &[name, b"No topic is set"])?));
+            },
+            Some(topic) => {
+                outbox.push((user.nick.clone(),
+                             reply(server, RPL_TOPIC, nick,
+                                   &[name, &topic.text])?));
+                outbox.push((user.nick.clone(),
+                             reply(server, RPL_TOPICWHOTIME, nick,
+                                   &[name, &topic.setter,
+                                     topic.time.to_string().as_bytes()])?));
+            },
+        }
+        Ok(())
+    }
+    /// Queue `RPL_NAMREPLY`s and `RPL_ENDOFNAMES`.
+    fn names_replies(&self, server: &[u8], user: &User, outbox: &mut Outbox)
+                     -> Result<(), &'static str> {
+        let nick = user.nick.as_bytes();
+        let name = self.name.as_bytes();
+        if self.is_visible_to(&user.nick) {
+            let is_member = self.is_member(&user.nick);
+            let mut text = Vec::new();
+            for (member, status) in self.members.iter() {
+                if !is_member && status.user.modes.is_set(b'i') { continue }
+                if !text.is_empty() { text.push(b' ') }
+                text.extend(status.prefixes(user.multi_prefix));
+                text.extend_from_slice(member.as_bytes());
+            }
+            if !text.is_empty() {
+                let source = Source::Server { name: server };
+                for message in Message::split_text(
+                    Some(&source), &Command::Numeric(RPL_NAMREPLY),
+                    &[nick, self.names_marker(), name], &text)? {
+                    outbox.push((user.nick.clone(), message));
+                }
+            }
+        }
+        outbox.push((user.nick.clone(),
+                     reply(server, RPL_ENDOFNAMES, nick,
+                           &[name, b"End of /NAMES list"])?));
+        Ok(())
+    }
+}
+
+/// Truncate a topic to `MAX_TOPIC_LEN` bytes, without cutting a UTF-8
+/// sequence in half.
+fn truncate_topic(text: &[u8]) -> &[u8] {
+    if text.len() <= MAX_TOPIC_LEN { return text }
+    let mut end = MAX_TOPIC_LEN;
+    while end > 0 && text[end] & 0xC0 == 0x80 { end -= 1 }
+    &text[..end]
+}
+
+/// Queue a numeric reply to `user`.
+pub(crate) fn queue_reply(server: &[u8], user: &User, numeric: u32,
+                          params: &[&[u8]], outbox: &mut Outbox)
+                          -> Result<(), &'static str> {
+    outbox.push((user.nick.clone(),
+                 reply(server, numeric, user.nick.as_bytes(), params)?));
+    Ok(())
+}
+
+/// A client, as ban matching sees it: extbans like `$j:` need to know about
+/// channels other than the one being checked.
+pub struct Candidate<'a> {
+    pub user: &'a User,
+    channels: &'a Channels,
+}
+
+impl BanTarget for Candidate<'_> {
+    fn source(&self) -> Source<'_> { self.user.source() }
+    fn ip(&self) -> Option<IpAddr> { self.user.ip }
+    fn account(&self) -> Option<&[u8]> { self.user.account.as_deref() }
+    fn realname(&self) -> &[u8] { &self.user.realname }
+    fn is_tls(&self) -> bool { self.user.tls }
+    fn is_in_channel(&self, channel: &[u8]) -> bool {
+        self.channels.get(channel)
+            .map(|x| x.is_member(&self.user.nick)).unwrap_or(false)
+    }
+}
+
+/// Every channel on the server.
+pub struct Channels {
+    /// Our server name, the source of numeric replies.
+    server: Vec<u8>,
+    mapping: CaseMapping,
+    prefixes: Prefixes,
+    channels: HashMap<Name, Channel>,
+}
+
+impl Channels {
+    /// Makes an empty registry, using every prefix mode.
+    pub fn new(server: &[u8], mapping: CaseMapping) -> Channels {
+        Channels::with_prefixes(server, mapping, Prefixes::default())
+    }
+    /// Makes an empty registry, using only the given prefix modes.
+    pub fn with_prefixes(server: &[u8], mapping: CaseMapping,
+                         prefixes: Prefixes) -> Channels {
+        Channels {
+            server: server.to_vec(),
+            mapping,
+            prefixes,
+            channels: HashMap::new(),
+        }
+    }
+    /// The prefix modes in use.
+    pub fn prefixes(&self) -> &Prefixes { &self.prefixes }
+    /// The key a channel name is stored under.
+    fn key(&self, name: &[u8]) -> Name {
+        match names::prepare_channel(name, self.mapping) {
+            Ok(x) => Name::new(&x, self.mapping),
+            Err(_) => Name::new(name, self.mapping),
+        }
+    }
+    /// Finds a channel by name.
+    pub fn get(&self, name: &[u8]) -> Option<&Channel> {
+        self.channels.get(&self.key(name))
+    }
+    /// This client, for checking against bans.
+    pub fn candidate<'a>(&'a self, user: &'a User) -> Candidate<'a> {
+        Candidate { user, channels: self }
+    }
+    /// Every channel, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+    /// Handles `JOIN`, `PART`, `TOPIC`, `NAMES`, `LIST`, `KICK`, `WHO`,
+    /// and `MODE`, `PRIVMSG` and `NOTICE` for channels. Returns `None` if
+    /// `message` is some other command.
+    pub fn handle(&mut self, user: &User, message: &Message, now: u64)
+                  -> Option<Result<Outbox, &'static str>> {
+        let command = match message.get_command() {
+            Command::Textual(x) => x,
+            Command::Numeric(_) => return None,
+        };
+        let min_params = match command {
+            b"JOIN" | b"PART" | b"TOPIC" | b"MODE" | b"WHO" => 1,
+            b"KICK" | b"PRIVMSG" | b"NOTICE" => 2,
+            b"NAMES" | b"LIST" => 0,
+            _ => return None,
+        };
+        let params: Vec<&[u8]> = (0 .. message.get_param_count())
+            .filter_map(|n| message.get_nth_param(n)).collect();
+        let target = params.first().copied().unwrap_or(b"");
+        // MODE for a nick is a user mode, and messages to a nick are
+        // private
+        if matches!(command, b"MODE" | b"PRIVMSG" | b"NOTICE")
+            && target.first()
+            .map(|x| !names::CHANNEL_PREFIXES.contains(x))
+            .unwrap_or(false) {
+            return None
+        }
+        // an empty target is no target at all
+        let given = if target.is_empty() { 0 } else { params.len() };
+        if given < min_params {
+            let mut outbox = Vec::new();
+            let (numeric, text): (u32, &[u8]) = match (command, given) {
+                // NOTICE never gets an error
+                (b"NOTICE", _) => return Some(Ok(outbox)),
+                (b"PRIVMSG", 0) => {
+                    (ERR_NORECIPIENT, b"No recipient given (PRIVMSG)")
+                },
+                (b"PRIVMSG", _) => (ERR_NOTEXTTOSEND, b"No text to send"),
+                _ => (ERR_NEEDMOREPARAMS, b"Not enough parameters"),
+            };
+            let params: &[&[u8]] = if numeric == ERR_NEEDMOREPARAMS {
+                &[command, text]
+            } else { &[text] };
+            return Some(queue_reply(&self.server, user, numeric, params,
+                                    &mut outbox).map(|_| outbox))
+        }
+        Some(match command {
+            b"JOIN" => self.join(user, params[0], params.get(1).copied(),
+                                 now),
+            b"PART" => self.part(user, params[0], params.get(1).copied()),
+            b"TOPIC" => self.topic(user, params[0], params.get(1).copied(),
+                                   now),
+            b"NAMES" => self.names(user, params.first().copied()),
+            b"MODE" => self.mode(user, params[0], &params[1..], now),
+            b"KICK" => self.kick(user, params[0], params[1],
+                                 params.get(2).copied()),
+            b"WHO" => self.who(user, params[0]),
+            b"PRIVMSG" | b"NOTICE" => self.message(user, command, params[0],
+                                                   params[1]),
+            _ => self.list(user, params.first().copied(), now),
+        })
+    }
+    /// `JOIN #a,#b key1,key2`. A channel that doesn't exist yet is created,
+    /// with the client given the most powerful status in use (usually
+    /// owner). `JOIN 0` leaves every channel.
+    pub fn join(&mut self, user: &User, targets: &[u8], keys: Option<&[u8]>,
+                now: u64) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        if targets == b"0" {
+            let mut joined: Vec<Vec<u8>> = self.channels.values()
+                .filter(|x| x.is_member(&user.nick))
+                .map(|x| x.name.as_bytes().to_vec()).collect();
+            joined.sort();
+            for name in joined.iter() {
+                outbox.extend(self.part(user, name, None)?);
+            }
+            return Ok(outbox)
+        }
+        let server = &self.server[..];
+        let mut keys = keys.unwrap_or(b"").split(|x| *x == b',');
+        for target in targets.split(|x| *x == b',') {
+            if target.is_empty() { continue }
+            let key = keys.next().filter(|x| !x.is_empty());
+            let name = match names::prepare_channel(target, self.mapping) {
+                Ok(x) => Name::new(&x, self.mapping),
+                Err(_) => {
+                    queue_reply(server, user, ERR_BADCHANMASK,
+                                &[target, b"Bad Channel Mask"], &mut outbox)?;
+                    continue
+                },
+            };
+            if let Some(channel) = self.channels.get(&name) {
+                if channel.is_member(&user.nick) { continue }
+                let error = channel.join_error(&self.candidate(user), key);
+                if let Some((numeric, text)) = error {
+                    queue_reply(server, user, numeric,
+                                &[channel.name.as_bytes(), text],
+                                &mut outbox)?;
+                    continue
+                }
+            }
+            let channel = self.channels.entry(name.clone())
+                .or_insert_with(|| Channel::new(name, now));
+            let mut member = Member::new(user.clone());
+            if channel.members.is_empty() {
+                member.set(self.prefixes.highest(), true);
+            }
+            channel.members.insert(user.nick.clone(), member);
+            let join = Message::assemble(Some(&user.source()),
+                                         &Command::Textual(b"JOIN"),
+                                         &[channel.name.as_bytes()], false)?;
+            channel.broadcast(&join, &mut outbox);
+            if channel.topic.is_some() {
+                channel.topic_replies(server, user, &mut outbox)?;
+            }
+            channel.names_replies(server, user, &mut outbox)?;
+        }
+        Ok(outbox)
+    }
+    /// `PART #a,#b :reason`. A channel is destroyed when its last member
+    /// leaves.
+    pub fn part(&mut self, user: &User, targets: &[u8],
+                reason: Option<&[u8]>) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        for target in targets.split(|x| *x == b',') {
+            if target.is_empty() { continue }
+            let key = self.key(target);
+            let server = &self.server[..];
+            let channel = match self.channels.get_mut(&key) {
+                Some(x) if x.is_member(&user.nick) => x,
+                Some(x) if x.is_visible_to(&user.nick) => {
+                    queue_reply(server, user, ERR_NOTONCHANNEL,
+                                &[target, b"You're not on that channel"],
+                                &mut outbox)?;
+                    continue
+                },
+                _ => {
+                    queue_reply(server, user, ERR_NOSUCHCHANNEL,
+                                &[target, b"No such channel"], &mut outbox)?;
+                    continue
+                },
+            };
+            let mut params = vec![channel.name.as_bytes()];
+            if let Some(reason) = reason.filter(|x| !x.is_empty()) {
+                params.push(reason);
+            }
+            let part = Message::assemble(Some(&user.source()),
+                                         &Command::Textual(b"PART"),
+                                         &params[..], params.len() > 1)?;
+            channel.broadcast(&part, &mut outbox);
+            channel.members.remove(&user.nick);
+            if channel.members.is_empty() { self.channels.remove(&key); }
+        }
+        Ok(outbox)
+    }
+    /// `TOPIC #chan` shows the topic; `TOPIC #chan :text` sets it, and
+    /// `TOPIC #chan :` clears it. Only members may set it, and only halfops
+    /// and above if the channel is `+t`.
+    pub fn topic(&mut self, user: &User, target: &[u8], text: Option<&[u8]>,
+                 now: u64) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let key = self.key(target);
+        let server = &self.server[..];
+        let channel = match self.channels.get_mut(&key) {
+            Some(x) if x.is_visible_to(&user.nick) => x,
+            _ => {
+                queue_reply(server, user, ERR_NOSUCHCHANNEL,
+                            &[target, b"No such channel"], &mut outbox)?;
+                return Ok(outbox)
+            },
+        };
+        let text = match text {
+            None => {
+                channel.topic_replies(server, user, &mut outbox)?;
+                return Ok(outbox)
+            },
+            Some(x) => truncate_topic(x),
+        };
+        if !channel.is_member(&user.nick) {
+            queue_reply(server, user, ERR_NOTONCHANNEL,
+                        &[channel.name.as_bytes(),
+                          b"You're not on that channel"], &mut outbox)?;
+            return Ok(outbox)
+        }
+        if channel.modes.is_set(b't')
+        && !self.prefixes.allows(channel.members.get(&user.nick), b'h') {
+            let text = format!("You must have channel {} access or above to \
+                                set the topic",
+                               status_name(self.prefixes.required(b'h')));
+            queue_reply(server, user, ERR_CHANOPRIVSNEEDED,
+                        &[channel.name.as_bytes(), text.as_bytes()],
+                        &mut outbox)?;
+            return Ok(outbox)
+        }
+        channel.topic = if text.is_empty() { None } else {
+            Some(Topic { text: text.to_vec(), setter: user.hostmask(),
+                         time: now })
+        };
+        let message = Message::assemble(Some(&user.source()),
+                                        &Command::Textual(b"TOPIC"),
+                                        &[channel.name.as_bytes(), text],
+                                        true)?;
+        channel.broadcast(&message, &mut outbox);
+        Ok(outbox)
+    }
+    /// `NAMES #a,#b`. Secret channels the client isn't in look empty.
+    pub fn names(&self, user: &User, targets: Option<&[u8]>)
+                 -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let targets = targets.unwrap_or(b"*");
+        for target in targets.split(|x| *x == b',') {
+            if target.is_empty() { continue }
+            match self.get(target) {
+                Some(channel) => {
+                    channel.names_replies(&self.server, user, &mut outbox)?
+                },
+                None => {
+                    queue_reply(&self.server, user, RPL_ENDOFNAMES,
+                                &[target, b"End of /NAMES list"],
+                                &mut outbox)?;
+                },
+            }
+        }
+        Ok(outbox)
+    }
+    /// `KICK #chan nick1,nick2 :reason`. Needs halfop or above, and only
+    /// removes members who don't outrank the kicker.
+    pub fn kick(&mut self, user: &User, target: &[u8], victims: &[u8],
+                reason: Option<&[u8]>) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let key = self.key(target);
+        let server = &self.server[..];
+        let channel = match self.channels.get_mut(&key) {
+            Some(x) if x.is_visible_to(&user.nick) => x,
+            _ => {
+                queue_reply(server, user, ERR_NOSUCHCHANNEL,
+                            &[target, b"No such channel"], &mut outbox)?;
+                return Ok(outbox)
+            },
+        };
+        let name = channel.name.as_bytes().to_vec();
+        let kicker = match channel.members.get(&user.nick) {
+            Some(x) => x,
+            None => {
+                queue_reply(server, user, ERR_NOTONCHANNEL,
+                            &[&name, b"You're not on that channel"],
+                            &mut outbox)?;
+                return Ok(outbox)
+            },
+        };
+        if !self.prefixes.allows(Some(kicker), b'h') {
+            let text = format!("You must have channel {} access or above to \
+                                kick",
+                               status_name(self.prefixes.required(b'h')));
+            queue_reply(server, user, ERR_CHANOPRIVSNEEDED,
+                        &[&name, text.as_bytes()], &mut outbox)?;
+            return Ok(outbox)
+        }
+        let rank = kicker.rank();
+        let reason = reason.filter(|x| !x.is_empty())
+            .unwrap_or(user.nick.as_bytes());
+        for victim in victims.split(|x| *x == b',') {
+            if victim.is_empty() { continue }
+            let nick = Name::new(victim, self.mapping);
+            let nick = match channel.members.get(&nick) {
+                Some(x) if x.rank() < rank => {
+                    queue_reply(server, user, ERR_CHANOPRIVSNEEDED,
+                                &[&name, b"You may not kick a member who \
+                                            outranks you"], &mut outbox)?;
+                    continue
+                },
+                Some(x) => x.user.nick.clone(),
+                None => {
+                    queue_reply(server, user, ERR_USERNOTINCHANNEL,
+                                &[victim, &name,
+                                  b"They aren't on that channel"],
+                                &mut outbox)?;
+                    continue
+                },
+            };
+            let kick = Message::assemble(Some(&user.source()),
+                                         &Command::Textual(b"KICK"),
+                                         &[&name, nick.as_bytes(), reason],
+                                         true)?;
+            channel.broadcast(&kick, &mut outbox);
+            channel.members.remove(&nick);
+        }
+        if channel.members.is_empty() { self.channels.remove(&key); }
+        Ok(outbox)
+    }
+    /// `PRIVMSG #chan :text` or `NOTICE #chan :text`, which is relayed to
+    /// every member but the sender and those who are deaf (`+D`). Errors
+    /// aren't reported for `NOTICE`.
+    pub fn message(&self, user: &User, command: &[u8], target: &[u8],
+                   text: &[u8]) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let server = &self.server[..];
+        let notice = command == b"NOTICE";
+        let channel = match self.get(target) {
+            Some(x) => x,
+            None => {
+                if !notice {
+                    queue_reply(server, user, ERR_NOSUCHCHANNEL,
+                                &[target, b"No such channel"], &mut outbox)?;
+                }
+                return Ok(outbox)
+            },
+        };
+        if let Err(why) = channel.check_send(&self.candidate(user), text) {
+            if !notice {
+                queue_reply(server, user, ERR_CANNOTSENDTOCHAN,
+                            &[channel.name.as_bytes(), why.as_bytes()],
+                            &mut outbox)?;
+            }
+            return Ok(outbox)
+        }
+        // one copy for recipients that may be sent tags, and one for those
+        // that mayn't
+        let params: &[&[u8]] = &[channel.name.as_bytes(), text];
+        let mut tagged = None;
+        let mut untagged = None;
+        for (nick, member) in channel.members.iter() {
+            if *nick == user.nick || member.user.modes.is_set(b'D') {
+                continue
+            }
+            let copy = if member.user.message_tags { &mut tagged }
+            else { &mut untagged };
+            if copy.is_none() {
+                *copy = Some(user.message_to(&member.user, command, params)?);
+            }
+            outbox.push((nick.clone(), copy.clone().unwrap()));
+        }
+        Ok(outbox)
+    }
+    /// Refreshes what every channel knows about this client, after its
+    /// modes, account, or the like change.
+    pub fn update_user(&mut self, user: &User) {
+        for channel in self.channels.values_mut() {
+            if let Some(member) = channel.members.get_mut(&user.nick) {
+                member.user = user.clone();
+            }
+        }
+    }
+    /// `WHO #chan`, which lists the channel's members if the client can see
+    /// it. `WHO` for anything other than a channel only gets
+    /// `RPL_ENDOFWHO` here.
+    pub fn who(&self, user: &User, target: &[u8])
+               -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let server = &self.server[..];
+        if let Some(channel) = self.get(target)
+            .filter(|x| x.is_visible_to(&user.nick)) {
+            let is_member = channel.is_member(&user.nick);
+            for member in channel.members.values() {
+                let modes = &member.user.modes;
+                if !is_member && modes.is_set(b'i') { continue }
+                let mut flags = b"H".to_vec();
+                if modes.is_set(b'o') { flags.push(b'*') }
+                flags.extend(member.prefixes(user.multi_prefix));
+                if modes.is_set(b'B') { flags.push(b'B') }
+                let mut trailer = b"0 ".to_vec();
+                trailer.extend_from_slice(&member.user.realname);
+                queue_reply(server, user, RPL_WHOREPLY,
+                            &[channel.name.as_bytes(), &member.user.user,
+                              &member.user.host, server,
+                              member.user.nick.as_bytes(), &flags,
+                              &trailer], &mut outbox)?;
+            }
+        }
+        queue_reply(server, user, RPL_ENDOFWHO,
+                    &[target, b"End of /WHO list"], &mut outbox)?;
+        Ok(outbox)
+    }
+    /// `LIST [conditions]`, where the conditions are as described in
+    /// `ListQuery`. Secret channels are only listed for their members.
+    pub fn list(&self, user: &User, conditions: Option<&[u8]>, now: u64)
+                -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let server = &self.server[..];
+        let query = ListQuery::parse(conditions.unwrap_or(b""),
+                                     self.mapping);
+        queue_reply(server, user, RPL_LISTSTART,
+                    &[b"Channel", b"Users  Name"], &mut outbox)?;
+        let mut channels: Vec<&Channel> = self.channels.values()
+            .filter(|x| x.is_visible_to(&user.nick) && query.matches(x, now))
+            .collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        for channel in channels {
+            let topic = channel.topic.as_ref().map(|x| &x.text[..])
+                .unwrap_or(b"");
+            queue_reply(server, user, RPL_LIST,
+                        &[channel.name.as_bytes(),
+                          channel.members.len().to_string().as_bytes(),
+                          topic], &mut outbox)?;
+        }
+        queue_reply(server, user, RPL_LISTEND, &[b"End of /LIST"],
+                    &mut outbox)?;
+        Ok(outbox)
+    }
+}
+
+/// Fixtures for the tests here, and in `channel::mode` and `user::mode`.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    pub const SERVER: &[u8] = b"irc.example.com";
+    /// A user called `nick`, `~u@host`, whose real name is "A. Fox".
+    pub fn user(nick: &str) -> User {
+        let mut user = User::new(nick.as_bytes(), b"~u", b"host",
+                                 CaseMapping::Rfc1459);
+        user.realname = b"A. Fox".to_vec();
+        user
+    }
+    /// Renders an outbox as `nick: line` strings.
+    pub fn lines(outbox: Outbox) -> Vec<String> {
+        outbox.into_iter().map(|(nick, message)| {
+            let raw = String::from_utf8_lossy(message.get_raw()).into_owned();
+            format!("{}: {}", nick, raw.trim_end())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fixtures::*;
+    fn command(line: &[u8]) -> Message {
+        Message::parse(line, ParseMode::Strict).unwrap()
+    }
+    fn run(channels: &mut Channels, user: &User, line: &[u8], now: u64)
+           -> Vec<String> {
+        lines(channels.handle(user, &command(line), now).unwrap().unwrap())
+    }
+    #[test]
+    fn join_and_part() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen) = (user("Fox"), user("Vixen"));
+        assert_eq!(run(&mut channels, &fox, b"JOIN #Den", 100), vec![
+            "Fox: :Fox!~u@host JOIN #Den",
+            "Fox: :irc.example.com 353 Fox = #Den :~Fox",
+            "Fox: :irc.example.com 366 Fox #Den :End of /NAMES list",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, b"JOIN #den,#bad\x07", 200),
+                   vec![
+            "Fox: :Vixen!~u@host JOIN #Den",
+            "Vixen: :Vixen!~u@host JOIN #Den",
+            "Vixen: :irc.example.com 353 Vixen = #Den :~Fox Vixen",
+            "Vixen: :irc.example.com 366 Vixen #Den :End of /NAMES list",
+            "Vixen: :irc.example.com 476 Vixen #bad\x07 :Bad Channel Mask",
+        ]);
+        assert_eq!(channels.get(b"#DEN").unwrap().created, 100);
+        // joining twice does nothing
+        assert!(run(&mut channels, &vixen, b"JOIN #den", 300).is_empty());
+        assert_eq!(run(&mut channels, &fox, b"PART #den :bye now", 300), vec![
+            "Fox: :Fox!~u@host PART #Den :bye now",
+            "Vixen: :Fox!~u@host PART #Den :bye now",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"PART #den,#nowhere", 300), vec![
+            "Fox: :irc.example.com 442 Fox #den :You're not on that channel",
+            "Fox: :irc.example.com 403 Fox #nowhere :No such channel",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, b"JOIN 0", 300), vec![
+            "Vixen: :Vixen!~u@host PART #Den",
+        ]);
+        // the last one out turns off the lights
+        assert!(channels.get(b"#den").is_none());
+        assert_eq!(run(&mut channels, &fox, b"PART", 300), vec![
+            "Fox: :irc.example.com 461 Fox PART :Not enough parameters",
+        ]);
+    }
+    #[test]
+    fn topics() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen) = (user("Fox"), user("Vixen"));
+        run(&mut channels, &fox, b"JOIN #den", 100);
+        assert_eq!(run(&mut channels, &fox, b"TOPIC #den", 100), vec![
+            "Fox: :irc.example.com 331 Fox #den :No topic is set",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, b"TOPIC #den :mine now", 100),
+                   vec![
+            "Vixen: :irc.example.com 442 Vixen #den \
+             :You're not on that channel",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"TOPIC #den :Welcome!", 150),
+                   vec!["Fox: :Fox!~u@host TOPIC #den :Welcome!"]);
+        assert_eq!(&run(&mut channels, &vixen, b"JOIN #den", 200)[2..4], &[
+            "Vixen: :irc.example.com 332 Vixen #den :Welcome!",
+            "Vixen: :irc.example.com 333 Vixen #den Fox!~u@host :150",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, b"TOPIC #den :", 250), vec![
+            "Fox: :Vixen!~u@host TOPIC #den :",
+            "Vixen: :Vixen!~u@host TOPIC #den :",
+        ]);
+        assert_eq!(channels.get(b"#den").unwrap().topic, None);
+        let long = format!("TOPIC #den :{}", "ü".repeat(300));
+        run(&mut channels, &fox, long.as_bytes(), 300);
+        let topic = &channels.get(b"#den").unwrap().topic.as_ref().unwrap()
+            .text;
+        assert_eq!(topic.len(), MAX_TOPIC_LEN);
+        assert!(std::str::from_utf8(topic).is_ok());
+    }
+    #[test]
+    fn names_and_list() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen) = (user("Fox"), user("Vixen"));
+        run(&mut channels, &fox, b"JOIN #den,#burrow,#secret", 0);
+        run(&mut channels, &vixen, b"JOIN #den", 0);
+        run(&mut channels, &fox, b"TOPIC #burrow :Dig dig", 0);
+        run(&mut channels, &fox, b"MODE #secret +s", 0);
+        run(&mut channels, &fox, b"MODE #burrow +p", 0);
+        assert_eq!(run(&mut channels, &vixen, b"NAMES #burrow,#secret", 0),
+                   vec![
+            "Vixen: :irc.example.com 353 Vixen * #burrow :~Fox",
+            "Vixen: :irc.example.com 366 Vixen #burrow :End of /NAMES list",
+            "Vixen: :irc.example.com 366 Vixen #secret :End of /NAMES list",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"NAMES #secret", 0)[0],
+                   "Fox: :irc.example.com 353 Fox @ #secret :~Fox");
+        assert_eq!(run(&mut channels, &vixen, b"LIST", 7200), vec![
+            "Vixen: :irc.example.com 321 Vixen Channel :Users  Name",
+            "Vixen: :irc.example.com 322 Vixen #burrow 1 :Dig dig",
+            "Vixen: :irc.example.com 322 Vixen #den 2 :",
+            "Vixen: :irc.example.com 323 Vixen :End of /LIST",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"LIST >1", 7200)[1..], [
+            "Fox: :irc.example.com 322 Fox #den 2 :",
+            "Fox: :irc.example.com 323 Fox :End of /LIST",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"LIST #s*", 7200)[1],
+                   "Fox: :irc.example.com 322 Fox #secret 1 :");
+        assert!(channels.handle(&fox, &command(b"WHOIS Vixen"), 0)
+                .is_none());
+    }
+    #[test]
+    fn empty_targets() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let fox = user("Fox");
+        assert_eq!(run(&mut channels, &fox, b"PRIVMSG :", 0), vec![
+            "Fox: :irc.example.com 411 Fox :No recipient given (PRIVMSG)",
+        ]);
+        // " hi" isn't a channel, so this is left for private messages
+        assert!(channels.handle(&fox, &command(b"PRIVMSG : hi"), 0)
+                .is_none());
+        assert_eq!(run(&mut channels, &fox, b"PRIVMSG #den", 0), vec![
+            "Fox: :irc.example.com 412 Fox :No text to send",
+        ]);
+        assert!(run(&mut channels, &fox, b"NOTICE :", 0).is_empty());
+        for line in [&b"MODE :"[..], b"JOIN :", b"KICK : Fox"].iter() {
+            assert!(run(&mut channels, &fox, line, 0)[0]
+                    .contains(" 461 Fox "), "{:?}",
+                    String::from_utf8_lossy(line));
+        }
+    }
+    #[test]
+    fn ladder() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let [owner, admin, op, halfop, voice]
+            = ["Owner", "Admin", "Op", "Halfop", "Voice"].map(user);
+        for user in [&owner, &op, &admin, &halfop, &voice].iter() {
+            run(&mut channels, user, b"JOIN #den", 0);
+        }
+        run(&mut channels, &owner, b"MODE #den +o Op", 0);
+        assert_eq!(run(&mut channels, &op, b"MODE #den +qaoh Owner Admin \
+                                             Owner Halfop", 0)[..2], [
+            "Op: :irc.example.com 482 Op #den :You must have channel owner \
+             access or above to set channel mode q",
+            "Op: :irc.example.com 482 Op #den :You must have channel owner \
+             access or above to set channel mode a",
+        ]);
+        run(&mut channels, &owner, b"MODE #den +oav Owner Admin Voice", 0);
+        let mut multi = user("Multi");
+        multi.multi_prefix = true;
+        assert_eq!(run(&mut channels, &multi, b"NAMES #den", 0)[0],
+                   "Multi: :irc.example.com 353 Multi = #den \
+                    :&Admin %Halfop @Op ~@Owner +Voice");
+        assert_eq!(run(&mut channels, &multi, b"JOIN #den", 0)[6],
+                   "Multi: :irc.example.com 353 Multi = #den \
+                    :&Admin %Halfop Multi @Op ~@Owner +Voice");
+        assert_eq!(run(&mut channels, &voice, b"WHO #den", 0)[4],
+                   "Voice: :irc.example.com 352 Voice #den ~u host \
+                    irc.example.com Owner H~ :0 A. Fox");
+        assert_eq!(run(&mut channels, &multi, b"WHO #den", 0)[4],
+                   "Multi: :irc.example.com 352 Multi #den ~u host \
+                    irc.example.com Owner H~@ :0 A. Fox");
+        // halfops may voice and kick, but not op or kick their betters
+        assert_eq!(run(&mut channels, &halfop, b"MODE #den +vo Multi Multi",
+                       0), vec![
+            "Halfop: :irc.example.com 482 Halfop #den :You must have \
+             channel op access or above to set channel mode o",
+            "Admin: :Halfop!~u@host MODE #den +v Multi",
+            "Halfop: :Halfop!~u@host MODE #den +v Multi",
+            "Multi: :Halfop!~u@host MODE #den +v Multi",
+            "Op: :Halfop!~u@host MODE #den +v Multi",
+            "Owner: :Halfop!~u@host MODE #den +v Multi",
+            "Voice: :Halfop!~u@host MODE #den +v Multi",
+        ]);
+        assert_eq!(run(&mut channels, &halfop, b"KICK #den Op,Voice :out",
+                       0)[..2], [
+            "Halfop: :irc.example.com 482 Halfop #den :You may not kick a \
+             member who outranks you",
+            "Admin: :Halfop!~u@host KICK #den Voice :out",
+        ]);
+        assert!(!channels.get(b"#den").unwrap().is_member(&voice.nick));
+        assert_eq!(run(&mut channels, &voice, b"KICK #den Op", 0), vec![
+            "Voice: :irc.example.com 442 Voice #den \
+             :You're not on that channel",
+        ]);
+        assert_eq!(run(&mut channels, &multi, b"KICK #den Op", 0), vec![
+            "Multi: :irc.example.com 482 Multi #den :You must have channel \
+             halfop access or above to kick",
+        ]);
+        // ops can't touch admins, but anyone may step down
+        assert_eq!(run(&mut channels, &op, b"MODE #den -o Owner", 0), vec![
+            "Op: :irc.example.com 482 Op #den :You may not change the \
+             status of a member who outranks you",
+        ]);
+        assert_eq!(run(&mut channels, &owner, b"MODE #den -q Owner", 0)[0],
+                   "Admin: :Owner!~u@host MODE #den -q Owner");
+        assert_eq!(run(&mut channels, &op, b"KICK #den Owner", 0)[0],
+                   "Admin: :Op!~u@host KICK #den Owner :Op");
+        // a server that only uses +o and +v
+        let mut channels = Channels::with_prefixes(
+            SERVER, CaseMapping::Rfc1459, Prefixes::parse("ov").unwrap());
+        run(&mut channels, &op, b"JOIN #den", 0);
+        assert_eq!(run(&mut channels, &op, b"MODE #den +h Op", 0), vec![
+            "Op: :irc.example.com 472 Op h :is unknown mode char to me",
+        ]);
+    }
+    #[test]
+    fn messages() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (mut fox, mut vixen, wolf) = (user("Fox"), user("Vixen"),
+                                          user("Wolf"));
+        run(&mut channels, &fox, b"JOIN #den", 100);
+        run(&mut channels, &vixen, b"JOIN #den", 100);
+        assert_eq!(run(&mut channels, &fox, b"PRIVMSG #den :hi", 100), vec![
+            "Vixen: :Fox!~u@host PRIVMSG #den :hi",
+        ]);
+        assert_eq!(run(&mut channels, &wolf, b"PRIVMSG #lair :hi", 100), vec![
+            "Wolf: :irc.example.com 403 Wolf #lair :No such channel",
+        ]);
+        assert!(run(&mut channels, &wolf, b"NOTICE #lair :hi", 100)
+                .is_empty());
+        // nick targets are left for private messages
+        assert!(channels.handle(&fox, &command(b"PRIVMSG Vixen :hi"), 100)
+                .is_none());
+        vixen.mode(SERVER, CaseMapping::Rfc1459, b"Vixen", Some(b"+Di"))
+            .unwrap();
+        fox.mode(SERVER, CaseMapping::Rfc1459, b"Fox", Some(b"+B")).unwrap();
+        fox.oper_up().unwrap();
+        channels.update_user(&vixen);
+        channels.update_user(&fox);
+        assert!(run(&mut channels, &fox, b"PRIVMSG #den :hi", 100)
+                .is_empty());
+        assert_eq!(run(&mut channels, &vixen, b"NOTICE #den :hi", 100), vec![
+            "Fox: :Vixen!~u@host NOTICE #den :hi",
+        ]);
+        // vixen is invisible to non-members
+        assert_eq!(run(&mut channels, &wolf, b"NAMES #den", 100)[0],
+                   "Wolf: :irc.example.com 353 Wolf = #den :~Fox");
+        assert_eq!(run(&mut channels, &wolf, b"WHO #den", 100), vec![
+            "Wolf: :irc.example.com 352 Wolf #den ~u host irc.example.com \
+             Fox H*~B :0 A. Fox",
+            "Wolf: :irc.example.com 315 Wolf #den :End of /WHO list",
+        ]);
+        assert_eq!(run(&mut channels, &fox, b"NAMES #den", 100)[0],
+                   "Fox: :irc.example.com 353 Fox = #den :~Fox Vixen");
+        vixen.mode(SERVER, CaseMapping::Rfc1459, b"Vixen", Some(b"-D"))
+            .unwrap();
+        vixen.message_tags = true;
+        channels.update_user(&vixen);
+        run(&mut channels, &wolf, b"JOIN #den", 100);
+        // only those who negotiated message-tags see the bot tag
+        assert_eq!(run(&mut channels, &fox, b"PRIVMSG #den :hi", 100), vec![
+            "Vixen: @bot :Fox!~u@host PRIVMSG #den :hi",
+            "Wolf: :Fox!~u@host PRIVMSG #den :hi",
+        ]);
+    }
+}
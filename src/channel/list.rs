@@ -0,0 +1,154 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! The conditions a `LIST` can filter channels by, as advertised in `ELIST`.
+
+use std::cmp::Ordering;
+
+use super::*;
+use crate::mask::Glob;
+
+/// One condition of a `LIST` query.
+#[derive(Clone,Debug,PartialEq)]
+enum Condition {
+    /// `>N` / `<N`: more / fewer than N members.
+    Users(Ordering, u64),
+    /// `C>N` / `C<N`: created more / less than N minutes ago.
+    Created(Ordering, u64),
+    /// `T>N` / `T<N`: topic set more / less than N minutes ago. Channels
+    /// without a topic never match.
+    TopicSet(Ordering, u64),
+    /// `MASK`: the name matches.
+    Mask(Glob),
+    /// `!MASK`: the name doesn't match.
+    NotMask(Glob),
+}
+
+/// Parse `>N` or `<N`.
+fn parse_comparison(text: &[u8]) -> Option<(Ordering, u64)> {
+    let ordering = match text.first()? {
+        b'>' => Ordering::Greater,
+        b'<' => Ordering::Less,
+        _ => return None,
+    };
+    let number = std::str::from_utf8(&text[1..]).ok()?.parse().ok()?;
+    Some((ordering, number))
+}
+
+/// How many whole minutes ago `then` was.
+fn minutes_ago(then: u64, now: u64) -> u64 {
+    now.saturating_sub(then) / 60
+}
+
+/// A parsed `LIST` query: a comma-separated list of conditions, all of which
+/// must hold, except that a channel need only match one of several masks.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ListQuery {
+    conditions: Vec<Condition>,
+}
+
+impl ListQuery {
+    /// The `ELIST` token for the conditions we understand: masks (`M`),
+    /// negated masks (`N`), user counts (`U`), creation times (`C`) and topic
+    /// times (`T`).
+    pub const ELIST: &'static [u8] = b"CMNTU";
+    /// Parse a query. Conditions that don't parse are ignored.
+    pub fn parse(spec: &[u8], mapping: CaseMapping) -> ListQuery {
+        let conditions = spec.split(|x| *x == b',')
+            .filter(|x| !x.is_empty())
+            .filter_map(|item| match item {
+                [b'>', ..] | [b'<', ..] => parse_comparison(item)
+                    .map(|(o, n)| Condition::Users(o, n)),
+                [b'C', rest @ ..] | [b'c', rest @ ..]
+                    if parse_comparison(rest).is_some() =>
+                    parse_comparison(rest)
+                    .map(|(o, n)| Condition::Created(o, n)),
+                [b'T', rest @ ..] | [b't', rest @ ..]
+                    if parse_comparison(rest).is_some() =>
+                    parse_comparison(rest)
+                    .map(|(o, n)| Condition::TopicSet(o, n)),
+                [b'!', rest @ ..] => {
+                    Some(Condition::NotMask(Glob::compile(rest, mapping)))
+                },
+                _ => Some(Condition::Mask(Glob::compile(item, mapping))),
+            }).collect();
+        ListQuery { conditions }
+    }
+    /// Does `channel` satisfy this query, at time `now`?
+    pub fn matches(&self, channel: &Channel, now: u64) -> bool {
+        let name = channel.name.as_bytes();
+        let mut masks = self.conditions.iter().filter_map(|x| match x {
+            Condition::Mask(glob) => Some(glob),
+            _ => None,
+        }).peekable();
+        if masks.peek().is_some() && !masks.any(|x| x.matches(name)) {
+            return false
+        }
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Users(ordering, n) => {
+                (channel.members.len() as u64).cmp(n) == *ordering
+            },
+            Condition::Created(ordering, n) => {
+                minutes_ago(channel.created, now).cmp(n) == *ordering
+            },
+            Condition::TopicSet(ordering, n) => channel.topic.as_ref()
+                .map(|x| minutes_ago(x.time, now).cmp(n) == *ordering)
+                .unwrap_or(false),
+            Condition::Mask(_) => true,
+            Condition::NotMask(glob) => !glob.matches(name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn conditions() {
+        let mapping = CaseMapping::Ascii;
+        let mut channel = Channel::new(Name::new(b"#Foxes", mapping), 0);
+        for nick in &["a", "b", "c"] {
+            let user = User::new(nick.as_bytes(), b"~u", b"host", mapping);
+            channel.members.insert(user.nick.clone(), Member::new(user));
+        }
+        let now = 3600;
+        let cases: &[(&[u8], bool)] = &[
+            (b"", true),
+            (b">2", true),
+            (b">3", false),
+            (b"<4", true),
+            (b"C>59", true),
+            (b"C<60", false),
+            (b"T<10", false),
+            (b"#fox*", true),
+            (b"#wolf*", false),
+            (b"#wolf*,#fox*", true),
+            (b"!#fox*", false),
+            (b"#*,!#wolf*,>1", true),
+            (b">bogus", true),
+        ];
+        for &(spec, expected) in cases {
+            assert_eq!(ListQuery::parse(spec, mapping).matches(&channel, now),
+                       expected, "{:?}", String::from_utf8_lossy(spec));
+        }
+        channel.topic = Some(Topic { text: b"yip".to_vec(),
+                                     setter: b"a!b@c".to_vec(), time: 3300 });
+        assert!(ListQuery::parse(b"T<10", mapping).matches(&channel, now));
+        assert!(!ListQuery::parse(b"T>10", mapping).matches(&channel, now));
+    }
+}
@@ -0,0 +1,770 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Channel modes, and the `MODE` command for channels.
+//!
+//! Every mode we support is in `CHANNEL_MODES`, along with the kind of mode
+//! it is. The kind decides when the mode takes a parameter, and it is also
+//! what the `CHANMODES` and `PREFIX` tokens are made from, so what we
+//! advertise can't disagree with how we parse.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::*;
+use crate::mask::{BanMask, BanTarget};
+use crate::message::{validate_param, MAX_MESSAGE_LEN};
+
+/// The most changes with parameters we accept, and send, in one `MODE`.
+pub const MAX_PARAM_MODES: usize = 4;
+/// The most entries each list mode may hold.
+pub const MAX_LIST_LEN: usize = 100;
+
+/// How a channel mode behaves, which is also how `CHANMODES` classifies it.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ModeKind {
+    /// Type A: a list of masks. Takes a parameter when set or unset; with
+    /// none, shows the list, using these numerics. `name` goes in the end
+    /// of list reply.
+    List { entry: u32, end: u32, name: &'static str },
+    /// Type B: always takes a parameter.
+    AlwaysParam,
+    /// Type C: takes a parameter only when set.
+    SetParam,
+    /// Type D: an on/off flag.
+    Flag,
+    /// A status a member can have, shown as this prefix. Always takes a
+    /// nick as its parameter.
+    Prefix(u8),
+}
+
+/// A channel mode we support.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct ChannelMode {
+    pub letter: u8,
+    pub kind: ModeKind,
+    /// The status needed to change it. See `Prefixes::required`.
+    pub need: u8,
+}
+
+/// Every channel mode we support. Prefix modes go from most to least
+/// powerful.
+pub const CHANNEL_MODES: &[ChannelMode] = &[
+    ChannelMode { letter: b'b', need: b'h', kind: ModeKind::List {
+        entry: RPL_BANLIST, end: RPL_ENDOFBANLIST, name: "channel ban list",
+    } },
+    ChannelMode { letter: b'e', need: b'o', kind: ModeKind::List {
+        entry: RPL_EXCEPTLIST, end: RPL_ENDOFEXCEPTLIST,
+        name: "channel exception list",
+    } },
+    ChannelMode { letter: b'I', need: b'o', kind: ModeKind::List {
+        entry: RPL_INVITELIST, end: RPL_ENDOFINVITELIST,
+        name: "channel invite list",
+    } },
+    // key
+    ChannelMode { letter: b'k', need: b'o', kind: ModeKind::AlwaysParam },
+    // member limit
+    ChannelMode { letter: b'l', need: b'o', kind: ModeKind::SetParam },
+    // no CTCPs other than ACTION
+    ChannelMode { letter: b'C', need: b'o', kind: ModeKind::Flag },
+    // invite only
+    ChannelMode { letter: b'i', need: b'h', kind: ModeKind::Flag },
+    // moderated
+    ChannelMode { letter: b'm', need: b'h', kind: ModeKind::Flag },
+    // no messages from outside
+    ChannelMode { letter: b'n', need: b'o', kind: ModeKind::Flag },
+    // private
+    ChannelMode { letter: b'p', need: b'o', kind: ModeKind::Flag },
+    // secret
+    ChannelMode { letter: b's', need: b'o', kind: ModeKind::Flag },
+    // only halfops and above may set the topic
+    ChannelMode { letter: b't', need: b'o', kind: ModeKind::Flag },
+    ChannelMode { letter: b'q', need: b'q', kind: ModeKind::Prefix(b'~') },
+    ChannelMode { letter: b'a', need: b'q', kind: ModeKind::Prefix(b'&') },
+    ChannelMode { letter: b'o', need: b'o', kind: ModeKind::Prefix(b'@') },
+    ChannelMode { letter: b'h', need: b'o', kind: ModeKind::Prefix(b'%') },
+    ChannelMode { letter: b'v', need: b'h', kind: ModeKind::Prefix(b'+') },
+];
+
+/// Looks up a mode by letter.
+pub fn channel_mode(letter: u8) -> Option<&'static ChannelMode> {
+    CHANNEL_MODES.iter().find(|x| x.letter == letter)
+}
+
+/// Add `CHANMODES`, `PREFIX`, `MODES` and `MAXLIST` to `RPL_ISUPPORT`.
+pub fn advertise(isupport: &mut ISupport, prefixes: &Prefixes) {
+    let letters = |f: fn(&ModeKind) -> bool| -> Vec<u8> {
+        CHANNEL_MODES.iter().filter(|x| f(&x.kind)).map(|x| x.letter)
+            .collect()
+    };
+    let lists = letters(|x| matches!(x, ModeKind::List { .. }));
+    let chanmodes = [
+        &lists[..],
+        &letters(|x| *x == ModeKind::AlwaysParam)[..],
+        &letters(|x| *x == ModeKind::SetParam)[..],
+        &letters(|x| *x == ModeKind::Flag)[..],
+    ].join(&b',');
+    isupport.set("CHANMODES", Some(&chanmodes));
+    isupport.set("PREFIX", Some(&prefixes.token()));
+    isupport.set("MODES", Some(MAX_PARAM_MODES.to_string().as_bytes()));
+    let mut maxlist = lists;
+    maxlist.push(b':');
+    maxlist.extend_from_slice(MAX_LIST_LEN.to_string().as_bytes());
+    isupport.set("MAXLIST", Some(&maxlist));
+    mask::advertise(isupport);
+}
+
+/// For `RPL_MYINFO`: every channel mode letter we support, and those that
+/// take a parameter.
+pub fn mode_letters(prefixes: &Prefixes) -> (Vec<u8>, Vec<u8>) {
+    let mut all = Vec::new();
+    let mut with_param = Vec::new();
+    for mode in CHANNEL_MODES.iter() {
+        match mode.kind {
+            ModeKind::Prefix(_) if !prefixes.has(mode.letter) => continue,
+            ModeKind::Flag => (),
+            _ => with_param.push(mode.letter),
+        }
+        all.push(mode.letter);
+    }
+    all.sort_unstable();
+    with_param.sort_unstable();
+    (all, with_param)
+}
+
+/// An entry in a list mode.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ListEntry {
+    pub mask: BanMask,
+    /// The `nick!user@host` of whoever set it.
+    pub setter: Vec<u8>,
+    /// When it was set, in seconds since the epoch.
+    pub time: u64,
+}
+
+/// The modes set on a channel, other than members' statuses.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct ChannelModes {
+    flags: BTreeSet<u8>,
+    pub key: Option<Vec<u8>>,
+    pub limit: Option<u64>,
+    lists: BTreeMap<u8, Vec<ListEntry>>,
+}
+
+impl ChannelModes {
+    /// Is this flag (type D mode) set?
+    pub fn is_set(&self, letter: u8) -> bool {
+        self.flags.contains(&letter)
+    }
+    /// The entries of a list mode.
+    pub fn list(&self, letter: u8) -> &[ListEntry] {
+        self.lists.get(&letter).map(|x| &x[..]).unwrap_or(&[])
+    }
+    /// The entries of a list mode that match this client.
+    pub fn list_matches<'a>(&'a self, letter: u8, target: &'a dyn BanTarget)
+                            -> impl Iterator<Item=&'a ListEntry> {
+        self.list(letter).iter().filter(move |x| x.mask.matches(target))
+    }
+    /// The mode string and parameters for `RPL_CHANNELMODEIS`. The key is
+    /// only shown to members.
+    fn describe(&self, show_key: bool) -> Vec<Vec<u8>> {
+        let mut modes = b"+".to_vec();
+        let mut params = Vec::new();
+        for mode in CHANNEL_MODES.iter() {
+            match mode.letter {
+                b'k' => if let Some(key) = self.key.as_ref() {
+                    modes.push(b'k');
+                    params.push(if show_key { key.clone() }
+                                else { b"*".to_vec() });
+                },
+                b'l' => if let Some(limit) = self.limit {
+                    modes.push(b'l');
+                    params.push(limit.to_string().into_bytes());
+                },
+                x if self.is_set(x) => modes.push(x),
+                _ => (),
+            }
+        }
+        params.insert(0, modes);
+        params
+    }
+}
+
+/// One change to a channel's modes.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub letter: u8,
+    pub param: Option<Vec<u8>>,
+}
+
+/// What a `MODE` asked for.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct ParsedModes {
+    pub changes: Vec<ModeChange>,
+    /// List modes given without a parameter, whose lists should be shown.
+    pub queries: Vec<u8>,
+    /// Letters we don't know.
+    pub unknown: Vec<u8>,
+}
+
+/// Parse a mode string and its parameters, consuming parameters according
+/// to each mode's kind. A change missing its parameter is dropped, as are
+/// changes with parameters past `MAX_PARAM_MODES`. Prefix modes not in
+/// `prefixes` are unknown.
+pub fn parse_modes(modes: &[u8], params: &[&[u8]], prefixes: &Prefixes)
+                   -> ParsedModes {
+    let mut ret = ParsedModes::default();
+    let mut params = params.iter().copied();
+    let mut adding = true;
+    let mut param_count = 0;
+    for &letter in modes {
+        let kind = match letter {
+            b'+' => { adding = true; continue },
+            b'-' => { adding = false; continue },
+            _ => match channel_mode(letter) {
+                Some(ChannelMode { kind: ModeKind::Prefix(_), .. })
+                    if !prefixes.has(letter) => {
+                    if !ret.unknown.contains(&letter) {
+                        ret.unknown.push(letter)
+                    }
+                    continue
+                },
+                Some(x) => x.kind,
+                None => {
+                    if !ret.unknown.contains(&letter) {
+                        ret.unknown.push(letter)
+                    }
+                    continue
+                },
+            },
+        };
+        let takes_param = match kind {
+            ModeKind::List { .. } | ModeKind::AlwaysParam
+                | ModeKind::Prefix(_) => true,
+            ModeKind::SetParam => adding,
+            ModeKind::Flag => false,
+        };
+        let param = if takes_param {
+            match params.next() {
+                Some(x) => Some(x.to_vec()),
+                None => {
+                    if let ModeKind::List { .. } = kind {
+                        if !ret.queries.contains(&letter) {
+                            ret.queries.push(letter)
+                        }
+                    }
+                    continue
+                },
+            }
+        } else { None };
+        if param.is_some() {
+            param_count += 1;
+            if param_count > MAX_PARAM_MODES { continue }
+        }
+        ret.changes.push(ModeChange { adding, letter, param });
+    }
+    ret
+}
+
+/// Group changes into the parameters of as few `MODE` lines as possible,
+/// each with at most `MAX_PARAM_MODES` parameters, and with its mode string
+/// and parameters together taking at most `budget` bytes.
+pub fn coalesce(changes: &[ModeChange], budget: usize) -> Vec<Vec<Vec<u8>>> {
+    let mut ret = Vec::new();
+    let mut modes = Vec::new();
+    let mut params: Vec<Vec<u8>> = Vec::new();
+    let mut used = 0;
+    let mut sign = None;
+    for change in changes {
+        let param_len = change.param.as_ref().map(|x| x.len() + 1)
+            .unwrap_or(0);
+        let full = change.param.is_some() && params.len() == MAX_PARAM_MODES;
+        if !modes.is_empty() && (full || used + 2 + param_len > budget) {
+            params.insert(0, std::mem::take(&mut modes));
+            ret.push(std::mem::take(&mut params));
+            used = 0;
+            sign = None;
+        }
+        if sign != Some(change.adding) {
+            modes.push(if change.adding { b'+' } else { b'-' });
+            sign = Some(change.adding);
+            used += 1;
+        }
+        modes.push(change.letter);
+        used += 1 + param_len;
+        if let Some(param) = change.param.as_ref() {
+            params.push(param.clone());
+        }
+    }
+    if !modes.is_empty() {
+        params.insert(0, modes);
+        ret.push(params);
+    }
+    ret
+}
+
+/// Why a change couldn't be made.
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum ModeError {
+    /// The nick given to a prefix mode isn't a member.
+    NotOnChannel(Vec<u8>),
+    /// The list is full.
+    ListFull(u8),
+}
+
+impl Channel {
+    /// Applies one change. Returns the change as it should be relayed, or
+    /// `None` if it didn't change anything. Nothing is changed unless the
+    /// relayed parameter, if any, is one a message can carry.
+    fn apply(&mut self, change: &ModeChange, setter: &[u8], now: u64,
+             mapping: CaseMapping)
+             -> Result<Option<ModeChange>, ModeError> {
+        let ModeChange { adding, letter, .. } = *change;
+        let param = change.param.as_deref().unwrap_or(b"");
+        let relay = |param: Option<Vec<u8>>| {
+            Ok(Some(ModeChange { adding, letter, param }))
+        };
+        match channel_mode(letter).map(|x| x.kind) {
+            Some(ModeKind::List { .. }) => {
+                let mask = match BanMask::parse(param, mapping) {
+                    Ok(x) if validate_param(x.as_bytes()).is_ok() => x,
+                    _ => return Ok(None),
+                };
+                let list = self.modes.lists.entry(letter).or_default();
+                let existing = list.iter().position(|x| x.mask == mask);
+                match (adding, existing) {
+                    (true, None) => {
+                        if list.len() >= MAX_LIST_LEN {
+                            return Err(ModeError::ListFull(letter))
+                        }
+                        let text = mask.as_bytes().to_vec();
+                        list.push(ListEntry { mask, setter: setter.to_vec(),
+                                              time: now });
+                        relay(Some(text))
+                    },
+                    (false, Some(n)) => {
+                        let entry = list.remove(n);
+                        relay(Some(entry.mask.as_bytes().to_vec()))
+                    },
+                    _ => Ok(None),
+                }
+            },
+            Some(ModeKind::AlwaysParam) => {
+                // `k` is our only type B mode.
+                if adding {
+                    if validate_param(param).is_err()
+                    || param.contains(&b',') {
+                        return Ok(None)
+                    }
+                    self.modes.key = Some(param.to_vec());
+                    relay(Some(param.to_vec()))
+                }
+                else if let Some(key) = self.modes.key.take() {
+                    relay(Some(key))
+                }
+                else { Ok(None) }
+            },
+            Some(ModeKind::SetParam) => {
+                // `l` is our only type C mode.
+                if adding {
+                    let limit = std::str::from_utf8(param).ok()
+                        .and_then(|x| x.parse::<u64>().ok())
+                        .filter(|x| *x > 0);
+                    match limit {
+                        Some(x) if self.modes.limit != Some(x) => {
+                            self.modes.limit = Some(x);
+                            relay(Some(x.to_string().into_bytes()))
+                        },
+                        _ => Ok(None),
+                    }
+                }
+                else if self.modes.limit.take().is_some() { relay(None) }
+                else { Ok(None) }
+            },
+            Some(ModeKind::Flag) => {
+                let changed = if adding { self.modes.flags.insert(letter) }
+                              else { self.modes.flags.remove(&letter) };
+                if changed { relay(None) } else { Ok(None) }
+            },
+            Some(ModeKind::Prefix(_)) => {
+                if validate_param(param).is_err() { return Ok(None) }
+                let nick = match self.members
+                    .get_key_value(&Name::new(param, mapping)) {
+                    Some((x, _)) => x.clone(),
+                    None => {
+                        return Err(ModeError::NotOnChannel(param.to_vec()))
+                    },
+                };
+                let member = self.members.get_mut(&nick).unwrap();
+                if member.set(letter, adding) {
+                    relay(Some(nick.as_bytes().to_vec()))
+                }
+                else { Ok(None) }
+            },
+            None => Ok(None),
+        }
+    }
+    /// Queue the list replies for a list mode.
+    fn list_replies(&self, server: &[u8], user: &User, letter: u8,
+                    outbox: &mut Outbox) -> Result<(), &'static str> {
+        let (entry, end, name) = match channel_mode(letter).map(|x| x.kind) {
+            Some(ModeKind::List { entry, end, name }) => (entry, end, name),
+            _ => return Ok(()),
+        };
+        let channel = self.name.as_bytes();
+        for x in self.modes.list(letter) {
+            queue_reply(server, user, entry,
+                        &[channel, x.mask.as_bytes(), &x.setter,
+                          x.time.to_string().as_bytes()], outbox)?;
+        }
+        let text = format!("End of {}", name);
+        queue_reply(server, user, end, &[channel, text.as_bytes()], outbox)
+    }
+}
+
+impl Channels {
+    /// `MODE #chan [modes [params...]]`. With no modes, shows the channel's
+    /// modes. List modes given without a parameter show that list, which
+    /// anyone who can see the channel may do; changing anything needs
+    /// channel operator status.
+    pub fn mode(&mut self, user: &User, target: &[u8], args: &[&[u8]],
+                now: u64) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        let key = self.key(target);
+        let server = &self.server[..];
+        let mapping = self.mapping;
+        let prefixes = &self.prefixes;
+        let channel = match self.channels.get_mut(&key) {
+            Some(x) if x.is_visible_to(&user.nick) => x,
+            _ => {
+                queue_reply(server, user, ERR_NOSUCHCHANNEL,
+                            &[target, b"No such channel"], &mut outbox)?;
+                return Ok(outbox)
+            },
+        };
+        let name = channel.name.as_bytes().to_vec();
+        let modes = match args.split_first() {
+            None => {
+                let mut params = vec![name.clone()];
+                params.extend(channel.modes
+                              .describe(channel.is_member(&user.nick)));
+                let params: Vec<&[u8]> = params.iter().map(|x| &x[..])
+                    .collect();
+                queue_reply(server, user, RPL_CHANNELMODEIS, &params,
+                            &mut outbox)?;
+                queue_reply(server, user, RPL_CREATIONTIME,
+                            &[&name, channel.created.to_string().as_bytes()],
+                            &mut outbox)?;
+                return Ok(outbox)
+            },
+            Some((modes, params)) => parse_modes(modes, params, prefixes),
+        };
+        for letter in modes.unknown.iter() {
+            queue_reply(server, user, ERR_UNKNOWNMODE,
+                        &[&[*letter], b"is unknown mode char to me"],
+                        &mut outbox)?;
+        }
+        for letter in modes.queries.iter() {
+            channel.list_replies(server, user, *letter, &mut outbox)?;
+        }
+        let member = channel.members.get(&user.nick);
+        let rank = member.map(|x| x.rank()).unwrap_or(Rank::MAX);
+        let mut denied = Vec::new();
+        let changes: Vec<&ModeChange> = modes.changes.iter().filter(|x| {
+            let need = channel_mode(x.letter).unwrap().need;
+            let allowed = prefixes.allows(member, need);
+            if !allowed && !denied.contains(&x.letter) {
+                denied.push(x.letter)
+            }
+            allowed
+        }).collect();
+        for letter in denied {
+            let need = channel_mode(letter).unwrap().need;
+            let text = format!("You must have channel {} access or above to \
+                                set channel mode {}",
+                               status_name(prefixes.required(need)),
+                               letter as char);
+            queue_reply(server, user, ERR_CHANOPRIVSNEEDED,
+                        &[&name, text.as_bytes()], &mut outbox)?;
+        }
+        let setter = user.hostmask();
+        let mut applied = Vec::new();
+        for change in changes {
+            // Statuses may only be changed on members who don't outrank us,
+            // and ourselves.
+            if let ModeKind::Prefix(_) = channel_mode(change.letter)
+                .unwrap().kind {
+                let target = change.param.as_deref().unwrap_or(b"");
+                let target = Name::new(target, mapping);
+                if target != user.nick && channel.members.get(&target)
+                    .map(|x| x.rank() < rank).unwrap_or(false) {
+                    queue_reply(server, user, ERR_CHANOPRIVSNEEDED,
+                                &[&name, b"You may not change the status \
+                                            of a member who outranks you"],
+                                &mut outbox)?;
+                    continue
+                }
+            }
+            match channel.apply(change, &setter, now, mapping) {
+                Ok(Some(x)) => applied.push(x),
+                Ok(None) => (),
+                Err(ModeError::NotOnChannel(nick)) => {
+                    queue_reply(server, user, ERR_USERNOTINCHANNEL,
+                                &[&nick, &name,
+                                  b"They aren't on that channel"],
+                                &mut outbox)?;
+                },
+                Err(ModeError::ListFull(letter)) => {
+                    queue_reply(server, user, ERR_BANLISTFULL,
+                                &[&name, &[letter], b"Channel list is full"],
+                                &mut outbox)?;
+                },
+            }
+        }
+        // ":nick!user@host MODE #chan " and "\r\n"
+        let overhead = setter.len() + name.len() + 10;
+        let source = user.source();
+        for params in coalesce(&applied, MAX_MESSAGE_LEN - overhead) {
+            let mut all: Vec<&[u8]> = vec![&name];
+            all.extend(params.iter().map(|x| &x[..]));
+            let message = Message::assemble(Some(&source),
+                                            &Command::Textual(b"MODE"),
+                                            &all, false)?;
+            channel.broadcast(&message, &mut outbox);
+        }
+        Ok(outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::fixtures::*;
+    #[test]
+    fn advertising() {
+        let mut isupport = ISupport::new();
+        advertise(&mut isupport, &Prefixes::parse("ov").unwrap());
+        assert_eq!(isupport.get("CHANMODES"),
+                   Some(Some(&b"beI,k,l,Cimnpst"[..])));
+        assert_eq!(isupport.get("PREFIX"), Some(Some(&b"(ov)@+"[..])));
+        advertise(&mut isupport, &Prefixes::default());
+        assert_eq!(isupport.get("PREFIX"),
+                   Some(Some(&b"(qaohv)~&@%+"[..])));
+        assert_eq!(isupport.get("MODES"), Some(Some(&b"4"[..])));
+        assert_eq!(isupport.get("MAXLIST"), Some(Some(&b"beI:100"[..])));
+        assert_eq!(isupport.get("EXTBAN"), Some(Some(&b"$,ajmrz"[..])));
+        let (all, with_param) = mode_letters(&Prefixes::parse("ov")
+                                             .unwrap());
+        assert_eq!((&all[..], &with_param[..]),
+                   (&b"CIbeiklmnopstv"[..], &b"Ibeklov"[..]));
+    }
+    #[test]
+    fn parsing() {
+        let change = |adding, letter, param: Option<&str>| ModeChange {
+            adding, letter, param: param.map(|x| x.as_bytes().to_vec()),
+        };
+        let prefixes = Prefixes::parse("ov").unwrap();
+        let parsed = parse_modes(b"+kl-lk+b-o+nXh+e",
+                                 &[b"sekrit", b"10", b"*", b"fox!*@*",
+                                   b"Vixen"], &prefixes);
+        assert_eq!(parsed.changes, vec![
+            change(true, b'k', Some("sekrit")),
+            change(true, b'l', Some("10")),
+            change(false, b'l', None),
+            change(false, b'k', Some("*")),
+            change(true, b'b', Some("fox!*@*")),
+            change(true, b'n', None),
+        ]);
+        // -o was past MAX_PARAM_MODES, so it was dropped
+        assert_eq!(parsed.unknown, b"Xh");
+        assert_eq!(parsed.queries, b"e");
+        assert_eq!(parse_modes(b"b", &[], &prefixes).queries, b"b");
+    }
+    #[test]
+    fn coalescing() {
+        let changes: Vec<ModeChange> = (0 .. 6).map(|n| ModeChange {
+            adding: n % 3 != 0,
+            letter: b'v',
+            param: Some(format!("nick{}", n).into_bytes()),
+        }).chain(Some(ModeChange { adding: true, letter: b'm',
+                                   param: None })).collect();
+        let lines: Vec<Vec<u8>> = coalesce(&changes, 400).into_iter()
+            .map(|x| x.join(&b' ')).collect();
+        assert_eq!(lines, vec![b"-v+vv-v nick0 nick1 nick2 nick3".to_vec(),
+                               b"+vvm nick4 nick5".to_vec()]);
+        let lines = coalesce(&changes, 10);
+        assert_eq!(lines.len(), 6);
+        assert!(lines.iter().all(|x| x.iter().map(|x| x.len() + 1)
+                                 .sum::<usize>() <= 11));
+    }
+    fn run(channels: &mut Channels, user: &User, line: &str)
+           -> Vec<String> {
+        let message = Message::parse(line.as_bytes(), ParseMode::Strict)
+            .unwrap();
+        lines(channels.handle(user, &message, 1000).unwrap().unwrap())
+    }
+    #[test]
+    fn changing_modes() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen) = (user("fox"), user("vixen"));
+        run(&mut channels, &fox, "JOIN #den");
+        run(&mut channels, &vixen, "JOIN #den");
+        assert_eq!(run(&mut channels, &vixen, "MODE #den +m"), vec![
+            "vixen: :irc.example.com 482 vixen #den \
+             :You must have channel halfop access or above to set channel \
+             mode m",
+        ]);
+        assert_eq!(run(&mut channels, &fox,
+                       "MODE #den +ntvk-m+lX vixen sekrit 5"), vec![
+            "fox: :irc.example.com 472 fox X :is unknown mode char to me",
+            "fox: :fox!~u@host MODE #den +ntvkl vixen sekrit 5",
+            "vixen: :fox!~u@host MODE #den +ntvkl vixen sekrit 5",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, "NAMES #den")[0],
+                   "vixen: :irc.example.com 353 vixen = #den :~fox +vixen");
+        assert_eq!(run(&mut channels, &vixen, "MODE #den"), vec![
+            "vixen: :irc.example.com 324 vixen #den +klnt sekrit :5",
+            "vixen: :irc.example.com 329 vixen #den :1000",
+        ]);
+        // things that are already so aren't relayed
+        assert_eq!(run(&mut channels, &fox, "MODE #den +n-i+o wolf"), vec![
+            "fox: :irc.example.com 441 fox wolf #den \
+             :They aren't on that channel",
+        ]);
+        assert_eq!(run(&mut channels, &fox, "MODE #den -k+o * VIXEN")[0],
+                   "fox: :fox!~u@host MODE #den -k+o sekrit vixen");
+        let message = Message::parse(b"MODE wolf +i", ParseMode::Strict)
+            .unwrap();
+        assert!(channels.handle(&fox, &message, 1000).is_none());
+    }
+    #[test]
+    fn unrelayable_params() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let fox = user("fox");
+        run(&mut channels, &fox, "JOIN #den");
+        assert!(run(&mut channels, &fox, "MODE #den +k :a b").is_empty());
+        assert_eq!(channels.get(b"#den").unwrap().modes.key, None);
+        assert_eq!(run(&mut channels, &fox, "MODE #den +to :"), vec![
+            "fox: :fox!~u@host MODE #den +t",
+        ]);
+        assert!(run(&mut channels, &fox, "MODE #den +b ::wolf!*@*").is_empty());
+        assert!(channels.get(b"#den").unwrap().modes.list(b'b').is_empty());
+    }
+    #[test]
+    fn lists() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen) = (user("fox"), user("vixen"));
+        run(&mut channels, &fox, "JOIN #den");
+        assert_eq!(run(&mut channels, &fox, "MODE #den +bb-b wolf *@host \
+                                             nobody"), vec![
+            "fox: :fox!~u@host MODE #den +bb wolf!*@* *!*@host",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, "MODE #den b"), vec![
+            "vixen: :irc.example.com 367 vixen #den wolf!*@* fox!~u@host \
+             :1000",
+            "vixen: :irc.example.com 367 vixen #den *!*@host fox!~u@host \
+             :1000",
+            "vixen: :irc.example.com 368 vixen #den \
+             :End of channel ban list",
+        ]);
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den"), vec![
+            "vixen: :irc.example.com 474 vixen #den \
+             :Cannot join channel (+b)",
+        ]);
+        run(&mut channels, &fox, "MODE #den +e vixen");
+        assert_eq!(run(&mut channels, &vixen, "MODE #den e")[0],
+                   "vixen: :irc.example.com 348 vixen #den vixen!*@* \
+                    fox!~u@host :1000");
+        assert_eq!(run(&mut channels, &vixen, "MODE #den +I").last().unwrap(),
+                   "vixen: :irc.example.com 347 vixen #den \
+                    :End of channel invite list");
+        run(&mut channels, &fox, "MODE #den +ik sekrit");
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den sekrit")[0],
+                   "vixen: :irc.example.com 473 vixen #den \
+                    :Cannot join channel (+i)");
+        run(&mut channels, &fox, "MODE #den +I *!~u@*");
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den")[0],
+                   "vixen: :irc.example.com 475 vixen #den \
+                    :Cannot join channel (+k)");
+        run(&mut channels, &fox, "MODE #den +l 1");
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den sekrit")[0],
+                   "vixen: :irc.example.com 471 vixen #den \
+                    :Cannot join channel (+l)");
+        run(&mut channels, &fox, "MODE #den -l");
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den sekrit")[0],
+                   "fox: :vixen!~u@host JOIN #den");
+    }
+    #[test]
+    fn restrictions() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, vixen, wolf) = (user("fox"), user("vixen"), user("wolf"));
+        run(&mut channels, &fox, "JOIN #den");
+        run(&mut channels, &vixen, "JOIN #den");
+        run(&mut channels, &fox, "MODE #den +tnC");
+        assert_eq!(run(&mut channels, &vixen, "TOPIC #den :hi"), vec![
+            "vixen: :irc.example.com 482 vixen #den \
+             :You must have channel halfop access or above to set the topic",
+        ]);
+        let send = |channels: &Channels, user: &User, text: &[u8]| {
+            channels.get(b"#den").unwrap()
+                .check_send(&channels.candidate(user), text).is_ok()
+        };
+        assert!(send(&channels, &vixen, b"hi"));
+        assert!(send(&channels, &vixen, b"\x01ACTION hi\x01"));
+        assert!(!send(&channels, &vixen, b"\x01VERSION\x01"));
+        assert!(!send(&channels, &wolf, b"hi"));
+        run(&mut channels, &fox, "MODE #den +m-n");
+        assert!(send(&channels, &fox, b"hi"));
+        assert!(!send(&channels, &vixen, b"hi"));
+        assert!(!send(&channels, &wolf, b"hi"));
+        run(&mut channels, &fox, "MODE #den -m+b vixen");
+        assert!(!send(&channels, &vixen, b"hi"));
+    }
+    #[test]
+    fn extbans() {
+        let mut channels = Channels::new(SERVER, CaseMapping::Rfc1459);
+        let (fox, mut vixen, wolf) = (user("fox"), user("vixen"),
+                                      user("wolf"));
+        run(&mut channels, &fox, "JOIN #den");
+        run(&mut channels, &wolf, "JOIN #lair");
+        assert_eq!(run(&mut channels, &fox, "MODE #den +bbb $j:#LAIR \
+                                             $m:vixen $x"), vec![
+            "fox: :fox!~u@host MODE #den +bb $j:#LAIR $m:vixen!*@*",
+        ]);
+        assert_eq!(run(&mut channels, &wolf, "JOIN #den")[0],
+                   "wolf: :irc.example.com 474 wolf #den \
+                    :Cannot join channel (+b)");
+        // a mute lets vixen join, but not speak
+        assert_eq!(run(&mut channels, &vixen, "JOIN #den")[0],
+                   "fox: :vixen!~u@host JOIN #den");
+        let send = |channels: &Channels, user: &User| {
+            channels.get(b"#den").unwrap()
+                .check_send(&channels.candidate(user), b"hi").is_ok()
+        };
+        assert!(!send(&channels, &vixen));
+        vixen.account = Some(b"Vixen".to_vec());
+        run(&mut channels, &fox, "MODE #den +e $a:vix*");
+        assert!(send(&channels, &vixen));
+        run(&mut channels, &wolf, "PART #lair");
+        assert_eq!(run(&mut channels, &wolf, "JOIN #den")[0],
+                   "fox: :wolf!~u@host JOIN #den");
+        run(&mut channels, &fox, "MODE #den +b-e $~z $a:vix*");
+        assert!(!send(&channels, &vixen));
+        assert!(send(&channels, &fox));
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Member statuses: the `~&@%+` ladder of owner (`q`), admin (`a`), operator
+//! (`o`), halfop (`h`) and voice (`v`), which of them a server uses, and who
+//! may do what.
+//!
+//! Each mode in `CHANNEL_MODES` says which status is needed to change it.
+//! If a server doesn't use that status, the next more powerful one it does
+//! use is needed instead.
+
+use super::*;
+
+/// How powerful a prefix mode is. Lower is more powerful. Clients without
+/// any status rank below every prefix mode.
+pub type Rank = usize;
+
+/// The rank of a prefix mode, which is its place in `CHANNEL_MODES`.
+fn rank(letter: u8) -> Rank {
+    CHANNEL_MODES.iter().filter(|x| matches!(x.kind, ModeKind::Prefix(_)))
+        .position(|x| x.letter == letter).unwrap_or(Rank::MAX)
+}
+
+/// What a status is called in error messages.
+pub fn status_name(letter: u8) -> &'static str {
+    match letter {
+        b'q' => "owner",
+        b'a' => "admin",
+        b'o' => "op",
+        b'h' => "halfop",
+        b'v' => "voice",
+        _ => "special",
+    }
+}
+
+/// Which prefix modes a server uses.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Prefixes {
+    /// In `CHANNEL_MODES` order, i.e. most powerful first.
+    letters: Vec<u8>,
+}
+
+impl Default for Prefixes {
+    /// All of them.
+    fn default() -> Prefixes {
+        Prefixes {
+            letters: CHANNEL_MODES.iter()
+                .filter(|x| matches!(x.kind, ModeKind::Prefix(_)))
+                .map(|x| x.letter).collect(),
+        }
+    }
+}
+
+impl Prefixes {
+    /// Parses a set of prefix mode letters, such as `qaohv` or `ov`. `o`
+    /// must be among them.
+    pub fn parse(letters: &str) -> Result<Prefixes, String> {
+        let all = Prefixes::default();
+        if let Some(x) = letters.bytes().find(|x| !all.has(*x)) {
+            return Err(format!("Unknown prefix mode: {}", x as char))
+        }
+        if !letters.contains('o') {
+            return Err("The prefix modes must include o".to_owned())
+        }
+        Ok(Prefixes {
+            letters: all.letters.into_iter()
+                .filter(|x| letters.as_bytes().contains(x)).collect(),
+        })
+    }
+    /// The most powerful prefix mode in use, which a channel's creator gets.
+    pub fn highest(&self) -> u8 { self.letters[0] }
+    /// Is this prefix mode in use?
+    pub fn has(&self, letter: u8) -> bool { self.letters.contains(&letter) }
+    /// The value of the `PREFIX` token, e.g. `(qaohv)~&@%+`.
+    pub fn token(&self) -> Vec<u8> {
+        let mut ret = b"(".to_vec();
+        ret.extend_from_slice(&self.letters);
+        ret.push(b')');
+        ret.extend(self.letters.iter().filter_map(|x| {
+            match channel_mode(*x)?.kind {
+                ModeKind::Prefix(symbol) => Some(symbol),
+                _ => None,
+            }
+        }));
+        ret
+    }
+    /// The status actually needed where `letter` is asked for: `letter`
+    /// itself if it's in use, or else the nearest more powerful one that
+    /// is, or failing that, the most powerful one there is.
+    pub fn required(&self, letter: u8) -> u8 {
+        let wanted = rank(letter);
+        self.letters.iter().rev().copied().find(|x| rank(*x) <= wanted)
+            .unwrap_or_else(|| self.highest())
+    }
+    /// Does a member (or non-member, if `None`) have at least the status
+    /// that `letter` asks for?
+    pub fn allows(&self, member: Option<&Member>, letter: u8) -> bool {
+        member.map(|x| x.rank()).unwrap_or(Rank::MAX)
+            <= rank(self.required(letter))
+    }
+}
+
+impl Member {
+    /// Does the member have this prefix mode?
+    pub fn has(&self, letter: u8) -> bool { self.modes.contains(&letter) }
+    /// Does the member have any status at all? Any status is enough to
+    /// speak in a moderated channel.
+    pub fn is_voiced(&self) -> bool { !self.modes.is_empty() }
+    /// The rank of the most powerful status the member has.
+    pub fn rank(&self) -> Rank {
+        self.modes.first().map(|x| rank(*x)).unwrap_or(Rank::MAX)
+    }
+    /// Gives or takes away a prefix mode. Returns true if that changed
+    /// anything.
+    pub fn set(&mut self, letter: u8, on: bool) -> bool {
+        if self.has(letter) == on { return false }
+        if on {
+            self.modes.push(letter);
+            self.modes.sort_by_key(|x| rank(*x));
+        }
+        else { self.modes.retain(|x| *x != letter) }
+        true
+    }
+    /// The prefixes to show before the member's nick: all of them, most
+    /// powerful first, for clients with `multi-prefix`, or else just the
+    /// most powerful.
+    pub fn prefixes(&self, multi_prefix: bool) -> Vec<u8> {
+        let count = if multi_prefix { self.modes.len() } else { 1 };
+        self.modes.iter().take(count).filter_map(|x| {
+            match channel_mode(*x)?.kind {
+                ModeKind::Prefix(symbol) => Some(symbol),
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn prefixes() {
+        let all = Prefixes::default();
+        assert_eq!(all.token(), b"(qaohv)~&@%+");
+        let small = Prefixes::parse("vo").unwrap();
+        assert_eq!(small.token(), b"(ov)@+");
+        assert!(Prefixes::parse("hv").is_err());
+        assert!(Prefixes::parse("ox").is_err());
+        assert_eq!(all.required(b'h'), b'h');
+        assert_eq!(small.required(b'h'), b'o');
+        assert_eq!(small.required(b'q'), b'o');
+        assert_eq!(all.highest(), b'q');
+        assert_eq!(small.highest(), b'o');
+        let mut member = Member::new(User::new(b"fox", b"~u", b"host",
+                                               CaseMapping::Ascii));
+        assert!(!all.allows(Some(&member), b'v'));
+        assert!(member.set(b'v', true));
+        assert!(member.set(b'a', true));
+        assert!(!member.set(b'a', true));
+        assert!(all.allows(Some(&member), b'o'));
+        assert!(!all.allows(Some(&member), b'q'));
+        assert!(!all.allows(None, b'v'));
+        assert_eq!(member.prefixes(false), b"&");
+        assert_eq!(member.prefixes(true), b"&+");
+        member.set(b'a', false);
+        assert!(!small.allows(Some(&member), b'h'));
+    }
+}
@@ -2,6 +2,8 @@ use std::{
     net::SocketAddr,
 };
 
+use crate::*;
+
 use tokio::{
     prelude::*,
     io,
@@ -17,3 +19,118 @@ impl FoxyStream for TcpStream {
         TcpStream::peer_addr(self)
     }
 }
+
+/// Settings that apply to every connection accepted on a given listener.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct ListenerOptions {
+    /// How strictly to parse incoming lines.
+    pub parse_mode: ParseMode,
+    /// The encoding connections start out with. A client may change its own
+    /// encoding later; see `Connection::charset`.
+    pub encoding: Encoding,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> ListenerOptions {
+        ListenerOptions {
+            parse_mode: ParseMode::Lenient,
+            encoding: Encoding::Passthrough,
+        }
+    }
+}
+
+impl ListenerOptions {
+    /// Apply one comma-separated option from a listener specification.
+    pub fn apply(&mut self, option: &str) -> Result<(), String> {
+        match option {
+            "strict" => self.parse_mode = ParseMode::Strict,
+            "lenient" => self.parse_mode = ParseMode::Lenient,
+            x => match Encoding::from_name(x) {
+                Some(encoding) => self.encoding = encoding,
+                None => return Err(format!("Unknown listener option: {}",
+                                           x)),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// One client connection, registered or not.
+#[derive(Clone,Debug)]
+pub struct Connection {
+    /// The options of the listener that accepted it.
+    pub options: ListenerOptions,
+    encoding: Encoding,
+}
+
+impl Connection {
+    pub fn new(options: ListenerOptions) -> Connection {
+        Connection { options, encoding: options.encoding }
+    }
+    /// The encoding in use: the listener's, unless the client has chosen
+    /// another with `CHARSET`.
+    pub fn encoding(&self) -> Encoding { self.encoding }
+    /// `CHARSET [name]`. Switches the connection to the named encoding, if
+    /// given, and then says which one it's using. A `utf8only` listener has
+    /// promised UTF-8 to everyone, so its connections can't switch.
+    pub fn charset(&mut self, server: &[u8], name: Option<&[u8]>)
+                   -> Result<Message, &'static str> {
+        let source = Source::Server { name: server };
+        let reply = |command: &[u8], params: &[&[u8]]| {
+            let mut all: Vec<&[u8]> = vec![b"CHARSET"];
+            all.extend_from_slice(params);
+            Message::assemble(Some(&source), &Command::Textual(command),
+                              &all, true)
+        };
+        if let Some(name) = name {
+            if self.options.encoding == Encoding::Utf8Only {
+                return reply(b"FAIL", &[b"UTF8_REQUIRED", b"This server \
+                                        only accepts UTF-8 here"])
+            }
+            match std::str::from_utf8(name).ok()
+                .and_then(Encoding::from_name) {
+                Some(x) => self.encoding = x,
+                None => return reply(b"FAIL", &[b"UNKNOWN_CHARSET",
+                                                 b"Unknown encoding"]),
+            }
+        }
+        reply(b"NOTE", &[b"CURRENT", self.encoding.name().as_bytes(),
+                         b"Your encoding"])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const SERVER: &[u8] = b"irc.example.com";
+    fn raw(message: Message) -> String {
+        String::from_utf8_lossy(message.get_raw()).trim_end().to_owned()
+    }
+    #[test]
+    fn charset() {
+        let mut options = ListenerOptions::default();
+        options.apply("latin1").unwrap();
+        let mut connection = Connection::new(options);
+        assert_eq!(connection.encoding(), Encoding::Latin1);
+        assert_eq!(raw(connection.charset(SERVER, None).unwrap()),
+                   ":irc.example.com NOTE CHARSET CURRENT latin1 \
+                    :Your encoding");
+        assert_eq!(raw(connection.charset(SERVER, Some(b"cp1252"))
+                       .unwrap()),
+                   ":irc.example.com NOTE CHARSET CURRENT cp1252 \
+                    :Your encoding");
+        assert_eq!(connection.encoding(), Encoding::Cp1252);
+        assert_eq!(raw(connection.charset(SERVER, Some(b"a b"))
+                       .unwrap()),
+                   ":irc.example.com FAIL CHARSET UNKNOWN_CHARSET \
+                    :Unknown encoding");
+        assert_eq!(connection.encoding(), Encoding::Cp1252);
+        options.apply("utf8only").unwrap();
+        let mut connection = Connection::new(options);
+        assert_eq!(raw(connection.charset(SERVER, Some(b"latin1"))
+                       .unwrap()),
+                   ":irc.example.com FAIL CHARSET UTF8_REQUIRED \
+                    :This server only accepts UTF-8 here");
+        assert_eq!(connection.encoding(), Encoding::Utf8Only);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Client-To-Client Protocol. CTCP payloads ride inside the text of a
+//! `PRIVMSG` (queries) or `NOTICE` (replies), delimited by `\x01`. The server
+//! mostly passes them along, but needs to look inside for a few things: the
+//! channel mode that blocks CTCPs, probing client versions, and writing
+//! `ACTION`s sensibly into logs.
+
+use crate::*;
+
+/// The byte that delimits a CTCP payload.
+pub const DELIM: u8 = 0x01;
+
+/// A parsed CTCP payload.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Ctcp<'a> {
+    /// The CTCP command, as sent. Compare it case-insensitively.
+    pub command: &'a [u8],
+    /// Everything after the command and its space, if anything was.
+    pub args: Option<&'a [u8]>,
+}
+
+impl<'a> Ctcp<'a> {
+    /// Parse the text of a `PRIVMSG` or `NOTICE` as CTCP. Returns `None` if
+    /// it isn't one. The closing delimiter is optional, as some clients omit
+    /// it.
+    pub fn parse(text: &'a [u8]) -> Option<Ctcp<'a>> {
+        if text.len() < 2 || text[0] != DELIM { return None }
+        let body = &text[1..];
+        let body = match body.iter().position(|x| *x == DELIM) {
+            Some(end) => &body[..end],
+            None => body,
+        };
+        let (command, args) = match body.iter().position(|x| *x == b' ') {
+            Some(space) => (&body[..space], Some(&body[space+1..])),
+            None => (body, None),
+        };
+        if command.is_empty() { None }
+        else { Some(Ctcp { command, args }) }
+    }
+    /// Is this CTCP the given command?
+    pub fn is(&self, command: &[u8]) -> bool {
+        self.command.eq_ignore_ascii_case(command)
+    }
+    /// Is this a `/me` action?
+    pub fn is_action(&self) -> bool { self.is(b"ACTION") }
+    /// Encode this CTCP for use as the text of a `PRIVMSG` or `NOTICE`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.command.len()
+                                         + self.args.map(|x| x.len() + 1)
+                                         .unwrap_or(0) + 2);
+        ret.push(DELIM);
+        ret.extend_from_slice(self.command);
+        if let Some(args) = self.args {
+            ret.push(b' ');
+            ret.extend_from_slice(args);
+        }
+        ret.push(DELIM);
+        ret
+    }
+}
+
+/// Returns true if `text` is a CTCP other than `ACTION`. These are what the
+/// no-CTCP channel mode blocks.
+pub fn is_non_action_ctcp(text: &[u8]) -> bool {
+    Ctcp::parse(text).map(|x| !x.is_action()).unwrap_or(false)
+}
+
+/// Makes the `PRIVMSG` that asks a newly-connected client what software it's
+/// running.
+pub fn version_probe(server: &[u8], nick: &[u8])
+                     -> Result<Message, &'static str> {
+    let query = Ctcp { command: b"VERSION", args: None }.encode();
+    Message::assemble(Some(&Source::Server { name: server }),
+                      &Command::Textual(b"PRIVMSG"),
+                      &[nick, &query[..]], true)
+}
+
+/// If `message` is a client's reply to `version_probe`, returns the version
+/// it reported.
+pub fn version_reply(message: &Message) -> Option<&[u8]> {
+    if message.get_command() != Command::Textual(b"NOTICE")
+    || message.get_param_count() != 2 {
+        return None
+    }
+    let ctcp = Ctcp::parse(message.get_nth_param(1)?)?;
+    if ctcp.is(b"VERSION") { ctcp.args } else { None }
+}
+
+/// Makes the server notice that tells an oper what version a client
+/// reported.
+pub fn version_notice(server: &[u8], oper: &[u8], nick: &[u8],
+                      version: &[u8]) -> Result<Message, &'static str> {
+    let mut text = b"*** CTCP VERSION reply from ".to_vec();
+    text.extend_from_slice(nick);
+    text.extend_from_slice(b": ");
+    text.extend_from_slice(&formatting::strip(version));
+    Message::assemble(Some(&Source::Server { name: server }),
+                      &Command::Textual(b"NOTICE"),
+                      &[oper, &text[..]], true)
+}
+
+/// Renders a `PRIVMSG` from `nick` as a line for a human-readable log or
+/// history view. Actions come out as `* nick waves`, other CTCPs as
+/// `-nick- CTCP VERSION`, and everything else as `<nick> text`.
+pub fn log_line(nick: &[u8], text: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(nick.len() + text.len() + 3);
+    match Ctcp::parse(text) {
+        Some(ctcp) if ctcp.is_action() => {
+            ret.extend_from_slice(b"* ");
+            ret.extend_from_slice(nick);
+            if let Some(args) = ctcp.args {
+                ret.push(b' ');
+                ret.extend_from_slice(args);
+            }
+        },
+        Some(ctcp) => {
+            ret.push(b'-');
+            ret.extend_from_slice(nick);
+            ret.extend_from_slice(b"- CTCP ");
+            ret.extend(ctcp.command.iter().map(|x| upcase(*x)));
+        },
+        None => {
+            ret.push(b'<');
+            ret.extend_from_slice(nick);
+            ret.extend_from_slice(b"> ");
+            ret.extend_from_slice(text);
+        },
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse() {
+        assert_eq!(Ctcp::parse(b"\x01ACTION waves hello\x01"),
+                   Some(Ctcp { command: b"ACTION",
+                               args: Some(b"waves hello") }));
+        assert_eq!(Ctcp::parse(b"\x01VERSION"),
+                   Some(Ctcp { command: b"VERSION", args: None }));
+        assert_eq!(Ctcp::parse(b"just text"), None);
+        assert_eq!(Ctcp::parse(b"\x01\x01"), None);
+        assert!(Ctcp::parse(b"\x01action hi\x01").unwrap().is_action());
+        let ctcp = Ctcp { command: b"PING", args: Some(b"1234") };
+        assert_eq!(ctcp.encode(), b"\x01PING 1234\x01");
+        assert_eq!(Ctcp::parse(&ctcp.encode()), Some(ctcp));
+    }
+    #[test]
+    fn blocking() {
+        assert!(is_non_action_ctcp(b"\x01VERSION\x01"));
+        assert!(!is_non_action_ctcp(b"\x01ACTION dances\x01"));
+        assert!(!is_non_action_ctcp(b"hello"));
+    }
+    #[test]
+    fn version() {
+        let probe = version_probe(b"irc.example.com", b"nick").unwrap();
+        assert_eq!(probe.get_raw(),
+                   b":irc.example.com PRIVMSG nick :\x01VERSION\x01\r\n");
+        let reply = Message::parse(b":nick!u@h NOTICE irc.example.com \
+                                     :\x01VERSION HexChat 2.14\x01",
+                                   ParseMode::Strict).unwrap();
+        assert_eq!(version_reply(&reply), Some(&b"HexChat 2.14"[..]));
+        let notice = version_notice(b"irc.example.com", b"oper", b"nick",
+                                    b"\x02HexChat\x02 2.14").unwrap();
+        assert_eq!(notice.get_nth_param(1).unwrap(),
+                   b"*** CTCP VERSION reply from nick: HexChat 2.14");
+    }
+    #[test]
+    fn logging() {
+        assert_eq!(log_line(b"fox", b"\x01ACTION yips\x01"), b"* fox yips");
+        assert_eq!(log_line(b"fox", b"\x01ping 1\x01"), b"-fox- CTCP PING");
+        assert_eq!(log_line(b"fox", b"hi"), b"<fox> hi");
+    }
+}
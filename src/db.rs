@@ -16,152 +16,448 @@
  */
 
 use std::{
-    collections::hash_map::HashMap,
+    collections::hash_map::{Entry, HashMap},
+    fmt::{Display, Formatter},
+    future::Future,
     io::ErrorKind,
-    path::PathBuf,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
 };
 use serde_json::Value;
 use tokio::{
-    fs::File,
-    io::AsyncReadExt,
-    sync::RwLock,
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{oneshot, RwLock},
 };
 
+mod key;
+pub use key::*;
+mod backend;
+pub use backend::*;
+mod cache;
+pub use cache::{CacheLimits, CacheStats};
+use cache::*;
+mod watch;
+mod transaction;
+pub use transaction::Transaction;
+mod schema;
+pub use schema::*;
+mod archive;
+pub use archive::*;
+
+/// Something went wrong with the database.
+#[derive(Clone,Debug)]
+pub enum DbError {
+    /// An I/O error occurred while accessing the given file.
+    Io(PathBuf, Arc<std::io::Error>),
+    /// A write to the given file was abandoned, probably because the server
+    /// is shutting down.
+    Abandoned(PathBuf),
+    /// The given key doesn't follow the key grammar, for the given reason.
+    InvalidKey(String, &'static str),
+    /// The datum with the given key isn't what it should be, for the given
+    /// reason. (See `get_as`.)
+    Invalid(String, String),
+}
+
+impl Display for DbError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            DbError::Io(path, x) => write!(fmt, "{:?}: {}", path, x),
+            DbError::Abandoned(path)
+                => write!(fmt, "{:?}: write abandoned", path),
+            DbError::InvalidKey(key, why)
+                => write!(fmt, "invalid key {:?}: {}", key, why),
+            DbError::Invalid(key, why)
+                => write!(fmt, "invalid datum {:?}: {}", key, why),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// Turns an I/O error into a `DbError` about the given file.
+fn io_err(path: &Path) -> impl Fn(std::io::Error) -> DbError {
+    let path = path.to_owned();
+    move |x| DbError::Io(path.clone(), Arc::new(x))
+}
+
+/// Corrects a sorted list of the keys the backend has that start with
+/// `prefix` by what's in the cache, which may know of inserts and deletions
+/// the backend hasn't finished yet.
+fn merge_with_cache(mut keys: Vec<String>, cache: &Cache, prefix: &str)
+                    -> Vec<String> {
+    let mut changed = false;
+    let mut present = HashMap::new();
+    for (key, positive) in cache.with_prefix(prefix) {
+        present.insert(key, positive);
+    }
+    keys.retain(|key| match present.remove(key.as_str()) {
+        Some(false) => { changed = true; false },
+        _ => true,
+    });
+    for (key, positive) in present.into_iter() {
+        if positive {
+            keys.push(key.to_owned());
+            changed = true;
+        }
+    }
+    if changed { keys.sort() }
+    keys
+}
+
 pub struct Db {
-    backing_paths: Vec<PathBuf>,
-    cache: RwLock<HashMap<String, Option<Arc<Value>>>>,
-    verbose: bool,
+    backend: Box<dyn Backend>,
+    cache: RwLock<Cache>,
+    /// When data last changed. Changed under the cache's write lock, along
+    /// with the cache. Lets a read from the backend notice that the datum
+    /// changed while it was reading.
+    versions: Mutex<Versions>,
+    /// See `register_schema`.
+    schemas: Vec<Schema>,
 }
 
 impl Db {
-    pub fn new(backing_paths: Vec<PathBuf>, verbose: bool) -> Db {
+    /// Makes a new `Db` backed by JSON files in the given directories,
+    /// highest priority first. See `JsonDir`.
+    pub fn new(backing_paths: Vec<PathBuf>) -> Db {
+        Db::with_backend(Box::new(JsonDir::new(backing_paths)))
+    }
+    /// Makes a new `Db` backed by the given backend.
+    pub fn with_backend(backend: Box<dyn Backend>) -> Db {
         Db {
-            backing_paths, verbose,
-            cache: RwLock::new(HashMap::new()),
+            backend,
+            cache: RwLock::new(Cache::new(CacheLimits::default())),
+            versions: Mutex::new(Versions::default()),
+            schemas: SCHEMAS.to_vec(),
         }
     }
+    /// Describes where the data are kept, for messages.
+    pub fn describe(&self) -> String { self.backend.describe() }
     /// Clears the cache. Boom!
     pub async fn rehash(&self) {
         let mut cache = self.cache.write().await;
         cache.clear();
-        if self.verbose {
-            eprintln!("DB: Rehash!");
-        }
+        debug!("db", "Rehash!");
     }
     /// Attempts to get a datum from the cache. Returns `None` if no cache
-    /// entry for this path, or `Some(...)` if there is an entry. Note that
-    /// this can return `Some(None)` if the cached entry is that there is no
-    /// entry!
+    /// entry for this path, or `Some(...)` if there is an entry, along with
+    /// the generation it was current at. Note that this can return
+    /// `Some((None, ...))` if the cached entry is that there is no entry!
     ///
     /// Performs a read lock.
-    async fn get_from_cache(&self, path: &str) -> Option<Option<Arc<Value>>> {
-        self.cache.read().await.get(path).map(|x| x.clone())
+    async fn get_from_cache(&self, path: &str)
+                            -> Option<(Option<Arc<Value>>, u64)> {
+        let cache = self.cache.read().await;
+        let value = cache.get(path)?;
+        Some((value, self.versions.lock().unwrap().generation()))
     }
-    /// Attempts to get a datum from the filesystem. Returns `None` if no
-    /// backing directory has a valid file for this path, or `Some(...)` if one
-    /// did.
+    /// Attempts to get a datum from the backend. Returns `None` if it
+    /// doesn't have a valid one.
     ///
     /// **DOES NOT LOCK.** Doesn't need to.
-    async fn get_from_fs(&self, path: &str) -> Option<Value> {
-        for back in &self.backing_paths {
-            let load_path = back.join(path);
-            let file = File::open(&load_path).await;
-            match file {
-                Ok(mut f) => {
-                    let mut buf = Vec::new();
-                    match f.read_to_end(&mut buf).await {
-                        Ok(_) => (),
-                        Err(x) => {
-                            eprintln!("Warning: Attempting to read {:?}: {}",
-                                      load_path, x);
-                            continue
-                        },
-                    }
-                    match serde_json::from_slice(&buf[..]) {
-                        Ok(x) => {
-                            if self.verbose {
-                                eprintln!("DB: {:?} satisfied by {:?}",
-                                          path, load_path);
-                            }
-                            return Some(x)
-                        },
-                        Err(x) => {
-                            eprintln!("Warning: Attempting to parse {:?}: {}",
-                                      load_path, x);
-                            continue
-                        },
-                    }
-                },
-                Err(x) if x.kind() == ErrorKind::NotFound => {
-                    // Routine. Continue.
-                    continue
-                },
-                Err(x) => {
-                    eprintln!("Warning: Attempting to open {:?}: {}",
-                              load_path, x);
-                    continue
-                },
-            }
-            // unreachable
+    async fn get_from_backend(&self, path: &str) -> Option<Value> {
+        match self.backend.load(path, false).await {
+            Ok(x) => x,
+            Err(x) => {
+                warn!("db", "Attempting to load {}", x);
+                None
+            },
         }
-        if self.verbose {
-            eprintln!("DB: {:?} not satisfied", path);
-        }
-        None
     }
-    /// Put a value into the cache, but only if nobody has updated that datum
-    /// with a different value since a previous `get_from_cache`.
-    async fn put_into_cache(&self, path: String,
-                            old_value: Option<Option<Arc<Value>>>,
+    /// Put a value read from the backend into the cache, unless the cache
+    /// already has one, and return whichever is there, along with the
+    /// generation it's current at. Returns `None`, changing nothing, if the
+    /// datum changed since the read started at generation `start`, since
+    /// the value read may be stale.
+    async fn put_into_cache(&self, path: &str, start: u64,
                             new_value: Option<Arc<Value>>)
-                            -> Option<Arc<Value>>{
+                            -> Option<(Option<Arc<Value>>, u64)> {
         let mut cache = self.cache.write().await;
-        let cur_value = cache.get(&path).map(|x| x.clone());
-        if cur_value == old_value {
-            cache.insert(path, new_value.clone());
-            new_value
+        let versions = self.versions.lock().unwrap();
+        if let Some(cur_value) = cache.peek(path) {
+            return Some((cur_value.clone(), versions.generation()))
         }
-        else {
-            if self.verbose {
-                eprintln!("DB: {:?} changed between a get and a set!", path);
-            }
-            cur_value.and_then(|x| x.clone())
+        if versions.changed_since(path, start) {
+            debug!("db", "{:?} changed between a get and a set!", path);
+            return None
         }
+        cache.insert(path.to_owned(), new_value.clone(),
+                     |x| self.backend.is_storing(x));
+        Some((new_value, versions.generation()))
+    }
+    /// Get a datum from the database. May hit the backend if the datum isn't
+    /// yet cached. Fails only if `path` isn't a valid key.
+    pub async fn get(&self, path: &str)
+                     -> Result<Option<Arc<Value>>, DbError> {
+        validate_key(path)?;
+        Ok(self.get_observed(path).await.0)
     }
-    /// Get a datum from the database. May hit the filesystem if the datum
-    /// isn't yet cached.
-    pub async fn get(&self, path: &str) -> Option<Arc<Value>> {
-        // TODO: validate path sanity
-        // Try to get it from the cache (reader lock involved)
-        if let Some(value) = self.get_from_cache(path).await {
-            return value
+    /// Does the work of `get`, once `path` is known to be valid. Also
+    /// returns the generation the datum was current at.
+    async fn get_observed(&self, path: &str) -> (Option<Arc<Value>>, u64) {
+        loop {
+            // Start the read before looking in the cache, so that any change
+            // after the look is noticed.
+            let read = ReadGuard::new(&self.versions);
+            // Try to get it from the cache (reader lock involved)
+            if let Some(value) = self.get_from_cache(path).await {
+                return value
+            }
+            // okay, it wasn't in the cache. try to get it from the backend
+            // (no locks involved)
+            let result = self.get_from_backend(path).await.map(Arc::new);
+            // and then try to put the result, positive or negative, into the
+            // cache (writer lock involved), and return whatever's in the
+            // cache now, even if it's not what we tried to put in. If the
+            // datum changed and was evicted meanwhile, read it again.
+            if let Some(value) = self.put_into_cache(path, read.start,
+                                                     result).await {
+                return value
+            }
         }
-        // okay, it wasn't in the cache. try to get it from the filesystem (no
-        // locks involved)
-        let result = self.get_from_fs(path).await.map(|x| Arc::new(x));
-        // and then try to put the result, positive or negative, into the cache
-        // (writer lock involved)
-        self.put_into_cache(path.to_owned(), None, result.clone()).await
-        // return whatever's in the cache now, even if it's not what we tried
-        // to put in
-    }
-    /// Put a datum into the database. Hits the filesystem if the datum has
-    /// changed.
-    pub async fn insert(&self, path: &str, datum: Value) {
-        // We don't need to check the cache. Ordering for critical keys must be
-        // ensured by outside locks. The only operation that won't be caught
-        // by an outside lock is when inserting a value that isn't yet cached
-        // and someone else is populating the cache from the backing value at
-        // the same time. We have sufficient ABA protection logic in place on
-        // the inside to handle that.
+    }
+    /// Put a datum into the database. The cache is updated immediately, so
+    /// subsequent `get`s will see the new datum right away. The datum is then
+    /// stored by the backend in the background; the returned future completes
+    /// once that store, or a later one to the same path, is done.
+    ///
+    /// This doesn't care what was there before. Use `compare_and_swap` or a
+    /// `Transaction` if it matters.
+    pub async fn insert(&self, path: &str, datum: Value)
+                        -> Result<(), DbError> {
+        validate_key(path)?;
+        self.store(path, Some(Arc::new(datum))).await
+    }
+    /// Delete a datum from the database, as `insert`. Afterwards, `get`
+    /// returns `None` for it, even if a lower-priority backing directory
+    /// still has it.
+    pub async fn remove(&self, path: &str) -> Result<(), DbError> {
+        validate_key(path)?;
+        self.store(path, None).await
+    }
+    /// Does the work of `insert` and `remove`.
+    async fn store(&self, path: &str, datum: Option<Arc<Value>>)
+                   -> Result<(), DbError> {
+        // The only operation this can race with, other than another insert
+        // (last one wins), is someone populating the cache from the backing
+        // value at the same time. We have sufficient ABA protection logic in
+        // place on the inside to handle that.
         // TODO: avoid to_owned() if entry already exists?
         let mut cache = self.cache.write().await;
-        cache.insert(path.to_owned(), Some(Arc::new(datum)));
+        // Start the store while holding the cache lock, so that the backend
+        // sees updates in the same order the cache does. Start it first, so
+        // that the cache won't evict the datum before the backend has it.
+        let written = self.backend.store(vec![(path.to_owned(),
+                                               datum.clone())]);
+        cache.insert(path.to_owned(), datum,
+                     |x| self.backend.is_storing(x));
+        self.versions.lock().unwrap().bump(path);
+        drop(cache);
+        written.await
+    }
+    /// Lists, in order, the keys of every datum whose key starts with
+    /// `prefix`, as `get` would see them: data in higher-priority places
+    /// hide those in lower ones, deleted data are left out, and inserts
+    /// whose stores haven't finished yet are counted. `""` lists every key.
+    ///
+    /// Only the directory that `prefix` points into (everything up to its
+    /// last `/`) needs to be a valid key.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, DbError> {
+        let keys = self.backend.keys(prefix).await?;
+        Ok(merge_with_cache(keys, &*self.cache.read().await, prefix))
+    }
+    /// Gets every datum whose key starts with `prefix`, in key order. See
+    /// `list`.
+    pub async fn scan(&self, prefix: &str)
+                      -> Result<Vec<(String, Arc<Value>)>, DbError> {
+        let keys = self.list(prefix).await?;
+        let mut ret = Vec::with_capacity(keys.len());
+        for key in keys.into_iter() {
+            // It may have been deleted since we listed it.
+            if let Some(datum) = self.get(&key).await? {
+                ret.push((key, datum));
+            }
+        }
+        Ok(ret)
+    }
+    /// Re-read a datum from the backend, because something other than us may
+    /// have changed it. Does nothing if the datum isn't cached, since the
+    /// next `get` will read it anyway. If the datum is damaged, the cached
+    /// one is kept, with a warning, rather than silently falling back to
+    /// e.g. a lower-priority directory.
+    ///
+    /// Also does nothing if we have a store of our own in flight for this
+    /// datum, or if it was changed while we were reading. Either way, our
+    /// own datum is newer, and is written over whatever changed on the disk.
+    pub async fn reload(&self, path: &str) -> Result<(), DbError> {
+        validate_key(path)?;
+        let read = ReadGuard::new(&self.versions);
+        if self.cache.read().await.peek(path).is_none() { return Ok(()) }
+        let result = match self.backend.load(path, true).await {
+            Ok(x) => x.map(Arc::new),
+            Err(x) => {
+                warn!("db", "Keeping the old value of {:?}: {}",
+                      path, x);
+                return Ok(())
+            },
+        };
+        let mut cache = self.cache.write().await;
+        let mut versions = self.versions.lock().unwrap();
+        if versions.changed_since(path, read.start)
+        || self.backend.is_storing(path) {
+            return Ok(())
+        }
+        if cache.peek(path).is_some() {
+            debug!("db", "{:?} reloaded", path);
+            cache.insert(path.to_owned(), result,
+                         |x| self.backend.is_storing(x));
+            versions.bump(path);
+        }
+        Ok(())
+    }
+    /// Returns statistics about the cache, for opers.
+    pub async fn cache_stats(&self) -> CacheStats {
+        self.cache.read().await.stats()
+    }
+    /// Changes how big the cache may get, evicting entries right away if
+    /// it's now too big.
+    pub async fn set_cache_limits(&self, limits: CacheLimits) {
+        self.cache.write().await
+            .set_limits(limits, |x| self.backend.is_storing(x));
+    }
+    /// Makes sure that, once cached, the datum with key `pattern` is never
+    /// evicted. If `pattern` ends with `/`, all data whose keys start with it
+    /// are pinned instead. Meant for things like configuration, which must
+    /// not vanish because someone was busy doing WHOIS on random nicks.
+    pub async fn pin(&self, pattern: &str) {
+        self.cache.write().await.pin(pattern);
     }
 }
 
-// PATH SANITIZATION: must not end in ~, must end in .cj, %XX? must not contain
-// . leading or after slash?
-// TODO: purge feature, and automatically use it if we have hundreds of
-// thousands of cached Nones
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+    /// Make an empty directory to hold a test database.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("foxy-ircd-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    #[tokio::test]
+    async fn write_through() {
+        let dir = test_dir("write_through");
+        let db = Db::new(vec![dir.clone()]);
+        db.insert("motd", json!("Welcome!")).await.unwrap();
+        db.insert("accounts/fox", json!({"password": "hunter2"})).await
+            .unwrap();
+        assert!(dir.join("motd.cj").exists());
+        assert!(!dir.join("motd.cj~").exists());
+        // a fresh Db with an empty cache has to get it from the disk
+        let db = Db::new(vec![dir.clone()]);
+        assert_eq!(db.get("motd").await.unwrap().as_deref(),
+                   Some(&json!("Welcome!")));
+        assert_eq!(db.get("accounts/fox").await.unwrap().as_deref(),
+                   Some(&json!({"password": "hunter2"})));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn coalesced_writes() {
+        let dir = test_dir("coalesced_writes");
+        let db = Arc::new(Db::new(vec![dir.clone()]));
+        let tasks: Vec<_> = (0 .. 50).map(|n| {
+            let db = db.clone();
+            tokio::spawn(async move { db.insert("counter", json!(n)).await })
+        }).collect();
+        for task in tasks { task.await.unwrap().unwrap() }
+        db.insert("counter", json!("last")).await.unwrap();
+        let db = Db::new(vec![dir.clone()]);
+        assert_eq!(db.get("counter").await.unwrap().as_deref(),
+                   Some(&json!("last")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn write_errors() {
+        let dir = test_dir("write_errors");
+        // a file where a directory needs to be
+        std::fs::write(dir.join("blocker"), b"").unwrap();
+        std::fs::write(dir.join("secret"), b"\"secret\"").unwrap();
+        let db = Db::new(vec![dir.clone()]);
+        assert!(db.insert("blocker/key", json!(1)).await.is_err());
+        let sub = dir.join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        let db = Db::new(vec![sub]);
+        assert!(matches!(db.get("../secret").await,
+                         Err(DbError::InvalidKey(..))));
+        assert!(matches!(db.insert("../escaped", json!(1)).await,
+                         Err(DbError::InvalidKey(..))));
+        assert!(!dir.join("escaped.cj").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn layered_listing() {
+        let dir = test_dir("layered_listing");
+        let local = dir.join("local");
+        let defaults = dir.join("defaults");
+        std::fs::create_dir_all(defaults.join("opers")).unwrap();
+        std::fs::write(defaults.join("motd.cj"), b"\"default\"").unwrap();
+        std::fs::write(defaults.join("opers/fox.cj"), b"1").unwrap();
+        std::fs::write(defaults.join("opers/vixen.cj"), b"2").unwrap();
+        let db = Db::new(vec![local.clone(), defaults.clone()]);
+        db.insert("opers/kit", json!(3)).await.unwrap();
+        db.insert("opers/fox", json!(4)).await.unwrap();
+        db.remove("opers/vixen").await.unwrap();
+        assert!(local.join("opers/vixen.wh").exists());
+        assert!(defaults.join("opers/vixen.cj").exists());
+        assert_eq!(db.list("opers/").await.unwrap(),
+                   vec!["opers/fox", "opers/kit"]);
+        // a fresh Db has to work it out from the disk
+        let db = Db::new(vec![local.clone(), defaults.clone()]);
+        assert_eq!(db.get("opers/vixen").await.unwrap(), None);
+        assert_eq!(db.scan("opers/").await.unwrap(),
+                   vec![("opers/fox".to_owned(), Arc::new(json!(4))),
+                        ("opers/kit".to_owned(), Arc::new(json!(3)))]);
+        assert_eq!(db.list("").await.unwrap(),
+                   vec!["motd", "opers/fox", "opers/kit"]);
+        assert_eq!(db.list("opers/k").await.unwrap(), vec!["opers/kit"]);
+        assert!(db.list("../").await.is_err());
+        // bringing it back removes the whiteout
+        db.insert("opers/vixen", json!(5)).await.unwrap();
+        assert!(!local.join("opers/vixen.wh").exists());
+        assert_eq!(db.list("opers/v").await.unwrap(), vec!["opers/vixen"]);
+        // with only one directory, deleting just deletes
+        db.remove("opers/kit").await.unwrap();
+        let db = Db::new(vec![local.clone()]);
+        db.remove("opers/fox").await.unwrap();
+        assert!(!local.join("opers/fox.cj").exists());
+        assert!(!local.join("opers/fox.wh").exists());
+        assert_eq!(db.list("").await.unwrap(), vec!["opers/vixen"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn bounded_cache() {
+        let db = Db::with_backend(Box::new(Memory::new()));
+        db.set_cache_limits(CacheLimits { max_bytes: 1 << 20,
+                                          max_negative: 100 }).await;
+        db.pin("conf/").await;
+        db.insert("conf/motd", json!("Welcome!")).await.unwrap();
+        for n in 0 .. 1000 {
+            assert_eq!(db.get(&format!("nicks/{}", n)).await.unwrap(), None);
+        }
+        let stats = db.cache_stats().await;
+        assert_eq!(stats.negative_entries, 100);
+        assert_eq!(stats.pinned_entries, 1);
+        assert_eq!(stats.misses, 1000);
+        assert_eq!(stats.evictions, 900);
+        // squeeze out everything but the pinned entry
+        db.set_cache_limits(CacheLimits { max_bytes: 0,
+                                          max_negative: 0 }).await;
+        assert_eq!(db.cache_stats().await.entries, 1);
+        assert_eq!(db.get("conf/motd").await.unwrap().as_deref(),
+                   Some(&json!("Welcome!")));
+        assert_eq!(db.cache_stats().await.hits, 1);
+    }
+}
@@ -0,0 +1,384 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Snapshots of the whole database in a single file, for backups.
+//!
+//! An archive holds the database as `get` sees it: merged across every
+//! backing directory, without whiteouts. It comes in two formats:
+//!
+//! - JSON Lines: a header line, `{"format": "foxy-ircd-db", "version": 1}`,
+//!   and then one `{"key": KEY, "value": DATUM}` line per datum, in key order.
+//! - A tarball: a `foxy-ircd-db.json` file holding the same header, and then
+//!   one `data/KEY.cj` file per datum.
+
+use super::*;
+
+mod tar;
+
+/// The `format` member of an archive's header.
+const ARCHIVE_FORMAT: &str = "foxy-ircd-db";
+/// The `version` member of the header of archives we write. We can read
+/// this version and any earlier one.
+pub const ARCHIVE_VERSION: u64 = 1;
+/// Name of the header file in a tarball.
+const TAR_HEADER: &str = "foxy-ircd-db.json";
+/// What data file names in a tarball start with.
+const TAR_DATA: &str = "data/";
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ArchiveFormat {
+    JsonLines,
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// Picks a format from a file name: `.tar` means a tarball, anything
+    /// else JSON Lines.
+    pub fn from_path(path: &Path) -> ArchiveFormat {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("tar") => ArchiveFormat::Tar,
+            _ => ArchiveFormat::JsonLines,
+        }
+    }
+}
+
+/// How a datum in an archive differs from the database.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Difference {
+    /// Only the archive has it.
+    Added(String),
+    /// Only the database has it.
+    Removed(String),
+    /// They both have it, but disagree.
+    Changed(String),
+}
+
+impl Display for Difference {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Difference::Added(key) => write!(fmt, "+ {}", key),
+            Difference::Removed(key) => write!(fmt, "- {}", key),
+            Difference::Changed(key) => write!(fmt, "~ {}", key),
+        }
+    }
+}
+
+fn header() -> Value {
+    let mut header = serde_json::Map::new();
+    header.insert("format".to_owned(), ARCHIVE_FORMAT.into());
+    header.insert("version".to_owned(), ARCHIVE_VERSION.into());
+    Value::Object(header)
+}
+
+fn check_header(header: &Value) -> Result<(), String> {
+    if header.get("format").and_then(Value::as_str) != Some(ARCHIVE_FORMAT) {
+        return Err("not a Foxy IRCd database archive".to_owned())
+    }
+    match header.get("version").and_then(Value::as_u64) {
+        Some(x) if x <= ARCHIVE_VERSION => Ok(()),
+        Some(x) => Err(format!("archive version {} is newer than this \
+                                server understands ({})",
+                               x, ARCHIVE_VERSION)),
+        None => Err("archive version is missing".to_owned()),
+    }
+}
+
+/// Turns a snapshot into an archive.
+pub fn encode_archive(data: &[(String, Arc<Value>)], format: ArchiveFormat)
+                      -> Vec<u8> {
+    let mut out = Vec::new();
+    match format {
+        ArchiveFormat::JsonLines => {
+            out.extend(header().to_string().bytes());
+            out.push(b'\n');
+            for (key, datum) in data.iter() {
+                let mut line = serde_json::Map::new();
+                line.insert("key".to_owned(), key.as_str().into());
+                line.insert("value".to_owned(), Value::clone(datum));
+                out.extend(Value::Object(line).to_string().bytes());
+                out.push(b'\n');
+            }
+        },
+        ArchiveFormat::Tar => {
+            let mut buf = serde_json::to_vec_pretty(&header())
+                .expect("serializing a Value can't fail");
+            buf.push(b'\n');
+            tar::put_file(&mut out, TAR_HEADER, &buf[..]);
+            for (key, datum) in data.iter() {
+                let mut buf = serde_json::to_vec_pretty(&**datum)
+                    .expect("serializing a Value can't fail");
+                buf.push(b'\n');
+                tar::put_file(&mut out,
+                              &format!("{}{}{}", TAR_DATA, key, EXTENSION),
+                              &buf[..]);
+            }
+            tar::finish(&mut out);
+        },
+    }
+    out
+}
+
+/// Reads an archive of either format, returning its data in key order.
+pub fn decode_archive(bytes: &[u8]) -> Result<Vec<(String, Value)>, String> {
+    let mut data = Vec::new();
+    if tar::is_tar(bytes) {
+        let mut files = tar::files(bytes)?.into_iter();
+        match files.next() {
+            Some((name, contents)) if name == TAR_HEADER => {
+                check_header(&serde_json::from_slice(contents)
+                             .map_err(|x| format!("{}: {}", name, x))?)?;
+            },
+            _ => return Err("not a Foxy IRCd database archive".to_owned()),
+        }
+        for (name, contents) in files {
+            let key = name.strip_prefix(TAR_DATA)
+                .and_then(|x| x.strip_suffix(EXTENSION))
+                .ok_or_else(|| format!("{}: not a datum", name))?;
+            let datum = serde_json::from_slice(contents)
+                .map_err(|x| format!("{}: {}", name, x))?;
+            data.push((key.to_owned(), datum));
+        }
+    }
+    else {
+        let mut lines = bytes.split(|&x| x == b'\n').enumerate()
+            .filter(|x| !x.1.is_empty());
+        match lines.next() {
+            Some((_, line)) => {
+                check_header(&serde_json::from_slice(line).map_err(|_| {
+                    "not a Foxy IRCd database archive".to_owned()
+                })?)?;
+            },
+            None => return Err("archive is empty".to_owned()),
+        }
+        for (n, line) in lines {
+            let bad = |why: String| format!("line {}: {}", n + 1, why);
+            let line: Value = serde_json::from_slice(line)
+                .map_err(|x| bad(x.to_string()))?;
+            match (line.get("key").and_then(Value::as_str),
+                   line.get("value")) {
+                (Some(key), Some(datum))
+                    => data.push((key.to_owned(), datum.clone())),
+                _ => return Err(bad("not a datum".to_owned())),
+            }
+        }
+    }
+    for (key, _) in data.iter() {
+        validate_key(key).map_err(|x| x.to_string())?;
+    }
+    data.sort_by(|a, b| a.0.cmp(&b.0));
+    if data.windows(2).any(|x| x[0].0 == x[1].0) {
+        return Err("a key appears more than once".to_owned())
+    }
+    Ok(data)
+}
+
+/// A key to snapshot, with its cached datum, if it was cached.
+type Listed = (String, Option<Option<Arc<Value>>>);
+
+/// Lists every key for a snapshot, along with what the cache has for it.
+fn list_for_snapshot(keys: Vec<String>, cache: &Cache) -> Vec<Listed> {
+    merge_with_cache(keys, cache, "").into_iter().map(|key| {
+        let cached = cache.peek(&key).cloned();
+        (key, cached)
+    }).collect()
+}
+
+impl Db {
+    /// Gets every datum, in key order, as they all were at a single moment:
+    /// no insert or transaction is half in the snapshot and half out.
+    ///
+    /// The data are read without holding up anything else. If any of them
+    /// change meanwhile, the snapshot is started over. If that keeps
+    /// happening, the last try makes inserts wait until it's done.
+    pub async fn snapshot(&self)
+                          -> Result<Vec<(String, Arc<Value>)>, DbError> {
+        /// How many times to try without holding the cache lock.
+        const TRIES: usize = 3;
+        for _ in 0 .. TRIES {
+            let read = ReadGuard::new(&self.versions);
+            let keys = self.backend.keys("").await?;
+            let listed = list_for_snapshot(keys, &*self.cache.read().await);
+            let ret = self.load_for_snapshot(listed).await?;
+            if !self.versions.lock().unwrap().any_changed_since(read.start) {
+                return Ok(ret)
+            }
+            debug!("db", "Data changed during a snapshot, starting over");
+        }
+        let cache = self.cache.read().await;
+        let listed = list_for_snapshot(self.backend.keys("").await?, &cache);
+        self.load_for_snapshot(listed).await
+    }
+    /// Gets the data the cache didn't have for a snapshot.
+    async fn load_for_snapshot(&self, listed: Vec<Listed>)
+                               -> Result<Vec<(String, Arc<Value>)>, DbError> {
+        let mut ret = Vec::with_capacity(listed.len());
+        for (key, cached) in listed.into_iter() {
+            let datum = match cached {
+                Some(x) => x,
+                None => self.backend.load(&key, false).await?.map(Arc::new),
+            };
+            if let Some(datum) = datum {
+                ret.push((key, datum));
+            }
+        }
+        Ok(ret)
+    }
+    /// Writes a snapshot of the database to a single file. The file is
+    /// replaced all at once, so a backup that picks it up never sees half
+    /// of it. Returns how many data were written.
+    pub async fn export(&self, path: &Path, format: ArchiveFormat)
+                        -> Result<usize, DbError> {
+        let data = self.snapshot().await?;
+        write_file_atomically(path, &encode_archive(&data, format)[..]).await?;
+        debug!("db", "Exported {} data to {:?}", data.len(), path);
+        Ok(data.len())
+    }
+    /// Compares an archive with the database, and, unless `dry_run` is
+    /// true, makes the database match it. Returns the differences.
+    ///
+    /// Unless it's a dry run, the database must be empty, except that lower-
+    /// priority backing directories may have data; those the archive
+    /// doesn't have are deleted.
+    pub async fn import(&self, path: &Path, dry_run: bool)
+                        -> Result<Vec<Difference>, DbError> {
+        let bytes = fs::read(path).await.map_err(io_err(path))?;
+        let archived = decode_archive(&bytes[..]).map_err(|x| {
+            io_err(path)(std::io::Error::new(ErrorKind::InvalidData, x))
+        })?;
+        if !dry_run {
+            self.check_empty().await?;
+        }
+        let current = self.snapshot().await?;
+        let mut differences = Vec::new();
+        let mut writes = Vec::new();
+        let mut current = current.into_iter().peekable();
+        for (key, datum) in archived.into_iter() {
+            while let Some((old, _)) = current.next_if(|x| x.0 < key) {
+                writes.push((old.clone(), None));
+                differences.push(Difference::Removed(old));
+            }
+            match current.next_if(|x| x.0 == key) {
+                Some((_, old)) if *old == datum => continue,
+                Some(_) => differences.push(Difference::Changed(key.clone())),
+                None => differences.push(Difference::Added(key.clone())),
+            }
+            writes.push((key, Some(datum)));
+        }
+        for (old, _) in current {
+            writes.push((old.clone(), None));
+            differences.push(Difference::Removed(old));
+        }
+        if dry_run { return Ok(differences) }
+        /// How many data to write in each transaction.
+        const BATCH: usize = 256;
+        for writes in writes.chunks(BATCH) {
+            let mut transaction = self.transaction();
+            for (key, datum) in writes.iter() {
+                match datum {
+                    Some(datum) => transaction.insert(key, datum.clone())?,
+                    None => transaction.remove(key)?,
+                }
+            }
+            transaction.commit().await?;
+        }
+        debug!("db", "Imported {} changes from {:?}", differences.len(),
+               path);
+        Ok(differences)
+    }
+    /// Fails unless there's nowhere an import could clobber: the first
+    /// backing directory is empty, or, if there are no directories, the
+    /// backend has no data.
+    async fn check_empty(&self) -> Result<(), DbError> {
+        let not_empty = |path: PathBuf| {
+            DbError::Io(path, Arc::new(std::io::Error::new(
+                ErrorKind::AlreadyExists, "the database isn't empty")))
+        };
+        match self.backend.directories().first() {
+            Some(first) => match fs::read_dir(first).await {
+                Ok(mut entries) => {
+                    let entry = entries.next_entry().await
+                        .map_err(io_err(first))?;
+                    if entry.is_some() {
+                        return Err(not_empty(first.clone()))
+                    }
+                },
+                Err(x) if x.kind() == ErrorKind::NotFound => (),
+                Err(x) => return Err(io_err(first)(x)),
+            },
+            None => if !self.backend.keys("").await?.is_empty() {
+                return Err(not_empty(PathBuf::from(self.describe())))
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test]
+    async fn export_import() {
+        let dir = test_dir("export_import");
+        let local = dir.join("local");
+        let defaults = dir.join("defaults");
+        std::fs::create_dir_all(&defaults).unwrap();
+        std::fs::write(defaults.join("motd.cj"), b"\"default\"").unwrap();
+        std::fs::write(defaults.join("rules.cj"), b"\"be nice\"").unwrap();
+        let db = Db::new(vec![local.clone(), defaults.clone()]);
+        db.insert("motd", json!("Welcome!")).await.unwrap();
+        db.insert("accounts/fox", json!({"nicks": ["fox"]})).await.unwrap();
+        db.insert(&format!("history/{}", "long-".repeat(40)), json!(1)).await
+            .unwrap();
+        db.remove("rules").await.unwrap();
+        for name in ["backup.jsonl", "backup.tar"].iter() {
+            let path = dir.join(name);
+            let format = ArchiveFormat::from_path(&path);
+            assert_eq!(db.export(&path, format).await.unwrap(), 3);
+            let data = decode_archive(&std::fs::read(&path).unwrap())
+                .unwrap();
+            assert_eq!(data[0], ("accounts/fox".to_owned(),
+                                 json!({"nicks": ["fox"]})));
+            assert_eq!(data[2], ("motd".to_owned(), json!("Welcome!")));
+            // restore on top of just the defaults
+            let restored = dir.join(format!("restored-{}", name));
+            let fresh = Db::new(vec![restored.clone(), defaults.clone()]);
+            let mut differences = fresh.import(&path, true).await.unwrap();
+            differences.sort_by_key(|x| x.to_string());
+            assert_eq!(differences.iter().map(|x| x.to_string())
+                       .collect::<Vec<_>>(),
+                       vec!["+ accounts/fox".to_owned(),
+                            format!("+ history/{}", "long-".repeat(40)),
+                            "- rules".to_owned(),
+                            "~ motd".to_owned()]);
+            assert!(!restored.exists());
+            assert_eq!(fresh.import(&path, false).await.unwrap().len(), 4);
+            let fresh = Db::new(vec![restored.clone(), defaults.clone()]);
+            assert_eq!(fresh.snapshot().await.unwrap(),
+                       db.snapshot().await.unwrap());
+            assert!(fresh.import(&path, true).await.unwrap().is_empty());
+            // but not on top of something that's already there
+            assert!(fresh.import(&path, false).await.is_err());
+        }
+        std::fs::write(dir.join("bogus.jsonl"),
+                       b"{\"format\": \"foxy-ircd-db\", \"version\": 99}\n")
+            .unwrap();
+        assert!(db.import(&dir.join("bogus.jsonl"), true).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
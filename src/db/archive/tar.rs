@@ -0,0 +1,168 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Just enough of the POSIX tar format to write a snapshot as a tarball and
+//! read it back: regular files, and pax headers for names too long for a
+//! plain ustar header.
+
+const BLOCK: usize = 512;
+
+/// Appends `number` to `block` in octal, zero-padded to fill `width` bytes
+/// including a terminating NUL.
+fn put_octal(block: &mut [u8], offset: usize, width: usize, number: u64) {
+    let text = format!("{:0width$o}\0", number, width = width - 1);
+    block[offset .. offset + width].copy_from_slice(text.as_bytes());
+}
+
+fn checksum(block: &[u8]) -> u64 {
+    block.iter().enumerate().map(|(n, &b)| {
+        if (148 .. 156).contains(&n) { b' ' as u64 } else { b as u64 }
+    }).sum()
+}
+
+/// Appends one member to an archive.
+fn put_member(out: &mut Vec<u8>, name: &str, typeflag: u8, contents: &[u8]) {
+    let mut header = [0u8; BLOCK];
+    header[.. name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header, 100, 8, 0o644);
+    put_octal(&mut header, 108, 8, 0);
+    put_octal(&mut header, 116, 8, 0);
+    put_octal(&mut header, 124, 12, contents.len() as u64);
+    put_octal(&mut header, 136, 12, 0);
+    header[156] = typeflag;
+    header[257 .. 263].copy_from_slice(b"ustar\0");
+    header[263 .. 265].copy_from_slice(b"00");
+    let sum = checksum(&header);
+    put_octal(&mut header, 148, 7, sum);
+    header[155] = b' ';
+    out.extend_from_slice(&header);
+    out.extend_from_slice(contents);
+    let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+    out.resize(out.len() + padding, 0);
+}
+
+/// Appends a regular file to an archive. Names longer than a ustar header
+/// can hold get a pax header first.
+pub fn put_file(out: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    if name.len() < 100 {
+        put_member(out, name, b'0', contents);
+        return
+    }
+    // A pax record is "LENGTH path=NAME\n", and LENGTH counts itself.
+    let rest = " path=".len() + name.len() + 1;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+    let record = format!("{} path={}\n", length, name);
+    put_member(out, "PaxHeader", b'x', record.as_bytes());
+    // Older tars that don't know pax will see a truncated name.
+    let mut end = 99;
+    while !name.is_char_boundary(end) { end -= 1 }
+    put_member(out, &name[.. end], b'0', contents);
+}
+
+/// Finishes an archive.
+pub fn finish(out: &mut Vec<u8>) {
+    out.resize(out.len() + BLOCK * 2, 0);
+}
+
+/// Returns true if `bytes` looks like it starts with a tar header.
+pub fn is_tar(bytes: &[u8]) -> bool {
+    bytes.len() >= BLOCK && &bytes[257 .. 262] == b"ustar"
+}
+
+fn get_octal(field: &[u8]) -> Result<u64, String> {
+    let text = std::str::from_utf8(field).map_err(|_| "bad number")?;
+    let text = text.trim_matches(|x| x == '\0' || x == ' ');
+    if text.is_empty() { return Ok(0) }
+    u64::from_str_radix(text, 8).map_err(|_| "bad number".to_owned())
+}
+
+fn get_name(field: &[u8]) -> Result<String, String> {
+    let end = field.iter().position(|&x| x == 0).unwrap_or(field.len());
+    String::from_utf8(field[.. end].to_vec())
+        .map_err(|_| "name isn't UTF-8".to_owned())
+}
+
+/// Reads every regular file in an archive, as (name, contents) pairs.
+pub fn files(mut bytes: &[u8]) -> Result<Vec<(String, &[u8])>, String> {
+    let mut ret = Vec::new();
+    let mut long_name = None;
+    loop {
+        if bytes.len() < BLOCK {
+            return Err("archive is truncated".to_owned())
+        }
+        let header = &bytes[.. BLOCK];
+        if header.iter().all(|&x| x == 0) { return Ok(ret) }
+        if get_octal(&header[148 .. 156])? != checksum(header) {
+            return Err("header checksum is wrong".to_owned())
+        }
+        let size = get_octal(&header[124 .. 136])? as usize;
+        let padded = size + (BLOCK - size % BLOCK) % BLOCK;
+        if bytes.len() < BLOCK + padded {
+            return Err("archive is truncated".to_owned())
+        }
+        let contents = &bytes[BLOCK .. BLOCK + size];
+        let mut name = get_name(&header[.. 100])?;
+        let prefix = get_name(&header[345 .. 500])?;
+        if !prefix.is_empty() { name = format!("{}/{}", prefix, name) }
+        match header[156] {
+            b'x' => {
+                let records = std::str::from_utf8(contents)
+                    .map_err(|_| "pax header isn't UTF-8")?;
+                for record in records.lines() {
+                    if let Some(x) = record.split_once(' ')
+                        .and_then(|x| x.1.strip_prefix("path=")) {
+                        long_name = Some(x.to_owned());
+                    }
+                }
+            },
+            b'0' | 0 => {
+                ret.push((long_name.take().unwrap_or(name), contents));
+            },
+            // Directories and such don't matter to us.
+            _ => { long_name = None },
+        }
+        bytes = &bytes[BLOCK + padded ..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn round_trip() {
+        let long = format!("data/{}.cj", "a/".repeat(100));
+        let mut out = Vec::new();
+        put_file(&mut out, "short.json", b"{}");
+        put_file(&mut out, &long, &[b'x'; 1000]);
+        put_file(&mut out, "empty", b"");
+        finish(&mut out);
+        assert_eq!(out.len() % BLOCK, 0);
+        assert!(is_tar(&out));
+        let files = files(&out).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0], ("short.json".to_owned(), &b"{}"[..]));
+        assert_eq!(files[1].0, long);
+        assert_eq!(files[1].1.len(), 1000);
+        assert_eq!(files[2], ("empty".to_owned(), &b""[..]));
+        out[BLOCK * 2 + 3] ^= 1; // somewhere in the second header
+        assert!(super::files(&out).is_err());
+        assert!(super::files(&out[.. BLOCK * 3]).is_err());
+    }
+}
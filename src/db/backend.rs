@@ -0,0 +1,233 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Where the data actually live. `Db` takes care of caching and
+//! transactions; a `Backend` only has to load and store.
+
+use super::*;
+use std::pin::Pin;
+
+mod dir;
+pub use dir::JsonDir;
+pub(super) use dir::write_file_atomically;
+mod memory;
+pub use memory::Memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::Sqlite;
+
+/// A future that can be sent between threads, in a box.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output=T> + Send + 'a>>;
+
+/// A place to keep data.
+pub trait Backend: Send + Sync {
+    /// Describes where the data are kept, for messages.
+    fn describe(&self) -> String;
+    /// Loads a datum. Returns `Ok(None)` if there isn't one. If there is one,
+    /// but it's damaged, a `strict` load fails, and a non-strict one does the
+    /// best it can and warns about it.
+    fn load<'a>(&'a self, key: &'a str, strict: bool)
+                -> BoxFuture<'a, Result<Option<Value>, DbError>>;
+    /// Stores some data, deleting those that are `None`. If there's more than
+    /// one, then even if we crash, they will all be stored or none of them
+    /// will be.
+    ///
+    /// The `Db` calls this with its cache locked, so the backend must start
+    /// the stores (or at least fix their order) before returning. The
+    /// returned future completes when they are done.
+    fn store(&self, writes: Vec<(String, Option<Arc<Value>>)>)
+             -> BoxFuture<'static, Result<(), DbError>>;
+    /// Lists, in order, the key of every datum whose key starts with
+    /// `prefix`. (`""` lists them all.)
+    fn keys<'a>(&'a self, prefix: &'a str)
+                -> BoxFuture<'a, Result<Vec<String>, DbError>>;
+    /// Returns true if a store to the given key has started but not yet
+    /// finished.
+    fn is_storing(&self, _key: &str) -> bool { false }
+    /// Returns the directories that data are kept in as files, if any, in
+    /// descending order of priority. Used by `Db::watch`.
+    fn directories(&self) -> &[PathBuf] { &[] }
+    /// Looks for damage. By default, tries to load every datum.
+    fn check(&self) -> BoxFuture<'_, Result<Vec<Problem>, DbError>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+            for key in self.keys("").await?.into_iter() {
+                if let Err(DbError::InvalidKey(_, why)) = validate_key(&key) {
+                    problems.push(Problem::BadKey(key, why.to_owned()));
+                }
+                else if let Err(x) = self.load(&key, true).await {
+                    problems.push(Problem::Unparseable(key, x.to_string()));
+                }
+            }
+            Ok(problems)
+        })
+    }
+}
+
+/// Something wrong with the database, found by `Db::check`.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Problem {
+    /// The datum here (a key, or a file) can't be read, for the given
+    /// reason.
+    Unparseable(String, String),
+    /// This file will never be read, because the second one, in a directory
+    /// of higher priority (or a whiteout in the same one), hides it.
+    Shadowed(PathBuf, PathBuf),
+    /// This key, or file, doesn't follow the key grammar, for the given
+    /// reason.
+    BadKey(String, String),
+}
+
+impl Display for Problem {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Problem::Unparseable(at, why)
+                => write!(fmt, "{}: unreadable: {}", at, why),
+            Problem::Shadowed(path, by)
+                => write!(fmt, "{}: shadowed by {}", path.display(),
+                          by.display()),
+            Problem::BadKey(at, why)
+                => write!(fmt, "{}: bad key: {}", at, why),
+        }
+    }
+}
+
+/// Opens a backend from a specification of the form `KIND:WHERE`. `KIND` is
+/// one of:
+///
+/// - `dir`: JSON files in the directory `WHERE`
+/// - `sqlite`: the SQLite database file `WHERE`
+/// - `memory`: nowhere at all (`WHERE` is ignored)
+pub fn open_backend(spec: &str) -> Result<Box<dyn Backend>, String> {
+    let mut split = spec.splitn(2, ':');
+    let kind = split.next().unwrap_or("");
+    let place = split.next();
+    match (kind, place) {
+        ("dir", Some(x)) if !x.is_empty()
+            => Ok(Box::new(JsonDir::new(vec![PathBuf::from(x)]))),
+        #[cfg(feature = "sqlite")]
+        ("sqlite", Some(x)) if !x.is_empty()
+            => Sqlite::open(Path::new(x)).map(|x| Box::new(x) as Box<_>)
+            .map_err(|x| x.to_string()),
+        ("memory", _) => Ok(Box::new(Memory::new())),
+        _ => Err(format!("Invalid database specification: {}", spec)),
+    }
+}
+
+impl Db {
+    /// Looks for damage in the backend: data that can't be read, keys that
+    /// break the key grammar, and files that are hidden by others.
+    pub async fn check(&self) -> Result<Vec<Problem>, DbError> {
+        self.backend.check().await
+    }
+    /// Copies every datum into another backend, e.g. to move from one kind
+    /// of storage to another. Data written by older versions are upgraded
+    /// on the way (see `Schema`). Returns how many data were copied.
+    pub async fn copy_to(&self, to: &dyn Backend) -> Result<usize, DbError> {
+        /// How many data to store at once.
+        const BATCH: usize = 256;
+        let keys = self.backend.keys("").await?;
+        let mut count = 0;
+        for keys in keys.chunks(BATCH) {
+            let mut batch = Vec::with_capacity(keys.len());
+            for key in keys.iter() {
+                if let Some(datum) = self.get(key).await? {
+                    batch.push((key.clone(),
+                                Some(self.upgraded_copy(key, datum))));
+                }
+            }
+            count += batch.len();
+            to.store(batch).await?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test]
+    async fn migration() {
+        let dir = test_dir("migration");
+        let from = Db::new(vec![dir.join("from")]);
+        for n in 0 .. 300 {
+            from.insert(&format!("history/{}", n), json!(n)).await.unwrap();
+        }
+        from.insert("motd", json!("Welcome!")).await.unwrap();
+        let mut specs = vec![format!("dir:{}", dir.join("to").display()),
+                             "memory:".to_owned()];
+        if cfg!(feature = "sqlite") {
+            specs.push(format!("sqlite:{}", dir.join("to.db").display()));
+        }
+        for spec in specs.iter() {
+            let to = open_backend(spec).unwrap();
+            assert_eq!(from.copy_to(&*to).await.unwrap(), 301);
+            let keys = to.keys("").await.unwrap();
+            assert_eq!(keys.len(), 301);
+            assert_eq!(keys.last().map(String::as_str), Some("motd"));
+            assert_eq!(to.keys("history/1").await.unwrap().len(), 111);
+            let to = Db::with_backend(to);
+            assert_eq!(to.get("history/299").await.unwrap().as_deref(),
+                       Some(&json!(299)));
+            assert_eq!(to.get("motd").await.unwrap().as_deref(),
+                       Some(&json!("Welcome!")));
+        }
+        assert!(open_backend("nonsense").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn check() {
+        let dir = test_dir("check");
+        let local = dir.join("local");
+        let defaults = dir.join("defaults");
+        std::fs::create_dir_all(local.join("Accounts")).unwrap();
+        std::fs::create_dir_all(defaults.join("accounts")).unwrap();
+        std::fs::write(local.join("motd.cj"), b"\"hi\"").unwrap();
+        std::fs::write(local.join("rules.wh"), b"").unwrap();
+        std::fs::write(local.join("broken.cj"), b"{oops").unwrap();
+        std::fs::write(local.join("README"), b"").unwrap();
+        std::fs::write(local.join("motd.cj~"), b"\"h").unwrap();
+        std::fs::write(local.join("Accounts/fox.cj"), b"1").unwrap();
+        std::fs::write(defaults.join("motd.cj"), b"\"default\"").unwrap();
+        std::fs::write(defaults.join("rules.cj"), b"\"be nice\"").unwrap();
+        std::fs::write(defaults.join("accounts/fox.cj"), b"1").unwrap();
+        let db = Db::new(vec![local.clone(), defaults.clone()]);
+        let problems = db.check().await.unwrap();
+        let at = |path: &Path| path.display().to_string();
+        assert_eq!(problems.len(), 5);
+        assert_eq!(problems[0], Problem::BadKey(at(&local.join("Accounts")),
+                                                "invalid character"
+                                                .to_owned()));
+        assert_eq!(problems[1], Problem::BadKey(at(&local.join("README")),
+                                                "not a .cj or .wh file"
+                                                .to_owned()));
+        assert!(matches!(&problems[2], Problem::Unparseable(x, _)
+                         if x == &at(&local.join("broken.cj"))));
+        assert_eq!(problems[3], Problem::Shadowed(defaults.join("motd.cj"),
+                                                  local.join("motd.cj")));
+        assert_eq!(problems[4], Problem::Shadowed(defaults.join("rules.cj"),
+                                                  local.join("rules.wh")));
+        let db = Db::with_backend(Box::new(Memory::new()));
+        db.insert("motd", json!("hi")).await.unwrap();
+        assert!(db.check().await.unwrap().is_empty());
+        assert!(open_backend("dir:").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
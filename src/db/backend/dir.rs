@@ -0,0 +1,479 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Data kept as JSON files in layered directories. Each datum is a file
+//! whose path is its key (see `key_to_path`). If there's more than one
+//! directory, they are in descending order of priority: a datum comes from
+//! the first directory that has it, and is only ever written to the first
+//! one.
+//!
+//! Deleting a datum from the first directory can't delete it from the
+//! others, so instead we put a whiteout (see `key_to_whiteout_path`) in the
+//! first directory. A whiteout hides the key in every directory after the one
+//! it's in.
+
+use super::*;
+use std::collections::HashSet;
+
+mod journal;
+use journal::*;
+
+/// A write to the backing store that hasn't happened yet, along with everyone
+/// waiting to hear how it went.
+struct PendingWrite {
+    /// `None` means to delete it.
+    datum: Option<Arc<Value>>,
+    waiters: Vec<oneshot::Sender<Result<(), DbError>>>,
+    /// Journals of the transactions this write is (or supersedes) part of.
+    journals: Vec<Arc<Journal>>,
+}
+
+/// Writes waiting for their path's writer task to get to them. A path is
+/// present here if, and only if, a writer task is running for it. The lock is
+/// never held across an `await`.
+type PendingWrites = Arc<Mutex<HashMap<String, Option<PendingWrite>>>>;
+
+pub struct JsonDir {
+    backing_paths: Vec<PathBuf>,
+    writes: PendingWrites,
+    /// Number to give the next transaction journal.
+    next_journal: AtomicU64,
+}
+
+impl JsonDir {
+    /// Keeps data in the given directories, highest priority first. Any
+    /// transactions that were interrupted before they finished reaching the
+    /// first directory are finished now.
+    ///
+    /// With no directories at all, every store succeeds without doing
+    /// anything.
+    pub fn new(backing_paths: Vec<PathBuf>) -> JsonDir {
+        let next_journal = backing_paths.first()
+            .map(|x| replay_journals(x, backing_paths.len() > 1))
+            .unwrap_or(0);
+        JsonDir {
+            next_journal: AtomicU64::new(next_journal),
+            backing_paths,
+            writes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    /// Does the work of `load`. If `strict` is false, a file that exists but
+    /// can't be read or parsed is skipped, and lower-priority directories are
+    /// tried.
+    async fn load_from_fs(&self, path: &str, strict: bool)
+                          -> Result<Option<Value>, DbError> {
+        for (n, back) in self.backing_paths.iter().enumerate() {
+            if n > 0 {
+                let whiteout = key_to_whiteout_path(
+                    &self.backing_paths[n-1], path);
+                if fs::metadata(&whiteout).await.is_ok() {
+                    debug!("db", "{:?} deleted by {:?}", path, whiteout);
+                    return Ok(None)
+                }
+            }
+            let load_path = key_to_path(back, path);
+            let file = File::open(&load_path).await;
+            let error = match file {
+                Ok(mut f) => {
+                    let mut buf = Vec::new();
+                    match f.read_to_end(&mut buf).await {
+                        Ok(_) => (),
+                        Err(x) => {
+                            warn!("db", "Attempting to read {:?}: {}",
+                                  load_path, x);
+                            if strict {
+                                return Err(DbError::Io(load_path,
+                                                       Arc::new(x)))
+                            }
+                            continue
+                        },
+                    }
+                    match serde_json::from_slice(&buf[..]) {
+                        Ok(x) => {
+                            debug!("db", "{:?} satisfied by {:?}",
+                                   path, load_path);
+                            return Ok(Some(x))
+                        },
+                        Err(x) => {
+                            warn!("db", "Attempting to parse {:?}: {}",
+                                  load_path, x);
+                            std::io::Error::new(ErrorKind::InvalidData, x)
+                        },
+                    }
+                },
+                Err(x) if x.kind() == ErrorKind::NotFound => {
+                    // Routine. Continue.
+                    continue
+                },
+                Err(x) => {
+                    warn!("db", "Attempting to open {:?}: {}",
+                          load_path, x);
+                    x
+                },
+            };
+            if strict { return Err(DbError::Io(load_path, Arc::new(error))) }
+        }
+        debug!("db", "{:?} not satisfied", path);
+        Ok(None)
+    }
+    /// Queue a write of `datum` (or a deletion, if `None`) to `path`,
+    /// starting a writer task for it if there isn't one already. If it's part
+    /// of a transaction, `journal` is that transaction's journal. Returns a
+    /// future that completes when the write is done.
+    fn queue_write(&self, base: &Path, path: &str, datum: Option<Arc<Value>>,
+                   journal: Option<Arc<Journal>>)
+                   -> impl Future<Output=Result<(), DbError>> {
+        let (send, recv) = oneshot::channel();
+        let spawn_writer = {
+            let mut writes = self.writes.lock().unwrap();
+            // A path that's already present has a writer task running.
+            // Whatever it writes next will be this datum, or a later one.
+            let (pending, spawn_writer) = match writes.entry(path.to_owned()) {
+                Entry::Occupied(ent) => (ent.into_mut(), false),
+                Entry::Vacant(ent) => (ent.insert(None), true),
+            };
+            let pending = pending.get_or_insert_with(|| PendingWrite {
+                datum: datum.clone(), waiters: Vec::new(),
+                journals: Vec::new(),
+            });
+            pending.datum = datum;
+            pending.waiters.push(send);
+            pending.journals.extend(journal);
+            spawn_writer
+        };
+        let target = key_to_path(base, path);
+        if spawn_writer {
+            tokio::spawn(run_writer(self.writes.clone(), path.to_owned(),
+                                    target.clone(),
+                                    key_to_whiteout_path(base, path),
+                                    self.backing_paths.len() > 1));
+        }
+        async move {
+            recv.await.unwrap_or(Err(DbError::Abandoned(target)))
+        }
+    }
+}
+
+impl Backend for JsonDir {
+    fn describe(&self) -> String {
+        let paths: Vec<_> = self.backing_paths.iter()
+            .map(|x| format!("{:?}", x)).collect();
+        format!("JSON files in {}", paths.join(", "))
+    }
+    fn load<'a>(&'a self, key: &'a str, strict: bool)
+                -> BoxFuture<'a, Result<Option<Value>, DbError>> {
+        Box::pin(self.load_from_fs(key, strict))
+    }
+    fn store(&self, writes: Vec<(String, Option<Arc<Value>>)>)
+             -> BoxFuture<'static, Result<(), DbError>> {
+        let base = match self.backing_paths.first() {
+            Some(x) => x,
+            None => return Box::pin(async { Ok(()) }),
+        };
+        let (journal, written) = if writes.len() > 1 {
+            let number = self.next_journal.fetch_add(1, Ordering::SeqCst);
+            let (journal, written) = Journal::start(base, number, &writes);
+            (Some(journal), Some(written))
+        } else { (None, None) };
+        // They won't start until the journal is written.
+        let writes: Vec<_> = writes.into_iter().map(|(path, datum)| {
+            self.queue_write(base, &path, datum, journal.clone())
+        }).collect();
+        Box::pin(async move {
+            let mut result = match (journal.as_ref(), written) {
+                (Some(journal), Some(written)) => written.await
+                    .unwrap_or_else(|_| Err(DbError::Abandoned(
+                        journal.path().to_owned()))),
+                _ => Ok(()),
+            };
+            for write in writes.into_iter() {
+                let write = write.await;
+                if result.is_ok() { result = write }
+            }
+            // Don't claim to be done while the journal is still around to be
+            // replayed.
+            if let Some(journal) = journal.as_ref() {
+                let _ = journal.wait_for(JournalState::Done).await;
+            }
+            result
+        })
+    }
+    fn keys<'a>(&'a self, prefix: &'a str)
+                -> BoxFuture<'a, Result<Vec<String>, DbError>> {
+        Box::pin(async move {
+            // Only the directory the prefix points into needs to be walked.
+            let start = match prefix.rfind('/') {
+                Some(x) => {
+                    validate_key(&prefix[..x])?;
+                    Some(&prefix[..x])
+                },
+                None => None,
+            };
+            // Every key we've found a datum or a whiteout for. A key whose
+            // first appearance is a whiteout is deleted.
+            let mut seen = HashSet::new();
+            let mut keys = Vec::new();
+            for base in self.backing_paths.iter() {
+                let mut found = Vec::new();
+                let mut dirs = vec![match start {
+                    Some(start) => start.split('/')
+                        .fold(base.clone(), |a, b| a.join(b)),
+                    None => base.clone(),
+                }];
+                while let Some(dir) = dirs.pop() {
+                    let mut entries = match fs::read_dir(&dir).await {
+                        Ok(x) => x,
+                        Err(x) if x.kind() == ErrorKind::NotFound => continue,
+                        Err(x) => return Err(io_err(&dir)(x)),
+                    };
+                    while let Some(entry) = entries.next_entry().await
+                        .map_err(io_err(&dir))? {
+                        let path = entry.path();
+                        if entry.file_type().await.map_err(io_err(&path))?
+                            .is_dir() {
+                            dirs.push(path);
+                        }
+                        else if let Some(x) = path_to_entry(base, &path) {
+                            if x.0.starts_with(prefix) { found.push(x) }
+                        }
+                    }
+                }
+                // Data beat whiteouts in the same directory.
+                found.sort_by_key(|x| x.1);
+                for (key, whiteout) in found.into_iter() {
+                    if seen.insert(key.clone()) && !whiteout {
+                        keys.push(key);
+                    }
+                }
+            }
+            keys.sort();
+            Ok(keys)
+        })
+    }
+    fn is_storing(&self, key: &str) -> bool {
+        self.writes.lock().unwrap().contains_key(key)
+    }
+    fn directories(&self) -> &[PathBuf] { &self.backing_paths }
+    fn check(&self) -> BoxFuture<'_, Result<Vec<Problem>, DbError>> {
+        Box::pin(async move {
+            let mut problems = Vec::new();
+            // Where the first datum or whiteout for each key was found.
+            let mut seen: HashMap<String, PathBuf> = HashMap::new();
+            for base in self.backing_paths.iter() {
+                let mut found = Vec::new();
+                check_dir(base, base, &mut found, &mut problems).await?;
+                // Data beat whiteouts in the same directory.
+                found.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+                for (key, whiteout, path) in found.into_iter() {
+                    if let Some(by) = seen.get(&key) {
+                        problems.push(Problem::Shadowed(path, by.clone()));
+                        continue
+                    }
+                    if !whiteout {
+                        let result = fs::read(&path).await
+                            .map_err(|x| x.to_string())
+                            .and_then(|x| {
+                                serde_json::from_slice::<Value>(&x[..])
+                                    .map_err(|x| x.to_string())
+                            });
+                        if let Err(why) = result {
+                            problems.push(Problem::Unparseable(
+                                path.display().to_string(), why));
+                        }
+                    }
+                    seen.insert(key, path);
+                }
+            }
+            Ok(problems)
+        })
+    }
+}
+
+/// Does the walking for `JsonDir::check`. Adds the key, whether it's a
+/// whiteout, and the path of every datum or whiteout in `dir` to `found`,
+/// and everything whose name isn't a valid key to `problems`.
+fn check_dir<'a>(base: &'a Path, dir: &'a Path,
+                 found: &'a mut Vec<(String, bool, PathBuf)>,
+                 problems: &'a mut Vec<Problem>)
+                 -> BoxFuture<'a, Result<(), DbError>> {
+    Box::pin(async move {
+        let mut entries = match fs::read_dir(dir).await {
+            Ok(x) => x,
+            Err(x) if x.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(x) => return Err(io_err(dir)(x)),
+        };
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next_entry().await
+            .map_err(io_err(dir))? {
+            let is_dir = entry.file_type().await.map_err(io_err(dir))?
+                .is_dir();
+            paths.push((entry.path(), is_dir));
+        }
+        paths.sort();
+        for (path, is_dir) in paths.into_iter() {
+            let mut bad = |why: &str| {
+                problems.push(Problem::BadKey(path.display().to_string(),
+                                              why.to_owned()));
+            };
+            let rest = match path.strip_prefix(base).ok()
+                .and_then(|x| x.to_str()) {
+                Some(x) => x.replace(std::path::MAIN_SEPARATOR, "/"),
+                None => { bad("name isn't UTF-8"); continue },
+            };
+            // Writes in progress, or left behind by a crash, are harmless.
+            if rest.ends_with('~') || (dir == base
+                                       && rest.starts_with(JOURNAL_PREFIX)) {
+                continue
+            }
+            let (key, whiteout) = if is_dir { (&rest[..], false) }
+            else if let Some(x) = rest.strip_suffix(EXTENSION) { (x, false) }
+            else if let Some(x) = rest.strip_suffix(WHITEOUT_EXTENSION) {
+                (x, true)
+            }
+            else { bad("not a .cj or .wh file"); continue };
+            if let Err(DbError::InvalidKey(_, why)) = validate_key(key) {
+                bad(why);
+                continue
+            }
+            if is_dir {
+                check_dir(base, &path, found, problems).await?;
+            }
+            else {
+                found.push((key.to_owned(), whiteout, path.clone()));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Write out pending data for a path until there is none left. Updates that
+/// arrive while a write is in progress are coalesced: only the latest one
+/// gets written next.
+async fn run_writer(writes: PendingWrites, path: String, target: PathBuf,
+                    whiteout: PathBuf, need_whiteouts: bool) {
+    loop {
+        let pending = {
+            let mut writes = writes.lock().unwrap();
+            match writes.get_mut(&path).and_then(Option::take) {
+                Some(x) => x,
+                None => {
+                    writes.remove(&path);
+                    return
+                },
+            }
+        };
+        // If a transaction's journal couldn't be written, writing its data
+        // would leave the transaction half done after a crash, so fail them
+        // instead.
+        let mut journaled = Ok(());
+        for journal in pending.journals.iter() {
+            if let Err(x) = journal.wait_for(JournalState::Ready).await {
+                journaled = Err(x);
+            }
+        }
+        let result = match (journaled, pending.datum.as_ref()) {
+            (Err(x), _) => Err(x),
+            (Ok(()), Some(datum)) => {
+                write_datum(&target, &whiteout, datum).await
+            },
+            (Ok(()), None) => {
+                delete_datum(&target, &whiteout, need_whiteouts).await
+            },
+        };
+        if let Err(x) = result.as_ref() {
+            warn!("db", "Attempting to write {}", x);
+        }
+        else {
+            debug!("db", "{:?} written", path);
+        }
+        for waiter in pending.waiters.into_iter() {
+            let _ = waiter.send(result.clone());
+        }
+        // Nothing newer may reach the disk until every transaction this write
+        // was part of is completely written; otherwise, replaying one of them
+        // after a crash could undo the newer write.
+        for journal in pending.journals.iter() {
+            journal.finish_one(result.is_ok()).await;
+        }
+        for journal in pending.journals.iter() {
+            let _ = journal.wait_for(JournalState::Done).await;
+        }
+    }
+}
+
+/// Write a datum, replacing the whiteout for it if there is one. (The
+/// datum wins if we crash before the whiteout is removed.)
+async fn write_datum(target: &Path, whiteout: &Path, datum: &Value)
+                     -> Result<(), DbError> {
+    write_atomically(target, datum).await?;
+    remove_durably(whiteout).await
+}
+
+/// Delete a datum, putting a whiteout in its place if there are directories
+/// below this one for it to hide in.
+async fn delete_datum(target: &Path, whiteout: &Path, need_whiteout: bool)
+                      -> Result<(), DbError> {
+    if need_whiteout {
+        write_file_atomically(whiteout, b"").await?;
+    }
+    remove_durably(target).await
+}
+
+/// Write a datum to a file such that, even if we crash in the middle, the
+/// file will either have the old contents or the new ones.
+async fn write_atomically(target: &Path, datum: &Value)
+                          -> Result<(), DbError> {
+    let mut buf = serde_json::to_vec_pretty(datum)
+        .expect("serializing a Value can't fail");
+    buf.push(b'\n');
+    write_file_atomically(target, &buf[..]).await
+}
+
+/// Write a file such that, even if we crash in the middle, it will either
+/// have the old contents or the new ones. Writes to a temporary file next to
+/// the target (whose name ends in `~`), flushes it to disk, renames it over
+/// the target, and then flushes the directory.
+pub(in crate::db) async fn write_file_atomically(target: &Path, buf: &[u8])
+                                                 -> Result<(), DbError> {
+    let mut temp = target.as_os_str().to_owned();
+    temp.push("~");
+    let temp = PathBuf::from(temp);
+    let dir = target.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir).await.map_err(io_err(dir))?;
+    let mut file = File::create(&temp).await.map_err(io_err(&temp))?;
+    file.write_all(buf).await.map_err(io_err(&temp))?;
+    file.sync_all().await.map_err(io_err(&temp))?;
+    drop(file);
+    fs::rename(&temp, target).await.map_err(io_err(target))?;
+    // Make the rename itself durable.
+    File::open(dir).await.map_err(io_err(dir))?
+        .sync_all().await.map_err(io_err(dir))?;
+    Ok(())
+}
+
+/// Remove a file, if it's there, and make sure the removal has reached the
+/// disk.
+async fn remove_durably(path: &Path) -> Result<(), DbError> {
+    match fs::remove_file(path).await {
+        Ok(()) => (),
+        Err(x) if x.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(x) => return Err(io_err(path)(x)),
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    File::open(dir).await.map_err(io_err(dir))?
+        .sync_all().await.map_err(io_err(dir))
+}
@@ -0,0 +1,302 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Journals, which let several files be written all or nothing.
+//!
+//! Before a store of more than one datum starts, a journal (a file named
+//! `~journal.N` in the first backing directory) listing all of them is
+//! written. The journal is removed once every datum has reached the disk. If
+//! we crash before then, the next `JsonDir::new` finishes the job.
+//!
+//! A journal is a JSON object. Its `"store"` member is an object mapping keys
+//! to the data to store, and its `"delete"` member is an array of keys to
+//! delete.
+
+use super::*;
+use std::io::Write;
+use tokio::sync::watch;
+
+/// Journal file names start with this, and end with the journal's number.
+pub(super) const JOURNAL_PREFIX: &str = "~journal.";
+
+/// How far along a journal is.
+#[derive(Clone,Copy,Debug,PartialEq,PartialOrd)]
+pub(super) enum JournalState {
+    /// The journal itself is being written. None of its keys may be written
+    /// yet.
+    Writing,
+    /// The journal is on the disk, and its keys are being written.
+    Ready,
+    /// Every key has been written, and the journal is gone.
+    Done,
+    /// The journal couldn't be written, so none of its keys may be.
+    Failed,
+}
+
+/// The journal of a transaction whose writes haven't all reached the disk.
+pub(super) struct Journal {
+    path: PathBuf,
+    /// How many keys haven't been written yet, and whether any write failed.
+    remaining: Mutex<(usize, bool)>,
+    /// Why the journal couldn't be written, if it couldn't.
+    error: Mutex<Option<DbError>>,
+    state: watch::Receiver<JournalState>,
+    set_state: watch::Sender<JournalState>,
+}
+
+impl Journal {
+    /// Start writing the journal for a transaction, in the background.
+    /// Returns the journal, and a receiver that will learn whether the
+    /// journal itself was written successfully.
+    pub(super) fn start(base: &Path, number: u64,
+                        writes: &[(String, Option<Arc<Value>>)])
+                        -> (Arc<Journal>,
+                            oneshot::Receiver<Result<(), DbError>>) {
+        let (set_state, state) = watch::channel(JournalState::Writing);
+        let journal = Arc::new(Journal {
+            path: base.join(format!("{}{}", JOURNAL_PREFIX, number)),
+            remaining: Mutex::new((writes.len(), false)),
+            error: Mutex::new(None),
+            state, set_state,
+        });
+        let store: serde_json::Map<String, Value> = writes.iter()
+            .filter_map(|(path, datum)| {
+                datum.as_ref().map(|x| (path.clone(), (**x).clone()))
+            }).collect();
+        let delete: Vec<Value> = writes.iter()
+            .filter(|x| x.1.is_none())
+            .map(|x| Value::String(x.0.clone())).collect();
+        let mut contents = serde_json::Map::new();
+        contents.insert("store".to_owned(), Value::Object(store));
+        contents.insert("delete".to_owned(), Value::Array(delete));
+        let contents = Value::Object(contents);
+        let (send, recv) = oneshot::channel();
+        let ret = journal.clone();
+        tokio::spawn(async move {
+            let result = write_atomically(&journal.path, &contents).await;
+            let state = match result.as_ref() {
+                Ok(()) => JournalState::Ready,
+                Err(x) => {
+                    warn!("db", "Attempting to write journal {}", x);
+                    *journal.error.lock().unwrap() = Some(x.clone());
+                    JournalState::Failed
+                },
+            };
+            let _ = journal.set_state.broadcast(state);
+            let _ = send.send(result);
+        });
+        (ret, recv)
+    }
+    pub(super) fn path(&self) -> &Path { &self.path }
+    /// Wait until the journal has reached at least the given state. Returns
+    /// why the journal couldn't be written, if it couldn't, in which case
+    /// its keys must not be written either.
+    pub(super) async fn wait_for(&self, want: JournalState)
+                                 -> Result<(), DbError> {
+        let mut state = self.state.clone();
+        while let Some(x) = state.recv().await {
+            if x == JournalState::Failed {
+                return Err(self.error.lock().unwrap().clone()
+                           .unwrap_or_else(|| {
+                               DbError::Abandoned(self.path.clone())
+                           }))
+            }
+            if x >= want { return Ok(()) }
+        }
+        Ok(())
+    }
+    /// Note that one of the journal's keys has been written, or failed to
+    /// be. When they all have, and they all succeeded, the journal is
+    /// removed. If any failed, it's left for the next startup to replay.
+    /// A journal that couldn't be written stays `Failed`.
+    pub(super) async fn finish_one(&self, success: bool) {
+        if *self.state.borrow() == JournalState::Failed { return }
+        let (finished, failed) = {
+            let mut remaining = self.remaining.lock().unwrap();
+            remaining.0 -= 1;
+            remaining.1 |= !success;
+            (remaining.0 == 0, remaining.1)
+        };
+        if !finished { return }
+        if !failed {
+            match remove_durably(&self.path).await {
+                Ok(()) => debug!("db", "{:?} complete", self.path),
+                Err(x) => warn!("db", "Attempting to remove {}", x),
+            }
+        }
+        let _ = self.set_state.broadcast(JournalState::Done);
+    }
+}
+
+/// Finish any transactions whose journals are in `base`. Returns the number
+/// the next journal should have. If `whiteouts` is true, there are
+/// directories below `base`, and deletions must leave whiteouts.
+///
+/// Blocks. Only called when a `Db` is being made.
+pub(super) fn replay_journals(base: &Path, whiteouts: bool) -> u64 {
+    let entries = match std::fs::read_dir(base) {
+        Ok(x) => x,
+        Err(x) if x.kind() == ErrorKind::NotFound => return 0,
+        Err(x) => {
+            warn!("db", "Attempting to read {:?}: {}", base, x);
+            return 0
+        },
+    };
+    let mut journals = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let number = match name.to_str()
+            .and_then(|x| x.strip_prefix(JOURNAL_PREFIX)) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Ok(number) = number.parse::<u64>() {
+            journals.push((number, entry.path()));
+        }
+        else if number.ends_with('~') {
+            // This journal never finished being written, so none of its
+            // transaction's writes can have started.
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    journals.sort();
+    for (_, path) in journals.iter() {
+        match replay_journal(base, path, whiteouts) {
+            Ok(()) => debug!("db", "Replayed {:?}", path),
+            Err(x) => warn!("db", "Attempting to replay {:?}: {}",
+                            path, x),
+        }
+    }
+    journals.last().map(|x| x.0 + 1).unwrap_or(0)
+}
+
+/// Write everything in a journal, and then remove it. If this fails, the
+/// journal is left alone, to try again next time.
+fn replay_journal(base: &Path, path: &Path, whiteouts: bool)
+                  -> std::io::Result<()> {
+    let invalid = |x: &str| Err(std::io::Error::new(ErrorKind::InvalidData,
+                                                    x.to_owned()));
+    let contents = std::fs::read(path)?;
+    let contents: Value = match serde_json::from_slice(&contents[..]) {
+        Ok(x) => x,
+        Err(x) => return Err(std::io::Error::new(ErrorKind::InvalidData, x)),
+    };
+    let (store, delete) = match (contents.get("store"),
+                                 contents.get("delete")) {
+        (Some(Value::Object(store)), Some(Value::Array(delete)))
+            => (store, delete),
+        _ => return invalid("not a journal"),
+    };
+    // (A non-string in `delete` is treated as an invalid key.)
+    let deletes: Vec<&str> = delete.iter()
+        .map(|x| x.as_str().unwrap_or("")).collect();
+    for key in store.keys().map(String::as_str)
+        .chain(deletes.iter().copied()) {
+        if let Err(x) = validate_key(key) {
+            return invalid(&x.to_string())
+        }
+    }
+    for (key, datum) in store.iter() {
+        let mut buf = serde_json::to_vec_pretty(datum)
+            .expect("serializing a Value can't fail");
+        buf.push(b'\n');
+        write_blocking(&key_to_path(base, key), &buf[..])?;
+        remove_blocking(&key_to_whiteout_path(base, key))?;
+    }
+    for key in deletes.into_iter() {
+        if whiteouts {
+            write_blocking(&key_to_whiteout_path(base, key), b"")?;
+        }
+        remove_blocking(&key_to_path(base, key))?;
+    }
+    std::fs::remove_file(path)?;
+    std::fs::File::open(base)?.sync_all()
+}
+
+/// `write_file_atomically`, for when there's no runtime to be had.
+fn write_blocking(target: &Path, buf: &[u8]) -> std::io::Result<()> {
+    let mut temp = target.as_os_str().to_owned();
+    temp.push("~");
+    let dir = target.parent().unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, target)?;
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// `remove_durably`, for when there's no runtime to be had.
+fn remove_blocking(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => (),
+        Err(x) if x.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(x) => return Err(x),
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test]
+    async fn replay() {
+        let dir = test_dir("replay");
+        let defaults = dir.join("defaults");
+        let dir = dir.join("local");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&defaults).unwrap();
+        std::fs::write(dir.join("motd.cj"), b"\"old\"").unwrap();
+        std::fs::write(defaults.join("rules.cj"), b"\"old\"").unwrap();
+        std::fs::write(dir.join("~journal.3"),
+                       b"{\"store\": {\"motd\": \"new\", \"accounts/fox\": 1},\
+                          \"delete\": [\"rules\"]}").unwrap();
+        std::fs::write(dir.join("~journal.4~"), b"{\"store\": {\"mo").unwrap();
+        let db = Db::new(vec![dir.clone(), defaults.clone()]);
+        assert_eq!(db.get("motd").await.unwrap().as_deref(),
+                   Some(&json!("new")));
+        assert_eq!(db.get("accounts/fox").await.unwrap().as_deref(),
+                   Some(&json!(1)));
+        assert_eq!(db.get("rules").await.unwrap(), None);
+        assert!(!dir.join("~journal.3").exists());
+        assert!(!dir.join("~journal.4~").exists());
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+    #[tokio::test]
+    async fn unwritable_journal() {
+        let dir = test_dir("unwritable_journal");
+        // the journal's temporary file can't be made where a directory is
+        std::fs::create_dir_all(dir.join("~journal.0~")).unwrap();
+        let db = Db::new(vec![dir.clone()]);
+        let mut transaction = db.transaction();
+        transaction.insert("nicks/fox", json!("fox")).unwrap();
+        transaction.insert("accounts/fox", json!({"nicks": ["fox"]}))
+            .unwrap();
+        assert!(transaction.commit().await.is_err());
+        // neither half of the transaction reached the disk
+        assert!(!dir.join("nicks/fox.cj").exists());
+        assert!(!dir.join("accounts/fox.cj").exists());
+        // later writes to the same keys aren't held up
+        db.insert("nicks/fox", json!("vixen")).await.unwrap();
+        assert!(dir.join("nicks/fox.cj").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Data kept nowhere but memory, and lost when the server stops. Useful for
+//! tests, and for servers that don't need to remember anything.
+
+use super::*;
+
+#[derive(Default)]
+pub struct Memory {
+    data: Mutex<HashMap<String, Arc<Value>>>,
+}
+
+impl Memory {
+    pub fn new() -> Memory { Memory::default() }
+}
+
+impl Backend for Memory {
+    fn describe(&self) -> String { "memory".to_owned() }
+    fn load<'a>(&'a self, key: &'a str, _strict: bool)
+                -> BoxFuture<'a, Result<Option<Value>, DbError>> {
+        let datum = self.data.lock().unwrap().get(key)
+            .map(|x| Value::clone(x));
+        Box::pin(async move { Ok(datum) })
+    }
+    fn store(&self, writes: Vec<(String, Option<Arc<Value>>)>)
+             -> BoxFuture<'static, Result<(), DbError>> {
+        let mut data = self.data.lock().unwrap();
+        for (key, datum) in writes.into_iter() {
+            match datum {
+                Some(datum) => { data.insert(key, datum); },
+                None => { data.remove(&key); },
+            }
+        }
+        Box::pin(async { Ok(()) })
+    }
+    fn keys<'a>(&'a self, prefix: &'a str)
+                -> BoxFuture<'a, Result<Vec<String>, DbError>> {
+        let mut keys: Vec<String> = self.data.lock().unwrap().keys()
+            .filter(|x| x.starts_with(prefix)).cloned().collect();
+        keys.sort();
+        Box::pin(async move { Ok(keys) })
+    }
+}
@@ -0,0 +1,239 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Data kept in a single SQLite database file, one row per datum. Much
+//! kinder to the filesystem than `JsonDir` when there are hundreds of
+//! thousands of small data.
+//!
+//! SQLite blocks, so the connection lives on a thread of its own, and
+//! requests are sent to it. Stores that pile up while it's busy are done in a
+//! single SQLite transaction.
+
+use super::*;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::sync::mpsc;
+
+/// Something for the SQLite thread to do.
+enum Request {
+    Load(String, oneshot::Sender<Result<Option<String>, DbError>>),
+    Store(Vec<(String, Option<Arc<Value>>)>,
+          oneshot::Sender<Result<(), DbError>>),
+    Keys(String, oneshot::Sender<Result<Vec<String>, DbError>>),
+}
+
+pub struct Sqlite {
+    path: PathBuf,
+    requests: Mutex<mpsc::Sender<Request>>,
+}
+
+/// Turns an SQLite error into a `DbError` about the given file.
+fn sql_err(path: &Path) -> impl Fn(rusqlite::Error) -> DbError {
+    let path = path.to_owned();
+    move |x| DbError::Io(path.clone(),
+                         Arc::new(std::io::Error::other(x)))
+}
+
+impl Sqlite {
+    /// Opens (creating if needed) the given database file.
+    pub fn open(path: &Path) -> Result<Sqlite, DbError> {
+        let connection = Connection::open(path).map_err(sql_err(path))?;
+        connection.execute_batch("\
+            PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS data (
+                key TEXT PRIMARY KEY NOT NULL,
+                value TEXT NOT NULL
+            );").map_err(sql_err(path))?;
+        let (send, recv) = mpsc::channel();
+        let thread_path = path.to_owned();
+        std::thread::Builder::new()
+            .name("db sqlite".to_owned())
+            .spawn(move || run_sqlite(connection, thread_path, recv))
+            .map_err(|x| DbError::Io(path.to_owned(), Arc::new(x)))?;
+        Ok(Sqlite {
+            path: path.to_owned(),
+            requests: Mutex::new(send),
+        })
+    }
+    /// Sends a request to the SQLite thread, and waits for the answer.
+    fn request<T: Send + 'static>(&self,
+                                  make: impl FnOnce(oneshot::Sender<T>)
+                                  -> Request)
+                                  -> BoxFuture<'static, Result<T, DbError>> {
+        let (send, recv) = oneshot::channel();
+        // If the thread is gone, `send` is dropped and `recv` will fail.
+        let _ = self.requests.lock().unwrap().send(make(send));
+        let path = self.path.clone();
+        Box::pin(async move {
+            recv.await.map_err(|_| DbError::Abandoned(path))
+        })
+    }
+}
+
+impl Backend for Sqlite {
+    fn describe(&self) -> String {
+        format!("SQLite database {:?}", self.path)
+    }
+    fn load<'a>(&'a self, key: &'a str, strict: bool)
+                -> BoxFuture<'a, Result<Option<Value>, DbError>> {
+        let text = self.request(|x| Request::Load(key.to_owned(), x));
+        Box::pin(async move {
+            let text = match text.await?? {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            match serde_json::from_str(&text) {
+                Ok(x) => Ok(Some(x)),
+                Err(x) => {
+                    warn!("db", "Attempting to parse {:?} in {:?}: {}",
+                          key, self.path, x);
+                    if strict {
+                        Err(DbError::Io(self.path.clone(), Arc::new(
+                            std::io::Error::new(ErrorKind::InvalidData, x))))
+                    }
+                    else { Ok(None) }
+                },
+            }
+        })
+    }
+    fn store(&self, writes: Vec<(String, Option<Arc<Value>>)>)
+             -> BoxFuture<'static, Result<(), DbError>> {
+        let done = self.request(|x| Request::Store(writes, x));
+        Box::pin(async move { done.await? })
+    }
+    fn keys<'a>(&'a self, prefix: &'a str)
+                -> BoxFuture<'a, Result<Vec<String>, DbError>> {
+        let keys = self.request(|x| Request::Keys(prefix.to_owned(), x));
+        Box::pin(async move { keys.await? })
+    }
+}
+
+/// Body of the SQLite thread. Returns when the `Sqlite` is dropped.
+fn run_sqlite(mut connection: Connection, path: PathBuf,
+              requests: mpsc::Receiver<Request>) {
+    let sql_err = sql_err(&path);
+    while let Ok(first) = requests.recv() {
+        let mut batch: Vec<Request> = vec![first];
+        batch.extend(requests.try_iter());
+        let mut stores = Vec::new();
+        let mut waiters = Vec::new();
+        let mut batch = batch.into_iter().peekable();
+        while let Some(request) = batch.next() {
+            match request {
+                Request::Store(writes, waiter) => {
+                    stores.extend(writes);
+                    waiters.push(waiter);
+                    // Keep collecting as long as stores keep coming.
+                    if let Some(Request::Store(..)) = batch.peek() {
+                        continue
+                    }
+                    let result = store_all(&mut connection, &stores)
+                        .map_err(&sql_err);
+                    if let Err(x) = result.as_ref() {
+                        warn!("db", "Attempting to write {}", x);
+                    }
+                    for waiter in waiters.drain(..) {
+                        let _ = waiter.send(result.clone());
+                    }
+                    stores.clear();
+                },
+                Request::Load(key, waiter) => {
+                    let _ = waiter.send(connection.query_row(
+                        "SELECT value FROM data WHERE key = ?1",
+                        params![key], |row| row.get(0))
+                                        .optional().map_err(&sql_err));
+                },
+                Request::Keys(prefix, waiter) => {
+                    let _ = waiter.send(keys_with_prefix(&connection, &prefix)
+                                        .map_err(&sql_err));
+                },
+            }
+        }
+    }
+}
+
+/// Stores (or deletes) all of the given data in one transaction.
+fn store_all(connection: &mut Connection,
+             writes: &[(String, Option<Arc<Value>>)])
+             -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare_cached(
+            "INSERT OR REPLACE INTO data (key, value) VALUES (?1, ?2)")?;
+        let mut delete = transaction.prepare_cached(
+            "DELETE FROM data WHERE key = ?1")?;
+        for (key, datum) in writes.iter() {
+            match datum {
+                Some(datum) => insert.execute(params![key,
+                                                      datum.to_string()])?,
+                None => delete.execute(params![key])?,
+            };
+        }
+    }
+    transaction.commit()
+}
+
+fn keys_with_prefix(connection: &Connection, prefix: &str)
+                    -> rusqlite::Result<Vec<String>> {
+    // Keys can contain `%` and `_`, which mean something to LIKE (which also
+    // ignores case), so compare the start of each key directly.
+    let mut statement = connection.prepare_cached(
+        "SELECT key FROM data WHERE substr(key, 1, length(?1)) = ?1 \
+         ORDER BY key")?;
+    let keys = statement.query_map(params![prefix], |row| row.get(0))?;
+    keys.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test(threaded_scheduler)]
+    async fn sqlite() {
+        let dir = test_dir("sqlite");
+        let path = dir.join("foxy.db");
+        let db = Arc::new(Db::with_backend(Box::new(Sqlite::open(&path)
+                                                    .unwrap())));
+        let tasks: Vec<_> = (0 .. 50).map(|n| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.insert(&format!("history/{}", n), json!(n)).await
+            })
+        }).collect();
+        for task in tasks { task.await.unwrap().unwrap() }
+        let mut transaction = db.transaction();
+        transaction.insert("accounts/fox", json!({"nicks": ["fox"]}))
+            .unwrap();
+        transaction.insert("nicks/fox", json!("fox")).unwrap();
+        assert!(transaction.commit().await.unwrap());
+        drop(db);
+        let db = Db::with_backend(Box::new(Sqlite::open(&path).unwrap()));
+        assert_eq!(db.get("history/49").await.unwrap().as_deref(),
+                   Some(&json!(49)));
+        assert_eq!(db.get("nicks/fox").await.unwrap().as_deref(),
+                   Some(&json!("fox")));
+        assert_eq!(db.get("nicks/vixen").await.unwrap(), None);
+        db.remove("nicks/fox").await.unwrap();
+        assert_eq!(db.list("").await.unwrap().len(), 51);
+        drop(db);
+        let db = Db::with_backend(Box::new(Sqlite::open(&path).unwrap()));
+        assert_eq!(db.get("nicks/fox").await.unwrap(), None);
+        assert_eq!(db.list("history/4").await.unwrap().len(), 11);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `Db`'s cache, which has a limited size.
+//!
+//! Entries are evicted in (approximately) least-recently-used order, using
+//! the CLOCK algorithm: each entry has a "referenced" bit that is set when
+//! it's used, and the clock hand sweeps over the entries, clearing set bits
+//! and evicting entries whose bits were already clear. Setting the bit only
+//! needs a read lock.
+//!
+//! Negative entries (remembering that there is *no* datum for a key) are
+//! cheap, but there can be an awful lot of them, e.g. if someone does WHOIS
+//! on random nicks. They are swept separately, and have their own limit.
+
+use super::*;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem::size_of,
+    sync::atomic::AtomicBool,
+};
+
+/// How big the cache may get.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct CacheLimits {
+    /// Approximately how many bytes of memory all of the entries together
+    /// may use.
+    pub max_bytes: usize,
+    /// How many negative entries there may be.
+    pub max_negative: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> CacheLimits {
+        CacheLimits {
+            max_bytes: 64 << 20,
+            max_negative: 1 << 16,
+        }
+    }
+}
+
+/// How the cache is doing.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct CacheStats {
+    /// How many lookups found an entry, positive or negative.
+    pub hits: u64,
+    /// How many lookups had to go to the backend.
+    pub misses: u64,
+    /// How many entries have been evicted to make room.
+    pub evictions: u64,
+    /// How many entries there are, including negative and pinned ones.
+    pub entries: usize,
+    /// How many of the entries are negative.
+    pub negative_entries: usize,
+    /// How many of the entries are pinned.
+    pub pinned_entries: usize,
+    /// Approximately how many bytes the entries are using.
+    pub bytes: usize,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{} entries ({} negative, {} pinned), ~{} bytes; \
+                     {} hits, {} misses, {} evictions",
+               self.entries, self.negative_entries, self.pinned_entries,
+               self.bytes, self.hits, self.misses, self.evictions)
+    }
+}
+
+/// Returns approximately how many bytes a datum occupies in memory.
+pub fn approximate_size(value: &Value) -> usize {
+    size_of::<Value>() + match value {
+        Value::String(x) => x.capacity(),
+        Value::Array(x) => x.iter().map(approximate_size).sum(),
+        Value::Object(x) => x.iter().map(|(key, value)| {
+            // a rough guess at the per-node overhead of the map
+            size_of::<String>() + key.capacity() + approximate_size(value)
+                + 2 * size_of::<usize>()
+        }).sum(),
+        _ => 0,
+    }
+}
+
+struct CacheEntry {
+    value: Option<Arc<Value>>,
+    /// Approximate size of this entry, including the key.
+    size: usize,
+    /// Identifies this entry's place in a clock queue. If the queue has this
+    /// key with a different stamp, that's a stale place, and is skipped.
+    stamp: u64,
+    referenced: AtomicBool,
+    pinned: bool,
+}
+
+pub struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    /// The clock for positive entries, in the order the hand visits them.
+    positive: VecDeque<(String, u64)>,
+    /// The clock for negative entries.
+    negative: VecDeque<(String, u64)>,
+    next_stamp: u64,
+    /// Keys equal to, or starting with, any of these are pinned.
+    pins: Vec<String>,
+    limits: CacheLimits,
+    bytes: usize,
+    negative_count: usize,
+    pinned_count: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: u64,
+}
+
+impl Cache {
+    pub fn new(limits: CacheLimits) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            positive: VecDeque::new(),
+            negative: VecDeque::new(),
+            next_stamp: 0,
+            pins: Vec::new(),
+            limits,
+            bytes: 0,
+            negative_count: 0,
+            pinned_count: 0,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: 0,
+        }
+    }
+    /// Looks up an entry, noting that it was used. Only needs a read lock.
+    pub fn get(&self, key: &str) -> Option<Option<Arc<Value>>> {
+        match self.entries.get(key) {
+            Some(entry) => {
+                entry.referenced.store(true, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+    /// Looks at an entry without counting it as a use.
+    pub fn peek(&self, key: &str) -> Option<&Option<Arc<Value>>> {
+        self.entries.get(key).map(|x| &x.value)
+    }
+    /// Lists every entry whose key starts with `prefix`, and whether it's
+    /// positive, without counting them as uses.
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str)
+                           -> impl Iterator<Item=(&'a str, bool)> + 'a {
+        self.entries.iter().filter(move |x| x.0.starts_with(prefix))
+            .map(|(key, entry)| (key.as_str(), entry.value.is_some()))
+    }
+    fn is_pinned(&self, key: &str) -> bool {
+        self.pins.iter().any(|pin| {
+            key == pin || (pin.ends_with('/') && key.starts_with(&pin[..]))
+        })
+    }
+    /// Adds or replaces an entry, and then evicts entries until we're within
+    /// our limits. Entries for which `busy` returns true are not evicted.
+    pub fn insert(&mut self, key: String, value: Option<Arc<Value>>,
+                  busy: impl Fn(&str) -> bool) {
+        let size = size_of::<CacheEntry>() + size_of::<String>() + key.len()
+            + value.as_deref().map(approximate_size).unwrap_or(0);
+        let pinned = self.is_pinned(&key);
+        let negative = value.is_none();
+        // An entry that stays in the same clock keeps its place.
+        let stamp = match self.remove(&key) {
+            Some(old) if old.value.is_none() == negative && !pinned
+                => old.stamp,
+            _ => {
+                let stamp = self.next_stamp;
+                self.next_stamp += 1;
+                if !pinned {
+                    let clock = if negative { &mut self.negative }
+                                else { &mut self.positive };
+                    clock.push_back((key.clone(), stamp));
+                }
+                stamp
+            },
+        };
+        self.bytes += size;
+        if negative { self.negative_count += 1 }
+        if pinned { self.pinned_count += 1 }
+        self.entries.insert(key, CacheEntry {
+            value, size, stamp, pinned,
+            referenced: AtomicBool::new(true),
+        });
+        self.evict(busy);
+    }
+    /// Removes an entry, keeping the counts straight. Its place in a clock,
+    /// if any, becomes stale.
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.bytes -= entry.size;
+        if entry.value.is_none() { self.negative_count -= 1 }
+        if entry.pinned { self.pinned_count -= 1 }
+        Some(entry)
+    }
+    /// Removes every entry. Pins are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.positive.clear();
+        self.negative.clear();
+        self.bytes = 0;
+        self.negative_count = 0;
+        self.pinned_count = 0;
+    }
+    /// Pins every key equal to `pattern`, or, if `pattern` ends with `/`,
+    /// starting with it. Pinned entries are never evicted.
+    pub fn pin(&mut self, pattern: &str) {
+        self.pins.push(pattern.to_owned());
+        let mut newly_pinned = 0;
+        for (key, entry) in self.entries.iter_mut() {
+            if !entry.pinned && (key == pattern || (pattern.ends_with('/')
+                                 && key.starts_with(pattern))) {
+                // its place in the clock will be skipped
+                entry.pinned = true;
+                newly_pinned += 1;
+            }
+        }
+        self.pinned_count += newly_pinned;
+    }
+    pub fn set_limits(&mut self, limits: CacheLimits,
+                      busy: impl Fn(&str) -> bool) {
+        self.limits = limits;
+        self.evict(busy);
+    }
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions,
+            entries: self.entries.len(),
+            negative_entries: self.negative_count,
+            pinned_entries: self.pinned_count,
+            bytes: self.bytes,
+        }
+    }
+    /// Evicts entries until we're within our limits, or there's nothing left
+    /// that can be evicted.
+    fn evict(&mut self, busy: impl Fn(&str) -> bool) {
+        while self.negative_count > self.limits.max_negative {
+            if !self.sweep(true, &busy) { break }
+        }
+        while self.bytes > self.limits.max_bytes {
+            if !self.sweep(false, &busy) && !self.sweep(true, &busy) { break }
+        }
+        // Don't let stale places pile up forever.
+        if self.positive.len() + self.negative.len()
+            > 2 * self.entries.len() + 64 {
+            let entries = &self.entries;
+            let current = |(key, stamp): &(String, u64)| {
+                entries.get(key).map(|x| x.stamp == *stamp && !x.pinned)
+                    .unwrap_or(false)
+            };
+            self.positive.retain(current);
+            self.negative.retain(current);
+        }
+    }
+    /// Moves the hand of one clock until it evicts something. Returns false
+    /// if it went all the way around twice without finding anything to
+    /// evict.
+    fn sweep(&mut self, negative: bool, busy: &impl Fn(&str) -> bool)
+             -> bool {
+        let mut steps = 2 * if negative { self.negative.len() }
+                            else { self.positive.len() };
+        while steps > 0 {
+            steps -= 1;
+            let clock = if negative { &mut self.negative }
+                        else { &mut self.positive };
+            let (key, stamp) = match clock.pop_front() {
+                Some(x) => x,
+                None => return false,
+            };
+            let entry = match self.entries.get(&key) {
+                Some(x) if x.stamp == stamp && !x.pinned => x,
+                // stale
+                _ => continue,
+            };
+            if entry.referenced.swap(false, Ordering::Relaxed)
+                || busy(&key) {
+                clock.push_back((key, stamp));
+                continue
+            }
+            self.remove(&key);
+            self.evictions += 1;
+            return true
+        }
+        false
+    }
+}
+
+/// When each key last changed, for as long as anyone might care. Lets a read
+/// from the backend, or a transaction, notice that the datum changed while it
+/// was busy, even if the cache has since evicted it.
+///
+/// Every change to the cache's contents bumps the generation. Everything
+/// that reads (see `ReadGuard`) notes the generation it started at, and
+/// changes are remembered until no read that started before them remains.
+#[derive(Default)]
+pub struct Versions {
+    generation: u64,
+    changed: HashMap<String, u64>,
+    /// The generations that reads in progress started at, and how many
+    /// started at each.
+    readers: BTreeMap<u64, usize>,
+}
+
+impl Versions {
+    /// The current generation.
+    pub fn generation(&self) -> u64 { self.generation }
+    /// Notes that a datum changed. Call it under the cache's write lock,
+    /// along with the change.
+    pub fn bump(&mut self, key: &str) {
+        self.generation += 1;
+        if !self.readers.is_empty() {
+            self.changed.insert(key.to_owned(), self.generation);
+        }
+    }
+    /// Has this datum changed since the given generation?
+    pub fn changed_since(&self, key: &str, generation: u64) -> bool {
+        self.changed.get(key).map(|x| *x > generation).unwrap_or(false)
+    }
+    /// Has any datum changed since the given generation?
+    pub fn any_changed_since(&self, generation: u64) -> bool {
+        self.changed.values().any(|x| *x > generation)
+    }
+    fn begin_read(&mut self) -> u64 {
+        *self.readers.entry(self.generation).or_default() += 1;
+        self.generation
+    }
+    fn end_read(&mut self, start: u64) {
+        if let Some(count) = self.readers.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 { self.readers.remove(&start); }
+        }
+        match self.readers.keys().next() {
+            None => self.changed.clear(),
+            Some(&oldest) => self.changed.retain(|_, x| *x > oldest),
+        }
+    }
+}
+
+/// A read in progress, as far as `Versions` is concerned. Dropping it ends
+/// the read.
+pub struct ReadGuard<'a> {
+    versions: &'a Mutex<Versions>,
+    /// The generation the read started at.
+    pub start: u64,
+}
+
+impl ReadGuard<'_> {
+    pub fn new(versions: &Mutex<Versions>) -> ReadGuard<'_> {
+        let start = versions.lock().unwrap().begin_read();
+        ReadGuard { versions, start }
+    }
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.versions.lock().unwrap().end_read(self.start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    fn idle(_: &str) -> bool { false }
+    #[test]
+    fn negative_limit() {
+        let mut cache = Cache::new(CacheLimits {
+            max_bytes: usize::MAX, max_negative: 10,
+        });
+        cache.insert("motd".to_owned(), Some(Arc::new(json!("hi"))), idle);
+        for n in 0 .. 100 {
+            cache.insert(format!("nicks/{}", n), None, idle);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.negative_entries, 10);
+        assert_eq!(stats.entries, 11);
+        assert_eq!(stats.evictions, 90);
+        assert!(cache.get("motd").is_some());
+    }
+    #[test]
+    fn byte_limit() {
+        let big = Arc::new(json!("x".repeat(1000)));
+        let size = size_of::<CacheEntry>() + size_of::<String>() + 4
+            + approximate_size(&big);
+        let mut cache = Cache::new(CacheLimits {
+            max_bytes: size * 4, max_negative: usize::MAX,
+        });
+        cache.pin("conf");
+        cache.insert("conf".to_owned(), Some(big.clone()), idle);
+        for n in 0 .. 4 {
+            cache.insert(format!("h/{}", n), Some(big.clone()), idle);
+        }
+        let stats = cache.stats();
+        assert!(stats.bytes <= size * 4);
+        assert_eq!(stats.evictions, 1);
+        assert!(cache.peek("conf").is_some());
+        assert!(cache.peek("h/0").is_none());
+        // a used entry gets a second chance
+        assert!(cache.get("h/2").is_some());
+        cache.insert("h/4".to_owned(), Some(big.clone()), idle);
+        assert!(cache.peek("h/1").is_none());
+        assert!(cache.peek("h/2").is_some());
+        assert!(cache.peek("h/3").is_some());
+        // pinned entries are never evicted, even when nothing else can be
+        cache.pin("h/");
+        cache.set_limits(CacheLimits { max_bytes: 0, max_negative: 0 },
+                         idle);
+        assert_eq!(cache.stats().entries, 4);
+        assert_eq!(cache.stats().pinned_entries, 4);
+        // busy entries aren't evicted either
+        cache.insert("busy".to_owned(), None, |x| x == "busy");
+        assert!(cache.peek("busy").is_some());
+        assert_eq!(cache.stats().hits, 1);
+    }
+    #[test]
+    fn versions() {
+        let versions = Mutex::new(Versions::default());
+        // nobody is reading, so nothing need be remembered
+        versions.lock().unwrap().bump("motd");
+        assert!(versions.lock().unwrap().changed.is_empty());
+        let first = ReadGuard::new(&versions);
+        versions.lock().unwrap().bump("motd");
+        let second = ReadGuard::new(&versions);
+        versions.lock().unwrap().bump("nicks/fox");
+        {
+            let versions = versions.lock().unwrap();
+            assert!(versions.changed_since("motd", first.start));
+            assert!(!versions.changed_since("motd", second.start));
+            assert!(versions.changed_since("nicks/fox", second.start));
+            assert!(!versions.changed_since("conf", first.start));
+            assert!(versions.any_changed_since(second.start));
+            assert!(!versions.any_changed_since(versions.generation()));
+        }
+        drop(first);
+        assert_eq!(versions.lock().unwrap().changed.len(), 1);
+        drop(second);
+        assert!(versions.lock().unwrap().changed.is_empty());
+    }
+}
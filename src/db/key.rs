@@ -0,0 +1,235 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Database keys, and how they map onto files.
+//!
+//! A key is one or more *segments* separated by `/`, such as
+//! `accounts/fox`. A segment consists of lowercase ASCII letters, digits,
+//! `-`, `_`, `.`, and `%XX` escapes (uppercase hex), and may not begin with
+//! `.`. This keeps keys from escaping the database directory, from colliding
+//! on case-insensitive filesystems, and from looking like our temporary
+//! files. Each key is stored in a file named after its last segment plus
+//! `.cj`.
+//!
+//! Names that come from users, such as nicknames and channel names, can be
+//! turned into segments with `encode_segment`, and back with
+//! `decode_segment`.
+
+use std::path::{Path, PathBuf};
+
+use super::DbError;
+
+/// The longest a single segment may be, in bytes. Leaves room for the file
+/// extension and the temporary file suffix within the usual 255-byte limit.
+pub const MAX_SEGMENT_LEN: usize = 240;
+/// The longest a whole key may be, in bytes.
+pub const MAX_KEY_LEN: usize = 1024;
+/// The most segments a key may have.
+pub const MAX_KEY_DEPTH: usize = 16;
+/// The extension given to the file that holds a key's datum.
+pub const EXTENSION: &str = ".cj";
+/// The extension given to a whiteout, which marks a key as deleted.
+pub const WHITEOUT_EXTENSION: &str = ".wh";
+
+fn is_plain_byte(b: u8) -> bool {
+    b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_'
+}
+
+fn is_upper_hex(b: u8) -> bool {
+    b.is_ascii_digit() || (b'A' ..= b'F').contains(&b)
+}
+
+/// Check one segment of a key.
+fn validate_segment(segment: &[u8]) -> Result<(), &'static str> {
+    if segment.is_empty() { return Err("empty segment") }
+    if segment.len() > MAX_SEGMENT_LEN { return Err("segment too long") }
+    if segment[0] == b'.' { return Err("segment starts with a dot") }
+    let mut n = 0;
+    while n < segment.len() {
+        match segment[n] {
+            b'%' => {
+                if segment.len() < n + 3 || !is_upper_hex(segment[n+1])
+                || !is_upper_hex(segment[n+2]) {
+                    return Err("bad escape")
+                }
+                n += 3;
+            },
+            b'.' => n += 1,
+            x if is_plain_byte(x) => n += 1,
+            _ => return Err("invalid character"),
+        }
+    }
+    Ok(())
+}
+
+/// Check that a key follows the key grammar.
+pub fn validate_key(key: &str) -> Result<(), DbError> {
+    let invalid = |why| Err(DbError::InvalidKey(key.to_owned(), why));
+    if key.len() > MAX_KEY_LEN { return invalid("key too long") }
+    if key.split('/').count() > MAX_KEY_DEPTH {
+        return invalid("too many segments")
+    }
+    for segment in key.split('/') {
+        if let Err(why) = validate_segment(segment.as_bytes()) {
+            return invalid(why)
+        }
+    }
+    Ok(())
+}
+
+/// Returns the file in which a (valid) key is stored, within the given
+/// database directory.
+pub fn key_to_path(base: &Path, key: &str) -> PathBuf {
+    key_to_file(base, key, EXTENSION)
+}
+
+/// Returns the file whose presence, within the given database directory,
+/// means that a (valid) key has been deleted, and any datum for it in a
+/// lower-priority directory is to be ignored.
+pub fn key_to_whiteout_path(base: &Path, key: &str) -> PathBuf {
+    key_to_file(base, key, WHITEOUT_EXTENSION)
+}
+
+fn key_to_file(base: &Path, key: &str, extension: &str) -> PathBuf {
+    debug_assert!(validate_key(key).is_ok());
+    let mut ret = base.to_owned();
+    for segment in key.split('/') {
+        ret.push(segment);
+    }
+    let mut file_name = ret.file_name().unwrap().to_owned();
+    file_name.push(extension);
+    ret.set_file_name(file_name);
+    ret
+}
+
+/// The inverse of `key_to_path`. Returns the key stored in the given file
+/// within the given database directory, or `None` if the file isn't where any
+/// valid key would be stored (e.g. a temporary file, a whiteout, or something
+/// outside `base` altogether).
+pub fn path_to_key(base: &Path, path: &Path) -> Option<String> {
+    match path_to_entry(base, path)? {
+        (key, false) => Some(key),
+        (_, true) => None,
+    }
+}
+
+/// The inverse of both `key_to_path` and `key_to_whiteout_path`. Returns the
+/// key the given file is for, and whether the file is a whiteout.
+pub fn path_to_entry(base: &Path, path: &Path) -> Option<(String, bool)> {
+    let rest = path.strip_prefix(base).ok()?;
+    let mut segments = rest.iter()
+        .map(|x| x.to_str())
+        .collect::<Option<Vec<&str>>>()?;
+    let last = segments.pop()?;
+    let (last, whiteout) = match last.strip_suffix(EXTENSION) {
+        Some(x) => (x, false),
+        None => (last.strip_suffix(WHITEOUT_EXTENSION)?, true),
+    };
+    segments.push(last);
+    let key = segments.join("/");
+    if validate_key(&key).is_ok() { Some((key, whiteout)) } else { None }
+}
+
+/// Turn an arbitrary name into a valid key segment. Reversible with
+/// `decode_segment`. Anything other than lowercase letters, digits, `-` and
+/// `_` is escaped, including uppercase letters, so names that differ only in
+/// case stay distinct even on a case-insensitive filesystem.
+///
+/// Returns `None` if the name is empty, or too long to fit in a segment once
+/// escaped.
+pub fn encode_segment(name: &[u8]) -> Option<String> {
+    if name.is_empty() { return None }
+    let mut ret = String::with_capacity(name.len());
+    for &b in name {
+        if is_plain_byte(b) { ret.push(b as char) }
+        else { ret.push_str(&format!("%{:02X}", b)) }
+    }
+    if ret.len() > MAX_SEGMENT_LEN { None } else { Some(ret) }
+}
+
+/// Reverse `encode_segment`. Returns `None` if `segment` is not something
+/// `encode_segment` could have produced.
+pub fn decode_segment(segment: &str) -> Option<Vec<u8>> {
+    let segment = segment.as_bytes();
+    let mut ret = Vec::with_capacity(segment.len());
+    let mut n = 0;
+    while n < segment.len() {
+        match segment[n] {
+            b'%' if segment.len() >= n + 3 && is_upper_hex(segment[n+1])
+                && is_upper_hex(segment[n+2]) => {
+                let hex = std::str::from_utf8(&segment[n+1..n+3]).ok()?;
+                ret.push(u8::from_str_radix(hex, 16).ok()?);
+                n += 3;
+            },
+            x if is_plain_byte(x) => { ret.push(x); n += 1 },
+            _ => return None,
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn keys() {
+        let good = ["motd", "accounts/fox", "channels/%23foxy-ircd",
+                    "bans/kline.1", "a_b-c/d"];
+        for key in good.iter() {
+            assert!(validate_key(key).is_ok(), "{:?}", key);
+        }
+        let bad = ["", "/etc/passwd", "../../etc/passwd", "a/../b", "a//b",
+                   "a/", ".hidden", "motd~", "Upper", "a/%2", "a/%2f",
+                   "sp ace", "back\\slash"];
+        for key in bad.iter() {
+            assert!(validate_key(key).is_err(), "{:?}", key);
+        }
+        assert!(validate_key(&"a".repeat(MAX_SEGMENT_LEN + 1)).is_err());
+        assert!(validate_key(&["a"; MAX_KEY_DEPTH + 1].join("/")).is_err());
+    }
+    #[test]
+    fn paths() {
+        assert_eq!(key_to_path(Path::new("/db"), "accounts/fox"),
+                   Path::new("/db/accounts/fox.cj"));
+        assert_eq!(path_to_key(Path::new("/db"),
+                               Path::new("/db/accounts/fox.cj")).as_deref(),
+                   Some("accounts/fox"));
+        assert_eq!(path_to_entry(Path::new("/db"),
+                                 &key_to_whiteout_path(Path::new("/db"),
+                                                       "accounts/fox")),
+                   Some(("accounts/fox".to_owned(), true)));
+        for path in ["/db/accounts/fox.cj~", "/db/motd", "/elsewhere/motd.cj",
+                     "/db/accounts/fox.wh",
+                     "/db/accounts/Fox.cj", "/db/.cj"].iter() {
+            assert_eq!(path_to_key(Path::new("/db"), Path::new(path)), None);
+        }
+    }
+    #[test]
+    fn segments() {
+        let names: &[&[u8]] = &[b"fox", b"#Foxy-IRCd", b"../..",
+                                "Füchsin".as_bytes(), b"a/b", b"%41"];
+        for name in names {
+            let segment = encode_segment(name).unwrap();
+            assert!(validate_key(&segment).is_ok(), "{:?}", segment);
+            assert_eq!(decode_segment(&segment).as_deref(), Some(*name));
+        }
+        assert_eq!(encode_segment(b"#Fox").unwrap(), "%23%46ox");
+        assert_eq!(encode_segment(b""), None);
+        assert_eq!(encode_segment(&[0xFF; MAX_SEGMENT_LEN / 3 + 1]), None);
+        assert_eq!(decode_segment("%2f"), None);
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Typed access to data, and upgrading data written by older versions.
+//!
+//! A kind of record (say, every key under `accounts/`) can have a `Schema`,
+//! which is a list of migrations. Migration N turns a record of version N into
+//! one of version N+1, so the current version is the number of migrations.
+//! Records with a schema are JSON objects, and keep their version in their
+//! `SCHEMA_FIELD` member; a record without one is version 0.
+//!
+//! Old records are upgraded (and the upgrade stored) when `get_as` reads
+//! them, or all at once by `upgrade_all`.
+
+use super::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The member of a record that holds its schema version.
+pub const SCHEMA_FIELD: &str = "schema";
+
+/// Turns a record of one version into a record of the next version. Doesn't
+/// need to touch `SCHEMA_FIELD`. Returns why, if it can't.
+pub type Migration = fn(Value) -> Result<Value, String>;
+
+/// The migrations for every key equal to `prefix`, or, if `prefix` ends with
+/// `/`, starting with it.
+#[derive(Clone,Copy)]
+pub struct Schema {
+    pub prefix: &'static str,
+    pub migrations: &'static [Migration],
+}
+
+/// The schemas the server knows about. When a kind of record first changes
+/// shape, give it an entry here.
+pub const SCHEMAS: &[Schema] = &[];
+
+impl Schema {
+    /// The version that records are upgraded to.
+    pub fn version(&self) -> u64 { self.migrations.len() as u64 }
+    fn applies_to(&self, key: &str) -> bool {
+        key == self.prefix
+            || (self.prefix.ends_with('/') && key.starts_with(self.prefix))
+    }
+}
+
+/// Returns the schema version of a record: its `SCHEMA_FIELD`, or 0 if it
+/// doesn't have one.
+pub fn schema_version(datum: &Value) -> Result<u64, &'static str> {
+    match datum.get(SCHEMA_FIELD) {
+        None => Ok(0),
+        Some(x) => x.as_u64().ok_or("schema version isn't a whole number"),
+    }
+}
+
+impl Db {
+    /// Adds a schema, on top of those in `SCHEMAS`. If more than one schema
+    /// applies to a key, the one with the longest prefix wins.
+    pub fn register_schema(&mut self, schema: Schema) {
+        self.schemas.push(schema);
+    }
+    fn schema_for(&self, key: &str) -> Option<&Schema> {
+        self.schemas.iter().filter(|x| x.applies_to(key))
+            .max_by_key(|x| x.prefix.len())
+    }
+    /// Runs whatever migrations a record needs. Returns `None` if it's
+    /// already current.
+    fn upgrade(&self, path: &str, datum: &Value)
+               -> Result<Option<Value>, DbError> {
+        let invalid = |why: String| DbError::Invalid(path.to_owned(), why);
+        let schema = match self.schema_for(path) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let version = schema_version(datum)
+            .map_err(|x| invalid(x.to_owned()))?;
+        if version == schema.version() { return Ok(None) }
+        else if version > schema.version() {
+            return Err(invalid(format!("schema version {} is newer than \
+                                        this server understands ({})",
+                                       version, schema.version())))
+        }
+        let mut datum = datum.clone();
+        for (n, migration) in schema.migrations.iter().enumerate()
+            .skip(version as usize) {
+            datum = migration(datum).map_err(|x| {
+                invalid(format!("upgrading to schema version {}: {}",
+                                n + 1, x))
+            })?;
+        }
+        match datum.as_object_mut() {
+            Some(x) => {
+                x.insert(SCHEMA_FIELD.to_owned(), schema.version().into());
+            },
+            None => return Err(invalid("upgraded record isn't an object"
+                                       .to_owned())),
+        }
+        debug!("db", "{:?} upgraded from schema version {} to {}",
+               path, version, schema.version());
+        Ok(Some(datum))
+    }
+    /// Upgrades a record, if it needs it, and stores the upgrade. Returns the
+    /// current record (`None` if there isn't one), and whether it upgraded
+    /// it.
+    async fn get_upgraded(&self, path: &str)
+                          -> Result<(Option<Arc<Value>>, bool), DbError> {
+        loop {
+            let old = match self.get(path).await? {
+                Some(x) => x,
+                None => return Ok((None, false)),
+            };
+            let new = match self.upgrade(path, &old)? {
+                Some(x) => x,
+                None => return Ok((Some(old), false)),
+            };
+            match self.compare_and_swap(path, Some(&old), new.clone()).await {
+                Ok(false) => continue, // someone else changed it; try again
+                Ok(true) => (),
+                // The cache has the upgrade, even if the backend doesn't.
+                Err(x) => warn!("db", "Storing upgraded {:?}: {}",
+                                path, x),
+            }
+            return Ok((Some(Arc::new(new)), true))
+        }
+    }
+    /// Gets a record, as `get`, and deserializes it. A record written by an
+    /// older version is upgraded first. A record that can't be upgraded or
+    /// deserialized is `DbError::Invalid`.
+    pub async fn get_as<T: DeserializeOwned>(&self, path: &str)
+                                             -> Result<Option<T>, DbError> {
+        let datum = match self.get_upgraded(path).await?.0 {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut datum = Value::clone(&datum);
+        if self.schema_for(path).is_some() {
+            if let Some(x) = datum.as_object_mut() {
+                x.remove(SCHEMA_FIELD);
+            }
+        }
+        serde_json::from_value(datum).map(Some)
+            .map_err(|x| DbError::Invalid(path.to_owned(), x.to_string()))
+    }
+    /// Serializes a record and inserts it, as `insert`. If the key has a
+    /// schema, the record is marked with the current version, and must
+    /// serialize to an object.
+    pub async fn insert_as<T: Serialize>(&self, path: &str, datum: &T)
+                                         -> Result<(), DbError> {
+        let invalid = |why: String| DbError::Invalid(path.to_owned(), why);
+        let mut datum = serde_json::to_value(datum)
+            .map_err(|x| invalid(x.to_string()))?;
+        if let Some(schema) = self.schema_for(path) {
+            match datum.as_object_mut() {
+                Some(x) => {
+                    x.insert(SCHEMA_FIELD.to_owned(), schema.version().into());
+                },
+                None => return Err(invalid("record with a schema isn't an \
+                                            object".to_owned())),
+            }
+        }
+        self.insert(path, datum).await
+    }
+    /// Upgrades every record that needs it. Records that can't be upgraded
+    /// are left alone, with a warning. Returns how many were upgraded.
+    pub async fn upgrade_all(&self) -> Result<usize, DbError> {
+        let mut count = 0;
+        for schema in self.schemas.iter() {
+            if schema.migrations.is_empty() { continue }
+            let keys = if schema.prefix.ends_with('/') {
+                self.list(schema.prefix).await?
+            }
+            else { vec![schema.prefix.to_owned()] };
+            for key in keys.iter() {
+                // A longer prefix may belong to another schema; it gets its
+                // turn.
+                if self.schema_for(key).map(|x| x.prefix)
+                    != Some(schema.prefix) {
+                    continue
+                }
+                match self.get_upgraded(key).await {
+                    Ok((_, true)) => count += 1,
+                    Ok((_, false)) => (),
+                    Err(x @ DbError::Invalid(..))
+                        => warn!("db", "Not upgrading {}", x),
+                    Err(x) => return Err(x),
+                }
+            }
+        }
+        Ok(count)
+    }
+    /// Upgrades a record on its way somewhere else, e.g. during `copy_to`.
+    /// Records that can't be upgraded are passed along as they are, with a
+    /// warning.
+    pub(super) fn upgraded_copy(&self, path: &str, datum: Arc<Value>)
+                                -> Arc<Value> {
+        match self.upgrade(path, &datum) {
+            Ok(Some(x)) => Arc::new(x),
+            Ok(None) => datum,
+            Err(x) => {
+                warn!("db", "Not upgrading {}", x);
+                datum
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    type Account = BTreeMap<String, Vec<String>>;
+    fn nick_to_nicks(mut datum: Value) -> Result<Value, String> {
+        let nick = datum.as_object_mut().and_then(|x| x.remove("nick"))
+            .ok_or("no nick")?;
+        datum["nicks"] = json!([nick]);
+        Ok(datum)
+    }
+    fn add_flags(mut datum: Value) -> Result<Value, String> {
+        datum["flags"] = json!([]);
+        Ok(datum)
+    }
+    const ACCOUNTS: Schema = Schema {
+        prefix: "accounts/",
+        migrations: &[nick_to_nicks, add_flags],
+    };
+    fn test_db() -> Db {
+        let mut db = Db::with_backend(Box::new(Memory::new()));
+        db.register_schema(ACCOUNTS);
+        db
+    }
+    #[tokio::test]
+    async fn upgrade_on_read() {
+        let db = test_db();
+        db.insert("accounts/fox", json!({"nick": "fox"})).await.unwrap();
+        let account: Account = db.get_as("accounts/fox").await.unwrap()
+            .unwrap();
+        assert_eq!(account["nicks"], vec!["fox"]);
+        assert!(account["flags"].is_empty());
+        // the upgrade was stored
+        assert_eq!(db.get("accounts/fox").await.unwrap().as_deref(),
+                   Some(&json!({"nicks": ["fox"], "flags": [],
+                                "schema": 2})));
+        // writing stamps the current version
+        db.insert_as("accounts/vixen", &account).await.unwrap();
+        assert_eq!(db.get("accounts/vixen").await.unwrap().unwrap()["schema"],
+                   json!(2));
+        // keys without a schema are left as they are
+        db.insert_as("motd", &"Welcome!").await.unwrap();
+        assert_eq!(db.get_as::<String>("motd").await.unwrap().unwrap(),
+                   "Welcome!");
+        assert_eq!(db.get_as::<String>("nothing").await.unwrap(), None);
+    }
+    #[tokio::test]
+    async fn invalid_records() {
+        let db = test_db();
+        db.insert("accounts/broken", json!({"nicks": "fox"})).await.unwrap();
+        db.insert("accounts/future", json!({"schema": 3})).await.unwrap();
+        db.insert("motd", json!(42)).await.unwrap();
+        for key in ["accounts/broken", "accounts/future"].iter() {
+            assert!(matches!(db.get_as::<Account>(key).await,
+                             Err(DbError::Invalid(..))));
+        }
+        assert!(matches!(db.get_as::<String>("motd").await,
+                         Err(DbError::Invalid(..))));
+        assert!(matches!(db.insert_as("accounts/fox", &"fox").await,
+                         Err(DbError::Invalid(..))));
+        assert_eq!(db.get("accounts/fox").await.unwrap(), None);
+    }
+    #[tokio::test]
+    async fn batch_upgrade() {
+        let db = test_db();
+        for n in 0 .. 10 {
+            db.insert(&format!("accounts/user{}", n),
+                      json!({"nick": format!("user{}", n)})).await.unwrap();
+        }
+        db.insert("accounts/current",
+                  json!({"nicks": [], "flags": [], "schema": 2})).await
+            .unwrap();
+        db.insert("accounts/half", json!({"nicks": [], "schema": 1})).await
+            .unwrap();
+        db.insert("accounts/broken", json!({"oops": 1})).await.unwrap();
+        db.insert("accounts/future", json!({"schema": 3})).await.unwrap();
+        assert_eq!(db.upgrade_all().await.unwrap(), 11);
+        assert_eq!(db.upgrade_all().await.unwrap(), 0);
+        assert_eq!(db.get("accounts/user3").await.unwrap().as_deref(),
+                   Some(&json!({"nicks": ["user3"], "flags": [],
+                                "schema": 2})));
+        assert_eq!(db.get("accounts/future").await.unwrap().as_deref(),
+                   Some(&json!({"schema": 3})));
+        assert_eq!(db.get("accounts/broken").await.unwrap().as_deref(),
+                   Some(&json!({"oops": 1})));
+    }
+}
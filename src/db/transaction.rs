@@ -0,0 +1,232 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reading and writing several keys at once, all or nothing.
+//!
+//! Transactions are optimistic. Everything a transaction reads is remembered,
+//! and it only commits if none of it has changed in the meantime. If it has,
+//! nothing is written, and the caller should start over. Making the writes
+//! all-or-nothing on the disk is up to the backend.
+
+use super::*;
+use std::collections::BTreeMap;
+
+/// A set of reads and writes that will happen all together, or not at all.
+/// Made by `Db::transaction`. Nothing is written until `commit`.
+pub struct Transaction<'a> {
+    db: &'a Db,
+    /// Keeps `Db::versions` remembering changes made since we started.
+    _read: ReadGuard<'a>,
+    /// Everything we've read, as it was when we read it, and the generation
+    /// it was current at.
+    reads: HashMap<String, (Option<Arc<Value>>, u64)>,
+    /// Everything we're going to write, or delete if `None`.
+    writes: BTreeMap<String, Option<Arc<Value>>>,
+}
+
+impl Db {
+    /// Starts a new transaction.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            db: self,
+            _read: ReadGuard::new(&self.versions),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+    /// Put a datum into the database, but only if the current datum is
+    /// `expected` (`None` meaning there isn't one). Returns whether it was
+    /// put. Once it returns `true`, the new datum has reached the disk.
+    pub async fn compare_and_swap(&self, path: &str, expected: Option<&Value>,
+                                  datum: Value) -> Result<bool, DbError> {
+        let mut transaction = self.transaction();
+        if transaction.get(path).await?.as_deref() != expected {
+            return Ok(false)
+        }
+        transaction.insert(path, datum)?;
+        transaction.commit().await
+    }
+}
+
+impl Transaction<'_> {
+    /// Get a datum, as `Db::get`. Sees any datum this transaction has
+    /// already `insert`ed or `remove`d. The transaction won't commit if the
+    /// datum changes before then.
+    pub async fn get(&mut self, path: &str)
+                     -> Result<Option<Arc<Value>>, DbError> {
+        if let Some(datum) = self.writes.get(path) {
+            return Ok(datum.clone())
+        }
+        if let Some((datum, _)) = self.reads.get(path) {
+            return Ok(datum.clone())
+        }
+        validate_key(path)?;
+        let (datum, generation) = self.db.get_observed(path).await;
+        self.reads.insert(path.to_owned(), (datum.clone(), generation));
+        Ok(datum)
+    }
+    /// Arrange to put a datum into the database when the transaction
+    /// commits. Fails only if `path` isn't a valid key.
+    pub fn insert(&mut self, path: &str, datum: Value) -> Result<(), DbError> {
+        validate_key(path)?;
+        self.writes.insert(path.to_owned(), Some(Arc::new(datum)));
+        Ok(())
+    }
+    /// Arrange to delete a datum when the transaction commits. Fails only if
+    /// `path` isn't a valid key.
+    pub fn remove(&mut self, path: &str) -> Result<(), DbError> {
+        validate_key(path)?;
+        self.writes.insert(path.to_owned(), None);
+        Ok(())
+    }
+    /// Try to commit the transaction. Returns `false`, having changed
+    /// nothing, if anything this transaction read has changed since, whether
+    /// or not it's still cached. Returns `true` once everything it wrote is
+    /// in the cache and on the disk.
+    ///
+    /// As with `Db::insert`, the cache is updated even if the disk can't be
+    /// written to; the error says what went wrong.
+    pub async fn commit(self) -> Result<bool, DbError> {
+        let db = self.db;
+        let mut cache = db.cache.write().await;
+        let written = {
+            let mut versions = db.versions.lock().unwrap();
+            for (path, (_, seen)) in self.reads.iter() {
+                if versions.changed_since(path, *seen) {
+                    debug!("db", "Transaction lost a race on {:?}", path);
+                    return Ok(false)
+                }
+            }
+            if self.writes.is_empty() { return Ok(true) }
+            // As in `insert`, start storing while holding the cache lock, and
+            // before updating the cache.
+            let written = db.backend.store(self.writes.iter()
+                                           .map(|(x, y)| (x.clone(),
+                                                          y.clone()))
+                                           .collect());
+            for (path, datum) in self.writes.into_iter() {
+                versions.bump(&path);
+                cache.insert(path, datum, |x| db.backend.is_storing(x));
+            }
+            written
+        };
+        drop(cache);
+        written.await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test]
+    async fn compare_and_swap() {
+        let db = Db::new(vec![]);
+        assert!(db.compare_and_swap("nicks/fox", None, json!("fox")).await
+                .unwrap());
+        assert!(!db.compare_and_swap("nicks/fox", None, json!("vixen")).await
+                .unwrap());
+        assert!(db.compare_and_swap("nicks/fox", Some(&json!("fox")),
+                                    json!("vixen")).await.unwrap());
+        assert_eq!(db.get("nicks/fox").await.unwrap().as_deref(),
+                   Some(&json!("vixen")));
+    }
+    #[tokio::test]
+    async fn uncached_reads() {
+        // nothing stays cached, so every read goes to the backend
+        let db = Db::with_backend(Box::new(Memory::new()));
+        db.set_cache_limits(CacheLimits { max_bytes: 0,
+                                          max_negative: 0 }).await;
+        assert!(db.compare_and_swap("nicks/fox", None, json!("fox")).await
+                .unwrap());
+        assert!(db.compare_and_swap("nicks/fox", Some(&json!("fox")),
+                                    json!("vixen")).await.unwrap());
+        assert_eq!(db.get("nicks/fox").await.unwrap().as_deref(),
+                   Some(&json!("vixen")));
+        assert_eq!(db.cache_stats().await.entries, 0);
+        // a change to something read is still noticed once it's evicted
+        let mut first = db.transaction();
+        assert_eq!(first.get("nicks/kit").await.unwrap(), None);
+        first.insert("nicks/kit", json!("first")).unwrap();
+        db.insert("nicks/kit", json!("second")).await.unwrap();
+        db.get("nicks/wolf").await.unwrap();
+        assert!(!first.commit().await.unwrap());
+        assert_eq!(db.get("nicks/kit").await.unwrap().as_deref(),
+                   Some(&json!("second")));
+    }
+    #[tokio::test]
+    async fn conflicts() {
+        let db = Db::new(vec![]);
+        let mut first = db.transaction();
+        assert_eq!(first.get("nicks/fox").await.unwrap(), None);
+        first.insert("nicks/fox", json!("first")).unwrap();
+        first.insert("accounts/first", json!({"nicks": ["fox"]})).unwrap();
+        // sees its own writes
+        assert_eq!(first.get("nicks/fox").await.unwrap().as_deref(),
+                   Some(&json!("first")));
+        let mut second = db.transaction();
+        assert_eq!(second.get("nicks/fox").await.unwrap(), None);
+        second.insert("nicks/fox", json!("second")).unwrap();
+        second.insert("accounts/second", json!({"nicks": ["fox"]})).unwrap();
+        assert!(first.commit().await.unwrap());
+        assert!(!second.commit().await.unwrap());
+        assert_eq!(db.get("nicks/fox").await.unwrap().as_deref(),
+                   Some(&json!("first")));
+        assert_eq!(db.get("accounts/second").await.unwrap(), None);
+        // dropping a nick
+        let mut third = db.transaction();
+        third.remove("nicks/fox").unwrap();
+        third.insert("accounts/first", json!({"nicks": []})).unwrap();
+        assert_eq!(third.get("nicks/fox").await.unwrap(), None);
+        assert!(third.commit().await.unwrap());
+        assert_eq!(db.get("nicks/fox").await.unwrap(), None);
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn racing_registrations() {
+        let dir = test_dir("racing_registrations");
+        let db = Arc::new(Db::new(vec![dir.clone()]));
+        let tasks: Vec<_> = (0 .. 20).map(|n| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let account = format!("accounts/user{}", n);
+                loop {
+                    let mut transaction = db.transaction();
+                    let count = transaction.get("count").await.unwrap()
+                        .and_then(|x| x.as_u64()).unwrap_or(0);
+                    transaction.insert("count", json!(count + 1)).unwrap();
+                    transaction.insert(&account, json!(n)).unwrap();
+                    if transaction.commit().await.unwrap() { break }
+                }
+            })
+        }).collect();
+        for task in tasks { task.await.unwrap() }
+        let db = Db::new(vec![dir.clone()]);
+        assert_eq!(db.get("count").await.unwrap().as_deref(),
+                   Some(&json!(20)));
+        for n in 0 .. 20 {
+            assert_eq!(db.get(&format!("accounts/user{}", n)).await.unwrap()
+                       .as_deref(), Some(&json!(n)));
+        }
+        // all the journals are gone
+        assert!(std::fs::read_dir(&dir).unwrap().flatten().all(|x| {
+            !x.file_name().to_str().unwrap().starts_with("~journal.")
+        }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
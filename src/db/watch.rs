@@ -0,0 +1,242 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Notices when something other than us changes a file in the backing
+//! directories, and reloads the affected datum, so that an operator can edit
+//! the database by hand without having to rehash.
+//!
+//! inotify has no async interface we can use, so a dedicated thread blocks on
+//! it and hands changed paths to a task. Only Linux is supported; elsewhere,
+//! `Db::watch` just says so.
+
+use super::*;
+
+/// Something the watcher thread has noticed.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum Change {
+    /// The file at this path was written, created, moved, or deleted.
+    File(PathBuf),
+    /// The kernel dropped some events. We don't know what changed.
+    Overflow,
+}
+
+impl Db {
+    /// Starts watching every backing directory for changes made by other
+    /// programs. Changes to data that are cached are reloaded, as with
+    /// `reload`. Watching stops once the `Db` is dropped and another change
+    /// comes along. Does nothing if the backend doesn't keep data in
+    /// directories.
+    #[cfg(target_os = "linux")]
+    pub fn watch(db: &Arc<Db>) -> Result<(), DbError> {
+        use inotify::Inotify;
+        use tokio::sync::mpsc;
+        if db.backend.directories().is_empty() { return Ok(()) }
+        let first = db.backend.directories().first().map(PathBuf::as_path)
+            .unwrap_or_else(|| Path::new("."));
+        let mut inotify = Inotify::init().map_err(io_err(first))?;
+        let mut dirs = HashMap::new();
+        for back in db.backend.directories().iter() {
+            let mut ignored = Vec::new();
+            linux::watch_tree(&mut inotify, &mut dirs, back, &mut ignored)
+                .map_err(io_err(back))?;
+        }
+        let (send, mut recv) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("db watcher".to_owned())
+            .spawn(move || linux::run_watcher(inotify, dirs, send))
+            .map_err(io_err(first))?;
+        let db = Arc::downgrade(db);
+        tokio::spawn(async move {
+            while let Some(change) = recv.recv().await {
+                let db = match db.upgrade() {
+                    Some(x) => x,
+                    None => return,
+                };
+                match change {
+                    Change::Overflow => {
+                        warn!("db", "Lost track of changes to the \
+                               database, rehashing");
+                        db.rehash().await;
+                    },
+                    Change::File(path) => {
+                        // A whiteout changing is as good as its datum
+                        // changing.
+                        let key = db.backend.directories().iter()
+                            .find_map(|x| path_to_entry(x, &path))
+                            .map(|x| x.0);
+                        if let Some(key) = key {
+                            let _ = db.reload(&key).await;
+                        }
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+    /// Starts watching every backing directory for changes made by other
+    /// programs. Not supported on this platform, so it just warns.
+    #[cfg(not(target_os = "linux"))]
+    pub fn watch(_db: &Arc<Db>) -> Result<(), DbError> {
+        warn!("db", "Database changes can only be watched on Linux. \
+               Rehash after editing it.");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use tokio::sync::mpsc::UnboundedSender;
+    fn mask() -> WatchMask {
+        WatchMask::CLOSE_WRITE | WatchMask::CREATE | WatchMask::DELETE
+            | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR
+    }
+    /// Watches `dir` and every directory under it. Every file found along
+    /// the way is added to `files`, in case the directory is new and those
+    /// files were put there before we could watch it.
+    pub fn watch_tree(inotify: &mut Inotify,
+                      dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+                      dir: &Path, files: &mut Vec<PathBuf>)
+                      -> std::io::Result<()> {
+        let wd = match inotify.watches().add(dir, mask()) {
+            Ok(x) => x,
+            // A backing directory that doesn't exist yet can't be watched,
+            // and one that vanished before we got to it doesn't matter.
+            Err(x) if x.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(x) => return Err(x),
+        };
+        dirs.insert(wd, dir.to_owned());
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                watch_tree(inotify, dirs, &entry.path(), files)?;
+            }
+            else {
+                files.push(entry.path());
+            }
+        }
+        Ok(())
+    }
+    /// Body of the watcher thread. Returns when the receiving task is gone,
+    /// or inotify fails.
+    pub fn run_watcher(mut inotify: Inotify,
+                       mut dirs: HashMap<WatchDescriptor, PathBuf>,
+                       send: UnboundedSender<Change>) {
+        let mut buffer = [0; 4096];
+        loop {
+            let mut changes = Vec::new();
+            let mut new_dirs = Vec::new();
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(x) => x,
+                Err(x) => {
+                    warn!("db", "Watching the database: {}", x);
+                    return
+                },
+            };
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    changes.push(Change::Overflow);
+                    continue
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    // The directory is gone.
+                    dirs.remove(&event.wd);
+                    continue
+                }
+                let (dir, name) = match (dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => (dir, name),
+                    _ => continue,
+                };
+                let path = dir.join(name);
+                if !event.mask.contains(EventMask::ISDIR) {
+                    changes.push(Change::File(path));
+                }
+                else if event.mask.intersects(EventMask::CREATE
+                                              | EventMask::MOVED_TO) {
+                    new_dirs.push(path);
+                }
+            }
+            for dir in new_dirs.into_iter() {
+                let mut files = Vec::new();
+                if let Err(x) = watch_tree(&mut inotify, &mut dirs, &dir,
+                                           &mut files) {
+                    warn!("db", "Watching {:?}: {}", dir, x);
+                }
+                changes.extend(files.into_iter().map(Change::File));
+            }
+            for change in changes.into_iter() {
+                if send.send(change).is_err() { return }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use super::super::tests::test_dir;
+    use serde_json::json;
+    /// Replace a file the way an editor might.
+    fn replace(path: &Path, contents: &[u8]) {
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, contents).unwrap();
+        std::fs::rename(&temp, path).unwrap();
+    }
+    /// Wait up to a couple of seconds for `key` to have the value `want`.
+    async fn wait_for(db: &Db, key: &str, want: Option<&Value>) -> bool {
+        for _ in 0 .. 200 {
+            if db.get(key).await.unwrap().as_deref() == want { return true }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        false
+    }
+    #[tokio::test(threaded_scheduler)]
+    async fn reloads_changes() {
+        let dir = test_dir("reloads_changes");
+        let local = dir.join("local");
+        let defaults = dir.join("defaults");
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::create_dir_all(&defaults).unwrap();
+        std::fs::write(defaults.join("motd.cj"), b"\"default\"").unwrap();
+        let db = Arc::new(Db::new(vec![local.clone(), defaults.clone()]));
+        Db::watch(&db).unwrap();
+        assert_eq!(db.get("motd").await.unwrap().as_deref(),
+                   Some(&json!("default")));
+        // a higher-priority file appears
+        replace(&local.join("motd.cj"), b"\"local\"");
+        assert!(wait_for(&db, "motd", Some(&json!("local"))).await);
+        // a broken file keeps the old value
+        std::fs::write(local.join("motd.cj"), b"{oops").unwrap();
+        tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+        assert_eq!(db.get("motd").await.unwrap().as_deref(),
+                   Some(&json!("local")));
+        // deleting it reveals the default again
+        std::fs::remove_file(local.join("motd.cj")).unwrap();
+        assert!(wait_for(&db, "motd", Some(&json!("default"))).await);
+        // so does a whiteout, which deletes it
+        std::fs::write(local.join("motd.wh"), b"").unwrap();
+        assert!(wait_for(&db, "motd", None).await);
+        // files in new directories are noticed too
+        assert_eq!(db.get("accounts/fox").await.unwrap(), None);
+        std::fs::create_dir(local.join("accounts")).unwrap();
+        replace(&local.join("accounts/fox.cj"), b"{\"nick\": \"fox\"}");
+        assert!(wait_for(&db, "accounts/fox",
+                         Some(&json!({"nick": "fox"}))).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Character encoding policy. `Message` deals purely in bytes; this module
+//! decides what those bytes are allowed to be, and translates them for
+//! clients that don't speak UTF-8.
+
+use std::borrow::Cow;
+
+use crate::*;
+
+/// How a connection's bytes relate to the UTF-8 the rest of the server would
+/// like to see.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Encoding {
+    /// Bytes pass through untouched, whatever they are.
+    Passthrough,
+    /// Only valid UTF-8 is accepted. Lines that aren't are rejected with a
+    /// `FAIL`, and `UTF8ONLY` is advertised.
+    Utf8Only,
+    /// The client speaks ISO 8859-1. Lines that are not valid UTF-8 are
+    /// assumed to be Latin-1 and converted; outgoing lines are converted to
+    /// Latin-1.
+    Latin1,
+    /// As `Latin1`, but for Windows code page 1252, which is what "Latin-1"
+    /// usually turns out to mean in practice.
+    Cp1252,
+}
+
+/// The characters that CP1252 puts in the C1 control range. The five holes
+/// in the code page map to the corresponding C1 controls, as in the WHATWG
+/// Encoding Standard.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}',
+    '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}',
+    '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}',
+    '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}',
+    '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// The `FAIL` code defined by the IRCv3 `UTF8ONLY` specification.
+const INVALID_UTF8: &[u8] = b"INVALID_UTF8";
+
+impl Encoding {
+    /// Look up an encoding by the name used in listener options.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "passthrough" => Some(Encoding::Passthrough),
+            "utf8only" => Some(Encoding::Utf8Only),
+            "latin1" => Some(Encoding::Latin1),
+            "cp1252" => Some(Encoding::Cp1252),
+            _ => None,
+        }
+    }
+    /// The name of this encoding, as `from_name` takes it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Passthrough => "passthrough",
+            Encoding::Utf8Only => "utf8only",
+            Encoding::Latin1 => "latin1",
+            Encoding::Cp1252 => "cp1252",
+        }
+    }
+    /// Add whatever `RPL_ISUPPORT` tokens this encoding calls for.
+    pub fn advertise(&self, isupport: &mut ISupport) {
+        match self {
+            Encoding::Utf8Only => isupport.set("UTF8ONLY", None),
+            _ => isupport.unset("UTF8ONLY"),
+        }
+    }
+    /// Convert an incoming line to the server's internal form. Returns `Err`
+    /// if the line must be rejected; see `invalid_utf8_fail`.
+    pub fn decode<'a>(&self, line: &'a [u8])
+                      -> Result<Cow<'a, [u8]>, &'static str> {
+        match self {
+            Encoding::Passthrough => Ok(Cow::Borrowed(line)),
+            Encoding::Utf8Only => match std::str::from_utf8(line) {
+                Ok(_) => Ok(Cow::Borrowed(line)),
+                Err(_) => Err("line is not valid UTF-8"),
+            },
+            // A legacy client may well be sending UTF-8 anyway, e.g. when
+            // pasting, and reinterpreting that as legacy bytes would only
+            // produce mojibake.
+            _ if line.is_ascii() || std::str::from_utf8(line).is_ok()
+                => Ok(Cow::Borrowed(line)),
+            Encoding::Latin1 => Ok(Cow::Owned(
+                line.iter().map(|x| *x as char).collect::<String>()
+                    .into_bytes())),
+            Encoding::Cp1252 => Ok(Cow::Owned(
+                line.iter().map(|x| cp1252_to_char(*x)).collect::<String>()
+                    .into_bytes())),
+        }
+    }
+    /// Convert an outgoing line from the server's internal form to whatever
+    /// the client expects. Characters the client's encoding can't represent
+    /// become `?`.
+    pub fn encode<'a>(&self, line: &'a [u8]) -> Cow<'a, [u8]> {
+        let from_char: fn(char) -> Option<u8> = match self {
+            Encoding::Passthrough | Encoding::Utf8Only
+                => return Cow::Borrowed(line),
+            Encoding::Latin1 => char_to_latin1,
+            Encoding::Cp1252 => char_to_cp1252,
+        };
+        if line.is_ascii() { return Cow::Borrowed(line) }
+        Cow::Owned(String::from_utf8_lossy(line).chars()
+                   .map(|x| from_char(x).unwrap_or(b'?')).collect())
+    }
+}
+
+fn cp1252_to_char(b: u8) -> char {
+    match b {
+        0x80 ..= 0x9F => CP1252_HIGH[(b - 0x80) as usize],
+        _ => b as char,
+    }
+}
+
+fn char_to_latin1(c: char) -> Option<u8> {
+    if (c as u32) < 0x100 { Some(c as u32 as u8) } else { None }
+}
+
+fn char_to_cp1252(c: char) -> Option<u8> {
+    match c as u32 {
+        0x80 ..= 0x9F => None,
+        x if x < 0x100 => Some(x as u8),
+        _ => CP1252_HIGH.iter().position(|x| *x == c)
+            .map(|x| x as u8 + 0x80),
+    }
+}
+
+/// Makes the `FAIL` message sent in response to a line that was rejected
+/// for not being UTF-8. `command` is the command of the offending line, if
+/// one could be made out.
+pub fn invalid_utf8_fail(server: &[u8], command: Option<&[u8]>) -> Message {
+    Message::assemble(Some(&Source::Server { name: server }),
+                      &Command::Textual(b"FAIL"),
+                      &[command.unwrap_or(b"*"), INVALID_UTF8,
+                        b"Message rejected, your IRC software MUST use UTF-8 \
+                          encoding on this network"],
+                      true).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn utf8only() {
+        let e = Encoding::Utf8Only;
+        assert_eq!(e.decode("PRIVMSG #a :héllo".as_bytes()).unwrap(),
+                   "PRIVMSG #a :héllo".as_bytes());
+        assert!(e.decode(b"PRIVMSG #a :h\xE9llo").is_err());
+        let mut isupport = ISupport::new();
+        e.advertise(&mut isupport);
+        assert_eq!(isupport.get("UTF8ONLY"), Some(None));
+        Encoding::Passthrough.advertise(&mut isupport);
+        assert_eq!(isupport.get("UTF8ONLY"), None);
+    }
+    #[test]
+    fn legacy_round_trip() {
+        let e = Encoding::Cp1252;
+        assert_eq!(e.decode(b"\x80 caf\xE9").unwrap(),
+                   "€ café".as_bytes());
+        // UTF-8 from a legacy client is left alone
+        assert_eq!(e.decode("café".as_bytes()).unwrap(),
+                   "café".as_bytes());
+        assert_eq!(e.encode("€ café ☃".as_bytes()), &b"\x80 caf\xE9 ?"[..]);
+        let e = Encoding::Latin1;
+        assert_eq!(e.decode(b"\x80 caf\xE9").unwrap(),
+                   "\u{80} café".as_bytes());
+        assert_eq!(e.encode("€ café".as_bytes()), &b"? caf\xE9"[..]);
+    }
+    #[test]
+    fn fail() {
+        assert_eq!(invalid_utf8_fail(b"irc.example.com", Some(b"PRIVMSG"))
+                   .get_raw(),
+                   &b":irc.example.com FAIL PRIVMSG INVALID_UTF8 :Message \
+                      rejected, your IRC software MUST use UTF-8 encoding \
+                      on this network\r\n"[..]);
+    }
+}
@@ -0,0 +1,396 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! IRC formatting codes: bold, italic, colors and friends. These live inside
+//! message text, usually the trailing parameter of a `PRIVMSG` or `NOTICE`.
+//! This module can pick them out, strip them, check them, and render text
+//! that contains them as plain text or HTML.
+
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    ops::Range,
+};
+
+pub const BOLD: u8 = 0x02;
+pub const COLOR: u8 = 0x03;
+pub const HEX_COLOR: u8 = 0x04;
+pub const RESET: u8 = 0x0F;
+pub const MONOSPACE: u8 = 0x11;
+pub const REVERSE: u8 = 0x16;
+pub const ITALIC: u8 = 0x1D;
+pub const STRIKETHROUGH: u8 = 0x1E;
+pub const UNDERLINE: u8 = 0x1F;
+
+/// The byte that delimits CTCP payloads. Not a formatting code, but it
+/// isn't stray garbage either.
+const CTCP_DELIM: u8 = 0x01;
+
+/// One piece of formatted text.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Token<'a> {
+    /// Text with no formatting codes in it.
+    Text(&'a [u8]),
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Monospace,
+    Reverse,
+    /// Turn off all formatting.
+    Reset,
+    /// A `\x03` color code. `fg` of `None` means "back to the default
+    /// colors"; `bg` of `None` means "leave the background alone".
+    Color { fg: Option<u8>, bg: Option<u8> },
+    /// A `\x04` color code, with colors given as RGB. `None` means the same
+    /// as for `Color`.
+    HexColor { fg: Option<[u8; 3]>, bg: Option<[u8; 3]> },
+}
+
+/// An iterator over the `Token`s in some text, along with the range of the
+/// text each one occupies.
+pub struct Tokens<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+/// Returns an iterator over the `Token`s in `text`.
+pub fn tokens(text: &[u8]) -> Tokens<'_> {
+    Tokens { text, pos: 0 }
+}
+
+fn is_code(b: u8) -> bool {
+    matches!(b, BOLD | COLOR | HEX_COLOR | RESET | MONOSPACE | REVERSE
+             | ITALIC | STRIKETHROUGH | UNDERLINE)
+}
+
+/// Parse up to two decimal digits at the start of `text`.
+fn parse_color_number(text: &[u8]) -> Option<(u8, usize)> {
+    let len = text.iter().take(2).take_while(|x| x.is_ascii_digit()).count();
+    if len == 0 { return None }
+    Some((text[..len].iter().fold(0, |a, x| a * 10 + (x - b'0')), len))
+}
+
+/// Parse exactly six hex digits at the start of `text`.
+fn parse_hex_color(text: &[u8]) -> Option<([u8; 3], usize)> {
+    if text.len() < 6 || !text[..6].iter().all(|x| x.is_ascii_hexdigit()) {
+        return None
+    }
+    let digit = |x: u8| (x as char).to_digit(16).unwrap() as u8;
+    let mut ret = [0; 3];
+    for (n, out) in ret.iter_mut().enumerate() {
+        *out = digit(text[n*2]) << 4 | digit(text[n*2+1]);
+    }
+    Some((ret, 6))
+}
+
+/// Parse the arguments of a color code, given a parser for one color.
+/// Returns the foreground, background, and number of bytes used.
+fn parse_color_args<T>(text: &[u8],
+                       parse: fn(&[u8]) -> Option<(T, usize)>)
+                       -> (Option<T>, Option<T>, usize) {
+    let (fg, mut len) = match parse(text) {
+        Some((fg, len)) => (fg, len),
+        None => return (None, None, 0),
+    };
+    let mut bg = None;
+    if text.get(len) == Some(&b',') {
+        if let Some((color, bg_len)) = parse(&text[len+1..]) {
+            bg = Some(color);
+            len += 1 + bg_len;
+        }
+    }
+    (Some(fg), bg, len)
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (Range<usize>, Token<'a>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let first = *rest.first()?;
+        let (token, len) = match first {
+            BOLD => (Token::Bold, 1),
+            ITALIC => (Token::Italic, 1),
+            UNDERLINE => (Token::Underline, 1),
+            STRIKETHROUGH => (Token::Strikethrough, 1),
+            MONOSPACE => (Token::Monospace, 1),
+            REVERSE => (Token::Reverse, 1),
+            RESET => (Token::Reset, 1),
+            COLOR => {
+                let (fg, bg, len) = parse_color_args(&rest[1..],
+                                                     parse_color_number);
+                (Token::Color { fg, bg }, 1 + len)
+            },
+            HEX_COLOR => {
+                let (fg, bg, len) = parse_color_args(&rest[1..],
+                                                     parse_hex_color);
+                (Token::HexColor { fg, bg }, 1 + len)
+            },
+            _ => {
+                let len = rest.iter().position(|x| is_code(*x))
+                    .unwrap_or(rest.len());
+                (Token::Text(&rest[..len]), len)
+            },
+        };
+        self.pos += len;
+        Some((start .. self.pos, token))
+    }
+}
+
+/// Returns true if `text` contains any formatting codes at all.
+pub fn has_formatting(text: &[u8]) -> bool {
+    text.iter().any(|x| is_code(*x))
+}
+
+/// Returns true if `text` contains any color codes.
+pub fn has_colors(text: &[u8]) -> bool {
+    text.iter().any(|x| *x == COLOR || *x == HEX_COLOR)
+}
+
+/// Remove all formatting codes from `text`.
+pub fn strip(text: &[u8]) -> Cow<'_, [u8]> {
+    if !has_formatting(text) { return Cow::Borrowed(text) }
+    let mut ret = Vec::with_capacity(text.len());
+    for (_, token) in tokens(text) {
+        if let Token::Text(x) = token { ret.extend_from_slice(x) }
+    }
+    Cow::Owned(ret)
+}
+
+/// Remove color codes from `text`, leaving other formatting alone.
+pub fn strip_colors(text: &[u8]) -> Cow<'_, [u8]> {
+    if !has_colors(text) { return Cow::Borrowed(text) }
+    let mut ret = Vec::with_capacity(text.len());
+    for (range, token) in tokens(text) {
+        match token {
+            Token::Color { .. } | Token::HexColor { .. } => (),
+            _ => ret.extend_from_slice(&text[range]),
+        }
+    }
+    Cow::Owned(ret)
+}
+
+/// Check `text` for malformed or unknown control codes: a `\x04` that isn't
+/// followed by a complete hex color, and C0 control characters that aren't
+/// formatting codes (or the CTCP delimiter).
+pub fn validate(text: &[u8]) -> Result<(), &'static str> {
+    for (range, token) in tokens(text) {
+        match token {
+            Token::HexColor { fg: None, .. }
+            if text.get(range.end).map(|x| x.is_ascii_hexdigit())
+                .unwrap_or(false)
+                => return Err("incomplete hex color code"),
+            Token::Text(x) if x.iter().any(|x| *x < 0x20 && *x != CTCP_DELIM)
+                => return Err("unknown control character"),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Render `text` as plain text, discarding formatting. Invalid UTF-8 is
+/// replaced.
+pub fn to_plain_text(text: &[u8]) -> String {
+    String::from_utf8_lossy(&strip(text)).into_owned()
+}
+
+/// The standard mIRC palette, followed by the extended colors 16 through 98.
+/// Color 99 means "default".
+const PALETTE: [u32; 99] = [
+    0xFFFFFF, 0x000000, 0x00007F, 0x009300, 0xFF0000, 0x7F0000, 0x9C009C,
+    0xFC7F00, 0xFFFF00, 0x00FC00, 0x009393, 0x00FFFF, 0x0000FC, 0xFF00FF,
+    0x7F7F7F, 0xD2D2D2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472C, 0x004747,
+    0x002747, 0x000047, 0x2E0047, 0x470047, 0x47002A,
+    0x740000, 0x743A00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474,
+    0x004074, 0x000074, 0x4B0074, 0x740074, 0x740045,
+    0xB50000, 0xB56300, 0xB5B500, 0x7DB500, 0x00B500, 0x00B571, 0x00B5B5,
+    0x0063B5, 0x0000B5, 0x7500B5, 0xB500B5, 0xB5006B,
+    0xFF0000, 0xFF8C00, 0xFFFF00, 0xB2FF00, 0x00FF00, 0x00FFA0, 0x00FFFF,
+    0x008CFF, 0x0000FF, 0xA500FF, 0xFF00FF, 0xFF0098,
+    0xFF5959, 0xFFB459, 0xFFFF71, 0xCFFF60, 0x6FFF6F, 0x65FFC9, 0x6DFFFF,
+    0x59B4FF, 0x5959FF, 0xC459FF, 0xFF66FF, 0xFF59BC,
+    0xFF9C9C, 0xFFD39C, 0xFFFF9C, 0xE2FF9C, 0x9CFF9C, 0x9CFFDB, 0x9CFFFF,
+    0x9CD3FF, 0x9C9CFF, 0xDC9CFF, 0xFF9CFF, 0xFF94D3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4D4D4D, 0x656565, 0x818181,
+    0x9F9F9F, 0xBCBCBC, 0xE2E2E2, 0xFFFFFF,
+];
+
+/// The formatting in effect at some point in the text.
+#[derive(Clone,Copy,Default,PartialEq,Eq)]
+struct State {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    monospace: bool,
+    reverse: bool,
+    fg: Option<u32>,
+    bg: Option<u32>,
+}
+
+impl State {
+    fn apply(&mut self, token: &Token) {
+        let palette = |x: u8| PALETTE.get(x as usize).copied();
+        let rgb = |x: [u8; 3]|
+            (x[0] as u32) << 16 | (x[1] as u32) << 8 | x[2] as u32;
+        match *token {
+            Token::Text(_) => (),
+            Token::Bold => self.bold = !self.bold,
+            Token::Italic => self.italic = !self.italic,
+            Token::Underline => self.underline = !self.underline,
+            Token::Strikethrough => self.strikethrough = !self.strikethrough,
+            Token::Monospace => self.monospace = !self.monospace,
+            Token::Reverse => self.reverse = !self.reverse,
+            Token::Reset => *self = State::default(),
+            Token::Color { fg: None, .. }
+            | Token::HexColor { fg: None, .. } => {
+                self.fg = None;
+                self.bg = None;
+            },
+            Token::Color { fg: Some(fg), bg } => {
+                self.fg = palette(fg);
+                if let Some(bg) = bg { self.bg = palette(bg) }
+            },
+            Token::HexColor { fg: Some(fg), bg } => {
+                self.fg = Some(rgb(fg));
+                if let Some(bg) = bg { self.bg = Some(rgb(bg)) }
+            },
+        }
+    }
+    /// The CSS for this state, or `None` if it's the default state. When
+    /// reversed with no colors set, assumes black text on white.
+    fn css(&self) -> Option<String> {
+        if *self == State::default() { return None }
+        let mut ret = String::new();
+        if self.bold { ret.push_str("font-weight:bold;") }
+        if self.italic { ret.push_str("font-style:italic;") }
+        match (self.underline, self.strikethrough) {
+            (true, true)
+                => ret.push_str("text-decoration:underline line-through;"),
+            (true, false) => ret.push_str("text-decoration:underline;"),
+            (false, true) => ret.push_str("text-decoration:line-through;"),
+            (false, false) => (),
+        }
+        if self.monospace { ret.push_str("font-family:monospace;") }
+        let (fg, bg) = if self.reverse {
+            (Some(self.bg.unwrap_or(PALETTE[0])),
+             Some(self.fg.unwrap_or(PALETTE[1])))
+        } else { (self.fg, self.bg) };
+        if let Some(fg) = fg {
+            let _ = write!(ret, "color:#{:06x};", fg);
+        }
+        if let Some(bg) = bg {
+            let _ = write!(ret, "background-color:#{:06x};", bg);
+        }
+        Some(ret)
+    }
+}
+
+fn escape_html(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Render `text` as HTML, with formatting turned into `<span>`s with inline
+/// styles. Invalid UTF-8 is replaced.
+pub fn to_html(text: &[u8]) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut state = State::default();
+    let mut open = false;
+    for (_, token) in tokens(text) {
+        match token {
+            Token::Text(x) => {
+                if !open {
+                    if let Some(css) = state.css() {
+                        let _ = write!(ret, "<span style=\"{}\">", css);
+                        open = true;
+                    }
+                }
+                escape_html(&mut ret, &String::from_utf8_lossy(x));
+            },
+            token => {
+                let old = state;
+                state.apply(&token);
+                if open && state != old {
+                    ret.push_str("</span>");
+                    open = false;
+                }
+            },
+        }
+    }
+    if open { ret.push_str("</span>") }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn tokenize() {
+        let text = b"\x02bold\x02 \x0304,12red\x03,5 \x04FF8000orange\x0f";
+        let got: Vec<Token> = tokens(text).map(|(_, x)| x).collect();
+        assert_eq!(got, vec![
+            Token::Bold,
+            Token::Text(b"bold"),
+            Token::Bold,
+            Token::Text(b" "),
+            Token::Color { fg: Some(4), bg: Some(12) },
+            Token::Text(b"red"),
+            Token::Color { fg: None, bg: None },
+            Token::Text(b",5 "),
+            Token::HexColor { fg: Some([0xFF, 0x80, 0x00]), bg: None },
+            Token::Text(b"orange"),
+            Token::Reset,
+        ]);
+    }
+    #[test]
+    fn strip_and_detect() {
+        let text = b"\x1Fhi\x1F \x033,1there\x03";
+        assert!(has_formatting(text));
+        assert!(has_colors(text));
+        assert_eq!(&strip(text)[..], b"hi there");
+        assert_eq!(&strip_colors(text)[..], b"\x1Fhi\x1F there");
+        assert!(!has_formatting(b"plain"));
+        assert!(matches!(strip(b"plain"), Cow::Borrowed(_)));
+        assert_eq!(to_plain_text(b"\x02caf\xC3\xA9"), "café");
+    }
+    #[test]
+    fn validation() {
+        assert!(validate(b"\x02ok\x0F \x01ACTION waves\x01").is_ok());
+        assert!(validate(b"\x04FF80 oops").is_err());
+        assert!(validate(b"bell\x07").is_err());
+    }
+    #[test]
+    fn html() {
+        assert_eq!(to_html(b"a \x02<b>\x02 \x034red\x0f"),
+                   "a <span style=\"font-weight:bold;\">&lt;b&gt;</span> \
+                    <span style=\"color:#ff0000;\">red</span>");
+        assert_eq!(to_html(b"\x16rev"),
+                   "<span style=\"color:#ffffff;background-color:#000000;\">\
+                    rev</span>");
+    }
+}
@@ -1,33 +1,166 @@
 use crate::*;
 
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 pub struct Invocation {
     pub runtime: tokio::runtime::Runtime,
+    pub casemapping: CaseMapping,
+    pub db: Arc<Db>,
+    /// Where an operator's `SNAPSHOT` writes the database, if anywhere.
+    pub snapshot_file: Option<PathBuf>,
 }
 
 fn print_usage(program_name: &str, opts: getopts::Options) {
     let brief = format!(r#"
-Usage: {} options...
+Usage: {} options... [db COMMAND ARGS...]
 
 Foxy IRCd is IRC server software written in Rust."#, program_name);
     print!(r#"{}
+A listener address may be followed by comma-separated options:
+
+  strict      Reject any line that deviates from the RFC grammar.
+  lenient     Accept common deviations, such as extra spaces, trailing
+              whitespace, lone LF line endings, lowercase commands, and more
+              than 15 parameters. (default)
+  passthrough Pass bytes through without regard to encoding. (default)
+  utf8only    Reject lines that aren't valid UTF-8, and advertise UTF8ONLY.
+  latin1      Treat lines that aren't valid UTF-8 as Latin-1, and convert
+              them to UTF-8. Convert outgoing lines to Latin-1.
+  cp1252      As latin1, but using Windows code page 1252.
+
+For example: -l [::]:6667,strict,utf8only
+
 If NO -l options are given, the default is:
 
   -l [::]:6667
+
+--copy-db copies the database given by -d or --sqlite to one of:
+
+  dir:PATH    JSON files in the directory PATH.
+  sqlite:FILE The SQLite database FILE.
+
+Giving "db" and a command works on the database given by -d or --sqlite,
+instead of starting the server. The commands are:
+
+  get KEY       Print the datum with the given key.
+  set KEY JSON  Replace the datum with the given key.
+  delete KEY    Delete the datum with the given key. If it's in a lower
+                priority -d directory, it's hidden rather than deleted.
+  list [PREFIX] List the keys that start with PREFIX, or every key.
+  check         Look for data that can't be read, files whose names aren't
+                valid keys, and files hidden by higher priority directories.
+  export FILE   Write everything in the database to one file: a tarball if
+                FILE ends in .tar, JSON Lines otherwise.
+  import FILE   Restore a file written by export into an empty database, and
+                list what changed. With --dry-run, only list what would
+                change.
+
+These see the database exactly as a running server would. A server watching
+the same directories notices changes made this way.
 "#, opts.usage(&brief));
     // TODO: add to default, -s 0.0.0.0:6697, if there's a key and cert
 }
 
+/// Parse a listener specification of the form `ADDR:PORT[,OPTION...]`.
+fn parse_listener(spec: &str)
+                  -> Result<(SocketAddr, ListenerOptions), String> {
+    let mut split = spec.split(',');
+    let addr = split.next().unwrap_or("");
+    let addr: SocketAddr = match addr.parse() {
+        Ok(x) => x,
+        Err(_) => return Err(format!("Invalid IP address+host: {}", addr)),
+    };
+    let mut options = ListenerOptions::default();
+    for option in split {
+        options.apply(option)?;
+    }
+    Ok((addr, options))
+}
+
+/// Does one of the `db` commands, printing the results.
+async fn db_command(db: &Db, args: &[String], dry_run: bool)
+                    -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match &args[..] {
+        ["get", key] => match db.get(key).await.map_err(|x| x.to_string())? {
+            Some(datum) => println!("{:#}", datum),
+            None => return Err(format!("{}: not found", key)),
+        },
+        ["set", key, json] => {
+            let datum = serde_json::from_str(json)
+                .map_err(|x| format!("Invalid JSON: {}", x))?;
+            db.insert(key, datum).await.map_err(|x| x.to_string())?;
+        },
+        ["delete", key] => {
+            if db.get(key).await.map_err(|x| x.to_string())?.is_none() {
+                return Err(format!("{}: not found", key))
+            }
+            db.remove(key).await.map_err(|x| x.to_string())?;
+        },
+        ["list"] | ["list", _] => {
+            let prefix = args.get(1).copied().unwrap_or("");
+            for key in db.list(prefix).await.map_err(|x| x.to_string())? {
+                println!("{}", key);
+            }
+        },
+        ["check"] => {
+            let problems = db.check().await.map_err(|x| x.to_string())?;
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            if problems.is_empty() {
+                println!("No problems found in {}.", db.describe());
+            }
+            else {
+                return Err(format!("{} problems found in {}.",
+                                   problems.len(), db.describe()))
+            }
+        },
+        ["export", file] => {
+            let path = Path::new(file);
+            let count = db.export(path, ArchiveFormat::from_path(path)).await
+                .map_err(|x| format!("Export failed: {}", x))?;
+            println!("Exported {} data from {} to {:?}.", count,
+                     db.describe(), path);
+        },
+        ["import", file] => {
+            let differences = db.import(Path::new(file), dry_run).await
+                .map_err(|x| format!("Import failed: {}", x))?;
+            for difference in differences.iter() {
+                println!("{}", difference);
+            }
+            if dry_run {
+                println!("{} differences. Nothing was changed.",
+                         differences.len());
+            }
+            else {
+                println!("Imported {} changes into {}.", differences.len(),
+                         db.describe());
+            }
+        },
+        _ => return Err(format!("Unknown db command: {}", args.join(" "))),
+    }
+    Ok(())
+}
+
+/// Works out what we were asked to do. If it's to run the server, sets it
+/// up and returns it. Otherwise, does it (e.g. a `db` command) and returns the
+/// status to exit with: 0 if it went well, 1 if it didn't, or if it found
+/// problems.
 pub fn get_invocation<I>(incoming_connection_handler: I)
-                         -> Option<Invocation>
-where I: FnMut(Box<dyn FoxyStream>) + Clone + Send + 'static {
+                         -> Result<Invocation, i32>
+where I: FnMut(Box<dyn FoxyStream>, ListenerOptions, Arc<ISupport>)
+    + Clone + Send + 'static {
     let mut opts = getopts::Options::new();
     opts.optflag("h", "help", ""); // heh
     opts.optflag("?", "usage", "Print what you're reading now.");
     opts.optmulti("l", "listen", "Listen for non-TLS connections on a given \
                                   address and port. May be given more than \
-                                  once.", "ADDR:PORT");
+                                  once.", "ADDR:PORT[,OPTION...]");
     //opts.optmulti("s", "listen-tls", "Listen for TLS connections on a given \
     //                                  address and port. May be given more \
     //                                  than once.", "ADDR:PORT");
@@ -35,71 +168,215 @@ where I: FnMut(Box<dyn FoxyStream>) + Clone + Send + 'static {
                                   If given more than once, they are in \
                                   descending order of priority, and only the \
                                   first one will be written to.", "PATH");
+    opts.optopt("", "sqlite", "Keep the database in an SQLite file instead \
+                               of directories.", "FILE");
+    opts.optopt("", "copy-db", "Copy everything in the database to another \
+                                one, upgrading old records on the way, then \
+                                exit.", "KIND:WHERE");
+    opts.optflag("", "migrate-db", "Upgrade every old record in the \
+                                    database to the current format, then \
+                                    exit.");
+    opts.optflag("n", "dry-run", "With db import, change nothing.");
+    opts.optopt("", "snapshot-file", "Let operators write the database to \
+                                      FILE with SNAPSHOT while the server \
+                                      runs, as db export would.", "FILE");
+    opts.optopt("", "log", "Specify what to log, per module. Levels are \
+                            error, warn, info, debug, and trace.",
+                "[LEVEL][,MODULE=LEVEL...] (default info)");
+    opts.optopt("", "log-format", "Specify how to write the log.",
+                "text (default) | json");
+    opts.optopt("", "log-file", "Log to a file instead of stderr. It's \
+                                 rotated on SIGHUP.", "FILE");
+    opts.optopt("", "log-max-size", "Rotate the log file when it would grow \
+                                     bigger than this.", "BYTES");
+    opts.optopt("c", "casemapping", "Specify how nicknames and channel names \
+                                     are compared.",
+                "ascii (default) | rfc1459 | strict-rfc1459 | rfc7613");
     opts.optopt("t", "threads", "Specify the number of reactor threads to \
                                  use.", "NUM | \"auto\" (default 1)");
     let args: Vec<String> = std::env::args().collect();
-    let program_name = args.get(0).map(|x| x.as_str()).unwrap_or("foxy_ircd");
+    let program_name = args.first().map(|x| x.as_str()).unwrap_or("foxy_ircd");
     if args.len() <= 1 {
         println!("At least one argument is required. If you really mean to \
                   start with the default listeners and runtime, and no \
                   database, pass '--' as the only argument.");
         print_usage(program_name, opts);
-        return None
+        return Err(1)
     }
     let matches = match opts.parse(&args[1..]) {
         Ok(x) => x,
         Err(x) => {
             println!("{}", x);
             print_usage(program_name, opts);
-            return None
+            return Err(1)
         }
     };
     if matches.opt_present("?") || matches.opt_present("h") {
         print_usage(program_name, opts);
-        return None
+        return Err(0)
+    }
+    let filter = match logging::Filter::parse(&matches.opt_str("log")
+                                              .unwrap_or_default()) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("{}", x);
+            print_usage(program_name, opts);
+            return Err(1)
+        },
+    };
+    let format = match matches.opt_str("log-format") {
+        None => logging::Format::Text,
+        Some(x) => match logging::Format::from_name(&x) {
+            Some(x) => x,
+            None => {
+                println!("Unknown log format: {}", x);
+                print_usage(program_name, opts);
+                return Err(1)
+            },
+        },
+    };
+    let max_size = match matches.opt_str("log-max-size").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(_)) => {
+            println!("Invalid log file size.");
+            print_usage(program_name, opts);
+            return Err(1)
+        },
+    };
+    let log_file = matches.opt_str("log-file").map(PathBuf::from);
+    if let Err(x) = logging::configure(filter, format, log_file.as_deref()
+                                       .map(|x| (x, max_size))) {
+        println!("Unable to open the log file: {}", x);
+        return Err(1)
     }
     // keep this around...
     let wanted_threads = matches.opt_str("t");
     // ...to borrow here.
-    let wanted_threads = match wanted_threads.as_ref().map(|x| x.as_str()) {
+    let wanted_threads = match wanted_threads.as_deref() {
         None | Some("1") => 1,
         Some("auto") => num_cpus::get(),
         Some(x) => match x.parse() {
             Err(_) | Ok(0) => {
                 println!("Invalid number of threads specified.");
                 print_usage(program_name, opts);
-                return None
+                return Err(1)
+            },
+            Ok(x) => x,
+        },
+    };
+    let casemapping = match matches.opt_str("c") {
+        None => CaseMapping::default(),
+        Some(x) => match CaseMapping::from_name(&x) {
+            Some(x) => x,
+            None => {
+                println!("Unknown case mapping: {}", x);
+                print_usage(program_name, opts);
+                return Err(1)
             },
+        },
+    };
+    let backend: Box<dyn Backend> = match matches.opt_str("sqlite") {
+        None => Box::new(JsonDir::new(matches.opt_strs("d").into_iter()
+                                      .map(PathBuf::from).collect())),
+        Some(_) if matches.opt_present("d") => {
+            println!("Only one of -d and --sqlite may be given.");
+            print_usage(program_name, opts);
+            return Err(1)
+        },
+        Some(x) => match open_backend(&format!("sqlite:{}", x)) {
             Ok(x) => x,
+            Err(x) => {
+                eprintln!("{}", x);
+                return Err(1)
+            },
         },
     };
+    let db = Arc::new(Db::with_backend(backend));
     let mut builder = tokio::runtime::Builder::new();
-    let runtime = match wanted_threads {
+    let mut runtime = match wanted_threads {
         1 => builder.basic_scheduler(),
         wanted_threads => builder.threaded_scheduler()
             .core_threads(wanted_threads),
     }.enable_io().build().unwrap();
+    if let Some(spec) = matches.opt_str("copy-db") {
+        let to = match open_backend(&spec) {
+            Ok(x) => x,
+            Err(x) => {
+                println!("{}", x);
+                print_usage(program_name, opts);
+                return Err(1)
+            },
+        };
+        return match runtime.block_on(db.copy_to(&*to)) {
+            Ok(count) => {
+                println!("Copied {} data from {} to {}.", count,
+                         db.describe(), to.describe());
+                Err(0)
+            },
+            Err(x) => {
+                eprintln!("Copy failed: {}", x);
+                Err(1)
+            },
+        }
+    }
+    if matches.opt_present("migrate-db") {
+        return match runtime.block_on(db.upgrade_all()) {
+            Ok(count) => {
+                println!("Upgraded {} records in {}.", count, db.describe());
+                Err(0)
+            },
+            Err(x) => {
+                eprintln!("Upgrade failed: {}", x);
+                Err(1)
+            },
+        }
+    }
+    match matches.free.split_first() {
+        None => (),
+        Some((command, args)) if command == "db" => {
+            return match runtime.block_on(db_command(
+                &db, args, matches.opt_present("n"))) {
+                Ok(()) => Err(0),
+                Err(x) => {
+                    eprintln!("{}", x);
+                    Err(1)
+                },
+            }
+        },
+        Some((command, _)) => {
+            println!("Unknown command: {}", command);
+            print_usage(program_name, opts);
+            return Err(1)
+        },
+    }
     let mut listeners = Vec::new();
     if !matches.opt_present("l") /*&& !matches.opt_present("s")*/ {
-        listeners.push((("[::]:6667").parse().unwrap(), false));
+        listeners.push((("[::]:6667").parse().unwrap(), false,
+                        ListenerOptions::default()));
     }
     for el in matches.opt_strs("l") {
-        let addr: SocketAddr = match el.parse() {
+        let (addr, options) = match parse_listener(&el) {
             Ok(x) => x,
-            Err(_) => {
-                println!("Invalid IP address+host: {}", el);
+            Err(x) => {
+                println!("{}", x);
                 print_usage(program_name, opts);
-                return None
+                return Err(1)
             },
         };
-        listeners.push((addr, false))
+        listeners.push((addr, false, options))
     }
+    let server_isupport = ISupport::for_server(casemapping,
+                                               &channel::Prefixes::default());
     if !runtime.enter(|| {
-        for (addr, _tls) in listeners.into_iter() {
+        for (addr, _tls, options) in listeners.into_iter() {
+            let mut isupport = server_isupport.clone();
+            options.encoding.advertise(&mut isupport);
+            let isupport = Arc::new(isupport);
             let listener = match std::net::TcpListener::bind(addr) {
                 Ok(x) => x,
                 Err(x) => {
-                    eprintln!("Unable to bind to {}: {}", addr, x);
+                    error!("server", "Unable to bind to {}: {}", addr, x);
                     return false
                 },
             };
@@ -110,14 +387,16 @@ where I: FnMut(Box<dyn FoxyStream>) + Clone + Send + 'static {
             runtime.spawn(async move {
                 loop {
                     if let Ok((sock, _)) = listener.accept().await {
-                        incoming_connection_handler(Box::new(sock));
+                        incoming_connection_handler(Box::new(sock), options,
+                                                    isupport.clone());
                     }
                 }
             });
         }
         true
-    }) { return None }
-    Some(Invocation {
-        runtime
+    }) { return Err(1) }
+    Ok(Invocation {
+        runtime, casemapping, db,
+        snapshot_file: matches.opt_str("snapshot-file").map(PathBuf::from),
     })
 }
@@ -0,0 +1,135 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The `RPL_ISUPPORT` (005) token list, which tells clients which features
+//! and limits this server has.
+
+use crate::*;
+
+/// The most tokens we will put into a single `RPL_ISUPPORT` line. Together
+/// with the nick and the trailer, this keeps us within the 15 parameter
+/// limit.
+const MAX_TOKENS_PER_LINE: usize = 13;
+
+/// An ordered set of `RPL_ISUPPORT` tokens.
+#[derive(Clone,Debug,Default)]
+pub struct ISupport {
+    tokens: Vec<(String, Option<Vec<u8>>)>,
+}
+
+/// Escape an `RPL_ISUPPORT` value. Spaces, backslashes and equals signs, as
+/// well as anything that isn't printable ASCII, become `\xHH`.
+fn escape_value(value: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(value.len());
+    for &b in value {
+        if b <= b' ' || b >= 0x7F || b == b'\\' || b == b'=' {
+            ret.extend_from_slice(format!("\\x{:02X}", b).as_bytes());
+        }
+        else {
+            ret.push(b);
+        }
+    }
+    ret
+}
+
+impl ISupport {
+    pub fn new() -> ISupport { ISupport::default() }
+    /// The tokens describing the server as a whole, given its case mapping
+    /// and channel prefix modes. Each listener adds its own on top; see
+    /// `Encoding::advertise`.
+    pub fn for_server(casemapping: CaseMapping, prefixes: &channel::Prefixes)
+                      -> ISupport {
+        let mut isupport = ISupport::new();
+        casemapping.advertise(&mut isupport);
+        names::advertise(&mut isupport);
+        channel::advertise(&mut isupport, prefixes);
+        user::advertise(&mut isupport);
+        isupport
+    }
+    /// Set a token, replacing any previous value it had. Token names are
+    /// uppercase letters and digits.
+    pub fn set(&mut self, name: &str, value: Option<&[u8]>) {
+        debug_assert!(!name.is_empty() && name.bytes().all(|x| {
+            x.is_ascii_uppercase() || x.is_ascii_digit()
+        }));
+        let value = value.map(escape_value);
+        match self.tokens.iter_mut().find(|(x, _)| x == name) {
+            Some(token) => token.1 = value,
+            None => self.tokens.push((name.to_owned(), value)),
+        }
+    }
+    /// Remove a token, if present.
+    pub fn unset(&mut self, name: &str) {
+        self.tokens.retain(|(x, _)| x != name)
+    }
+    /// Returns the (escaped) value of a token. `Some(None)` means the token
+    /// is present but has no value.
+    pub fn get(&self, name: &str) -> Option<Option<&[u8]>> {
+        self.tokens.iter().find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_deref())
+    }
+    /// Makes the `RPL_ISUPPORT` lines to send to a client, splitting them up
+    /// as needed.
+    pub fn messages(&self, server: &[u8], nick: &[u8])
+                    -> Result<Vec<Message>, &'static str> {
+        let tokens: Vec<Vec<u8>> = self.tokens.iter().map(|(name, value)| {
+            let mut token = name.as_bytes().to_vec();
+            if let Some(value) = value {
+                token.push(b'=');
+                token.extend_from_slice(value);
+            }
+            token
+        }).collect();
+        let tokens: Vec<&[u8]> = tokens.iter().map(|x| &x[..]).collect();
+        Message::pack_params(Some(&Source::Server { name: server }),
+                             &Command::Numeric(numeric::RPL_ISUPPORT),
+                             &[nick], &tokens[..],
+                             Some(b"are supported by this server"),
+                             MAX_TOKENS_PER_LINE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn set_and_escape() {
+        let mut isupport = ISupport::new();
+        isupport.set("NETWORK", Some(b"Foxy Net"));
+        isupport.set("SAFELIST", None);
+        isupport.set("NETWORK", Some(b"Foxy=Net"));
+        assert_eq!(isupport.get("NETWORK"), Some(Some(&b"Foxy\\x3DNet"[..])));
+        assert_eq!(isupport.get("SAFELIST"), Some(None));
+        assert_eq!(isupport.get("NICKLEN"), None);
+        let messages = isupport.messages(b"irc.example.com", b"nick")
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_raw(),
+                   &b":irc.example.com 005 nick NETWORK=Foxy\\x3DNet \
+                      SAFELIST :are supported by this server\r\n"[..]);
+    }
+    #[test]
+    fn for_server() {
+        let isupport = ISupport::for_server(CaseMapping::StrictRfc1459,
+                                            &channel::Prefixes::default());
+        assert_eq!(isupport.get("CASEMAPPING"),
+                   Some(Some(&b"strict-rfc1459"[..])));
+        assert_eq!(isupport.get("PREFIX"), Some(Some(&b"(qaohv)~&@%+"[..])));
+        assert_eq!(isupport.get("CHANTYPES"), Some(Some(&b"#&"[..])));
+        assert_eq!(isupport.get("UTF8ONLY"), None);
+    }
+}
@@ -0,0 +1,46 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Foxy IRCd, as a library: everything but `main`, so that benchmarks can
+//! get at it too.
+
+#[macro_use]
+pub mod logging;
+pub mod message;
+pub use message::{Command, Message, MessageRef, ParseMode, Source};
+pub mod db;
+pub use db::*;
+pub mod case;
+pub use case::*;
+pub mod channel;
+pub use channel::Channels;
+pub mod invocation;
+pub use invocation::*;
+pub mod connection;
+pub use connection::*;
+pub mod ctcp;
+pub mod encoding;
+pub use encoding::Encoding;
+pub mod formatting;
+pub mod isupport;
+pub use isupport::ISupport;
+pub mod mask;
+pub use mask::Mask;
+pub mod names;
+pub mod numeric;
+pub mod user;
+pub use user::User;
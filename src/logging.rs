@@ -0,0 +1,499 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Logging, for everything the server has to say that isn't said to a
+//! client.
+//!
+//! Every line has a level and a module (`"db"`, `"conn"`, `"server"`...), and
+//! a `Filter` decides, per module, which levels are written. Lines are
+//! written as text or as JSON Lines, to stderr or to a file. A file is
+//! rotated when it gets too big, or when we get SIGHUP.
+//!
+//! A task working on behalf of a connection runs inside `scope`, and every
+//! line it logs says which connection it was.
+//!
+//! Log with the `error!`, `warn!`, `info!`, `debug!` and `trace!` macros,
+//! which take the module and then `format!` arguments.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, AtomicUsize,
+                                Ordering}},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use serde_json::Value;
+
+/// How many old log files to keep when rotating. `FILE.1` is the newest.
+const ROTATED_FILES: u32 = 5;
+
+/// How important a line is.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info,
+                             Level::Debug, Level::Trace];
+    pub fn from_name(name: &str) -> Option<Level> {
+        Level::ALL.iter().copied()
+            .find(|x| x.name().eq_ignore_ascii_case(name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// Which levels are logged, for each module.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Filter {
+    /// The level for modules not in `modules`.
+    default: Level,
+    modules: Vec<(String, Level)>,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter { default: Level::Info, modules: Vec::new() }
+    }
+}
+
+impl Filter {
+    /// Parses a filter like `warn,db=debug,conn=info`: a comma-separated
+    /// list of `MODULE=LEVEL`, and at most one bare `LEVEL` for every other
+    /// module (default `info`). Each level includes the ones above it.
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut ret = Filter::default();
+        for part in spec.split(',').filter(|x| !x.is_empty()) {
+            let (module, level) = match part.split_once('=') {
+                Some((module, level)) => (Some(module), level),
+                None => (None, part),
+            };
+            let level = Level::from_name(level)
+                .ok_or_else(|| format!("Unknown log level: {}", level))?;
+            match module {
+                Some(module) => ret.modules.push((module.to_owned(), level)),
+                None => ret.default = level,
+            }
+        }
+        Ok(ret)
+    }
+    pub fn level_for(&self, module: &str) -> Level {
+        self.modules.iter().rev().find(|x| x.0 == module)
+            .map(|x| x.1).unwrap_or(self.default)
+    }
+    /// The most detailed level any module gets.
+    fn max_level(&self) -> Level {
+        self.modules.iter().map(|x| x.1).chain(Some(self.default))
+            .max().unwrap_or(self.default)
+    }
+}
+
+/// How lines are written.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Format {
+    /// `TIME LEVEL MODULE [CONNECTION] MESSAGE`, for people.
+    Text,
+    /// One JSON object per line, for log pipelines.
+    JsonLines,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Who a connection is, for logging. Made when the connection is accepted;
+/// the nick is filled in once there is one.
+#[derive(Debug)]
+pub struct ConnectionContext {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    nick: Mutex<Option<String>>,
+}
+
+impl ConnectionContext {
+    /// Makes the context for a new connection, with a new id.
+    pub fn new(peer: Option<SocketAddr>) -> Arc<ConnectionContext> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Arc::new(ConnectionContext {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            nick: Mutex::new(None),
+        })
+    }
+    pub fn nick(&self) -> Option<String> { self.nick.lock().unwrap().clone() }
+    pub fn set_nick(&self, nick: Option<String>) {
+        *self.nick.lock().unwrap() = nick;
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: Arc<ConnectionContext>;
+}
+
+/// Runs `f` on behalf of a connection. Everything it logs says so.
+pub async fn scope<F: Future>(context: Arc<ConnectionContext>, f: F)
+                              -> F::Output {
+    CONTEXT.scope(context, f).await
+}
+
+/// Returns the connection the current task is working for, if any.
+pub fn current_context() -> Option<Arc<ConnectionContext>> {
+    CONTEXT.try_with(|x| x.clone()).ok()
+}
+
+/// Where lines go.
+enum Output {
+    Stderr,
+    File {
+        path: PathBuf,
+        file: File,
+        /// How big the file is.
+        size: u64,
+        /// How big it may get before it's rotated.
+        max_size: Option<u64>,
+    },
+}
+
+struct Logger {
+    filter: Filter,
+    format: Format,
+    output: Output,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+/// `max_level` of the current filter, so that lines nobody wants cost almost
+/// nothing.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+/// Set by the SIGHUP handler.
+static ROTATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts logging with the given settings. If `file` is given, lines go
+/// there instead of stderr, and the file is rotated when it would grow past
+/// `max_size` bytes, if given, and when we get SIGHUP.
+pub fn configure(filter: Filter, format: Format,
+                 file: Option<(&Path, Option<u64>)>) -> io::Result<()> {
+    let output = match file {
+        None => Output::Stderr,
+        Some((path, max_size)) => {
+            let file = open_log(path)?;
+            let size = file.metadata()?.len();
+            rotate_on_sighup();
+            Output::File { path: path.to_owned(), file, size, max_size }
+        },
+    };
+    MAX_LEVEL.store(filter.max_level() as usize, Ordering::Relaxed);
+    *LOGGER.lock().unwrap() = Some(Logger { filter, format, output });
+    Ok(())
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Returns true if a line at this level from this module would be written.
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level as usize > MAX_LEVEL.load(Ordering::Relaxed) { return false }
+    match LOGGER.lock().unwrap().as_ref() {
+        Some(logger) => level <= logger.filter.level_for(module),
+        None => level <= Filter::default().level_for(module),
+    }
+}
+
+/// Writes a line. Use the macros instead.
+pub fn write(level: Level, module: &str, message: fmt::Arguments) {
+    let context = current_context();
+    let mut logger = LOGGER.lock().unwrap();
+    let format = logger.as_ref().map(|x| x.format).unwrap_or(Format::Text);
+    let mut line = format_line(format, SystemTime::now(), level, module,
+                               context.as_deref(), message);
+    line.push('\n');
+    let output = match logger.as_mut() {
+        Some(x) => &mut x.output,
+        None => &mut Output::Stderr,
+    };
+    match output {
+        Output::Stderr => {
+            let _ = io::stderr().write_all(line.as_bytes());
+        },
+        Output::File { path, file, size, max_size } => {
+            let too_big = max_size.map(|max| *size > 0
+                                       && *size + line.len() as u64 > max)
+                .unwrap_or(false);
+            if ROTATE_REQUESTED.swap(false, Ordering::Relaxed) || too_big {
+                match rotate(path) {
+                    Ok(x) => { *file = x; *size = 0; },
+                    Err(x) => {
+                        let _ = writeln!(io::stderr(), "Unable to rotate \
+                                                        {:?}: {}", path, x);
+                    },
+                }
+            }
+            if let Err(x) = file.write_all(line.as_bytes()) {
+                // Nowhere else to complain but here.
+                let _ = writeln!(io::stderr(), "Unable to log to {:?}: {}",
+                                 path, x);
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            *size += line.len() as u64;
+        },
+    }
+}
+
+/// Renames `FILE` to `FILE.1` (and `FILE.1` to `FILE.2`, and so on), and
+/// opens a new `FILE`. If something else already moved `FILE` away, as
+/// logrotate does before sending SIGHUP, just opens a new one.
+fn rotate(path: &Path) -> io::Result<File> {
+    let numbered = |n: u32| {
+        let mut ret = path.as_os_str().to_owned();
+        ret.push(format!(".{}", n));
+        PathBuf::from(ret)
+    };
+    if path.exists() {
+        for n in (1 .. ROTATED_FILES).rev() {
+            let _ = std::fs::rename(numbered(n), numbered(n + 1));
+        }
+        std::fs::rename(path, numbered(1))?;
+    }
+    open_log(path)
+}
+
+#[cfg(unix)]
+fn rotate_on_sighup() {
+    extern "C" fn handler(_: libc::c_int) {
+        ROTATE_REQUESTED.store(true, Ordering::Relaxed);
+    }
+    // Safety: the handler only stores to an atomic, which is allowed in a
+    // signal handler.
+    let handler: extern "C" fn(libc::c_int) = handler;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn rotate_on_sighup() {}
+
+/// Formats a time as RFC 3339, in UTC, to the millisecond.
+struct Timestamp(SystemTime);
+
+impl Display for Timestamp {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        let since = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs();
+        let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+        // Howard Hinnant's civil_from_days.
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        write!(fmt, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+               year, month, day, secs / 3600, secs / 60 % 60, secs % 60,
+               since.subsec_millis())
+    }
+}
+
+fn format_line(format: Format, time: SystemTime, level: Level, module: &str,
+               context: Option<&ConnectionContext>, message: fmt::Arguments)
+               -> String {
+    let nick = context.and_then(|x| x.nick());
+    match format {
+        Format::Text => {
+            let mut ret = format!("{} {:5} {}", Timestamp(time),
+                                  level.name().to_ascii_uppercase(), module);
+            if let Some(context) = context {
+                ret.push_str(&format!(" [#{}", context.id));
+                if let Some(peer) = context.peer {
+                    ret.push_str(&format!(" {}", peer));
+                }
+                if let Some(nick) = nick {
+                    ret.push_str(&format!(" {}", nick));
+                }
+                ret.push(']');
+            }
+            ret.push_str(&format!(": {}", message));
+            ret
+        },
+        Format::JsonLines => {
+            let mut line = serde_json::Map::new();
+            line.insert("time".to_owned(), Timestamp(time).to_string().into());
+            line.insert("level".to_owned(), level.name().into());
+            line.insert("module".to_owned(), module.into());
+            if let Some(context) = context {
+                line.insert("conn".to_owned(), context.id.into());
+                if let Some(peer) = context.peer {
+                    line.insert("peer".to_owned(), peer.to_string().into());
+                }
+                if let Some(nick) = nick {
+                    line.insert("nick".to_owned(), nick.into());
+                }
+            }
+            line.insert("message".to_owned(), message.to_string().into());
+            Value::Object(line).to_string()
+        },
+    }
+}
+
+/// Logs a line at the given level, if anyone wants it.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $module:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, $module) {
+            $crate::logging::write($level, $module, format_args!($($arg)+))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($module:expr, $($arg:tt)+)
+        => { log!($crate::logging::Level::Error, $module, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($module:expr, $($arg:tt)+)
+        => { log!($crate::logging::Level::Warn, $module, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($module:expr, $($arg:tt)+)
+        => { log!($crate::logging::Level::Info, $module, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($module:expr, $($arg:tt)+)
+        => { log!($crate::logging::Level::Debug, $module, $($arg)+) };
+}
+
+#[allow(unused_macros)]
+#[macro_export]
+macro_rules! trace {
+    ($module:expr, $($arg:tt)+)
+        => { log!($crate::logging::Level::Trace, $module, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    #[test]
+    fn filters() {
+        let filter = Filter::parse("warn,db=debug,conn=info").unwrap();
+        assert_eq!(filter.level_for("db"), Level::Debug);
+        assert_eq!(filter.level_for("conn"), Level::Info);
+        assert_eq!(filter.level_for("server"), Level::Warn);
+        assert_eq!(filter.max_level(), Level::Debug);
+        assert_eq!(Filter::parse("").unwrap(), Filter::default());
+        assert_eq!(Filter::parse("DB=TRACE").unwrap().level_for("DB"),
+                   Level::Trace);
+        assert!(Filter::parse("db=loud").is_err());
+        assert!(Filter::parse("chatty").is_err());
+    }
+    #[test]
+    fn lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_603_000_000_123);
+        assert_eq!(Timestamp(time).to_string(), "2020-10-18T05:46:40.123Z");
+        assert_eq!(Timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400))
+                   .to_string(), "2000-02-29T00:00:00.000Z");
+        assert_eq!(format_line(Format::Text, time, Level::Warn, "db", None,
+                               format_args!("disk {}", "full")),
+                   "2020-10-18T05:46:40.123Z WARN  db: disk full");
+        let context = ConnectionContext {
+            id: 7,
+            peer: Some("192.0.2.1:5555".parse().unwrap()),
+            nick: Mutex::new(None),
+        };
+        assert_eq!(format_line(Format::Text, time, Level::Info, "conn",
+                               Some(&context), format_args!("hello")),
+                   "2020-10-18T05:46:40.123Z INFO  conn [#7 192.0.2.1:5555]: \
+                    hello");
+        context.set_nick(Some("fox".to_owned()));
+        let line: Value = serde_json::from_str(&format_line(
+            Format::JsonLines, time, Level::Error, "conn", Some(&context),
+            format_args!("oops \"{}\"", 1))).unwrap();
+        assert_eq!(line, serde_json::json!({
+            "time": "2020-10-18T05:46:40.123Z", "level": "error",
+            "module": "conn", "conn": 7, "peer": "192.0.2.1:5555",
+            "nick": "fox", "message": "oops \"1\"",
+        }));
+    }
+    #[tokio::test]
+    async fn context() {
+        assert!(current_context().is_none());
+        let context = ConnectionContext::new(None);
+        let id = context.id;
+        assert_eq!(scope(context, async {
+            current_context().map(|x| x.id)
+        }).await, Some(id));
+        assert_ne!(ConnectionContext::new(None).id, id);
+    }
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir()
+            .join(format!("foxy-ircd-test-{}-rotation", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foxy.log");
+        std::fs::write(&path, b"oldest\n").unwrap();
+        for n in 0 .. ROTATED_FILES + 2 {
+            let mut file = rotate(&path).unwrap();
+            writeln!(file, "{}", n).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "6\n");
+        assert_eq!(std::fs::read_to_string(dir.join("foxy.log.1")).unwrap(),
+                   "5\n");
+        assert_eq!(std::fs::read_to_string(dir.join("foxy.log.5")).unwrap(),
+                   "1\n");
+        assert!(!dir.join("foxy.log.6").exists());
+        // someone else moved it away
+        std::fs::rename(&path, dir.join("elsewhere")).unwrap();
+        rotate(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(dir.join("foxy.log.1")).unwrap(),
+                   "5\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
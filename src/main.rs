@@ -15,22 +15,18 @@
  * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
  */
 
-pub mod message;
-pub use message::Message;
-pub mod db;
-pub use db::*;
-pub mod case;
-pub use case::*;
-pub mod invocation;
-pub use invocation::*;
-pub mod connection;
-pub use connection::*;
+use foxy_ircd::*;
 
 fn main() {
-    let Invocation { mut runtime }
-    = match get_invocation(|x| println!("{}", x.peer_addr().unwrap())) {
-        Some(x) => x,
-        None => std::process::exit(1),
+    let Invocation { mut runtime, .. }
+    = match get_invocation(|x, _, _| {
+        let context = logging::ConnectionContext::new(x.peer_addr().ok());
+        tokio::spawn(logging::scope(context, async move {
+            info!("conn", "Connection accepted");
+        }));
+    }) {
+        Ok(x) => x,
+        Err(status) => std::process::exit(status),
     };
     let (mut send_quit, mut recv_quit) = tokio::sync::mpsc::channel(1);
     ctrlc::set_handler(move || {
@@ -39,7 +35,7 @@ fn main() {
     let reason = runtime.block_on(async {
         recv_quit.recv().await.unwrap()
     });
-    eprintln!("\nShutting down server due to {}.", reason);
+    info!("server", "Shutting down server due to {}.", reason);
     // Try to be patient and let ongoing tasks finish, but don't block for more
     // than 15 seconds.
     runtime.shutdown_timeout(std::time::Duration::new(15, 0));
@@ -0,0 +1,379 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Hostmask matching, for bans, exceptions, K-lines, oper host restrictions
+//! and the like. A mask is `nick!user@host`, where each part is a glob (`*`
+//! matches any run of characters, `?` matches any one character, and a
+//! backslash makes either of those literal). The host part may instead be a
+//! CIDR block, such as `192.0.2.0/24` or `2001:db8::/32`, which is matched
+//! against the client's real IP address.
+//!
+//! Masks are compiled once, when set, so that matching them against every
+//! joining client is cheap.
+//!
+//! Ban, exception and invite lists hold `BanMask`s, which may also be
+//! extbans; see the `extban` module.
+
+use std::net::IpAddr;
+
+use crate::*;
+use crate::message::is_nulcrlfspace;
+
+mod extban;
+pub use extban::*;
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum GlobToken {
+    /// A literal byte, already folded.
+    Literal(u8),
+    /// `?`
+    AnyOne,
+    /// `*`
+    AnyMany,
+}
+
+/// A compiled glob pattern. Matching is case-insensitive, according to a
+/// `CaseMapping`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Glob {
+    tokens: Vec<GlobToken>,
+    mapping: CaseMapping,
+}
+
+impl Glob {
+    /// Compile a glob pattern.
+    pub fn compile(pattern: &[u8], mapping: CaseMapping) -> Glob {
+        let mut tokens = Vec::with_capacity(pattern.len());
+        // Literals are folded a run at a time, since `Precis` can't fold a
+        // byte at a time. This also keeps `Rfc1459` from turning an escaping
+        // backslash into a `|`.
+        let mut run = Vec::new();
+        let flush = |run: &mut Vec<u8>, tokens: &mut Vec<GlobToken>| {
+            tokens.extend(mapping.fold(run).iter()
+                          .map(|x| GlobToken::Literal(*x)));
+            run.clear();
+        };
+        let mut n = 0;
+        while n < pattern.len() {
+            let token = match pattern[n] {
+                b'\\' if matches!(pattern.get(n+1), Some(b'*' | b'?' | b'\\'))
+                => {
+                    n += 1;
+                    run.push(pattern[n]);
+                    None
+                },
+                b'*' => Some(GlobToken::AnyMany),
+                b'?' => Some(GlobToken::AnyOne),
+                x => { run.push(x); None },
+            };
+            if let Some(token) = token {
+                flush(&mut run, &mut tokens);
+                // `**` is the same as `*`, and cheaper to match as one
+                if token != GlobToken::AnyMany
+                || tokens.last() != Some(&GlobToken::AnyMany) {
+                    tokens.push(token);
+                }
+            }
+            n += 1;
+        }
+        flush(&mut run, &mut tokens);
+        Glob { tokens, mapping }
+    }
+    /// Returns true if this glob matches everything.
+    pub fn is_universal(&self) -> bool {
+        self.tokens == [GlobToken::AnyMany]
+    }
+    /// Returns true if every literal byte in this glob is one of `bytes`,
+    /// i.e. if the glob may match text made only of them.
+    pub fn literals_within(&self, bytes: &[u8]) -> bool {
+        self.tokens.iter().all(|x| match x {
+            GlobToken::Literal(x) => bytes.contains(x),
+            _ => true,
+        })
+    }
+    /// Returns true if this glob matches `text`.
+    pub fn matches(&self, text: &[u8]) -> bool {
+        let text = self.mapping.fold(text);
+        let text = &text[..];
+        // `?` matches one character, which in UTF-8 may be several bytes.
+        let utf8 = self.mapping == CaseMapping::Precis;
+        // The classic backtracking matcher. Since `*`s are collapsed, it only
+        // ever has to remember the most recent one.
+        let tokens = &self.tokens[..];
+        let (mut t, mut p) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+        while t < text.len() {
+            match tokens.get(p) {
+                Some(GlobToken::AnyMany) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue
+                },
+                Some(GlobToken::AnyOne) => {
+                    p += 1;
+                    t += 1;
+                    while utf8 && t < text.len() && text[t] & 0xC0 == 0x80 {
+                        t += 1;
+                    }
+                    continue
+                },
+                Some(GlobToken::Literal(x)) if *x == text[t] => {
+                    p += 1; t += 1; continue
+                },
+                _ => (),
+            }
+            match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                },
+                None => return false,
+            }
+        }
+        tokens[p..].iter().all(|x| *x == GlobToken::AnyMany)
+    }
+}
+
+/// The host part of a compiled mask.
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum HostPattern {
+    /// `may_be_ip` is false if the glob can't match the textual form of
+    /// an IP address, so that it needn't be rendered.
+    Glob { glob: Glob, may_be_ip: bool },
+    Cidr(IpAddr, u8),
+}
+
+/// Parse `addr/bits` as a CIDR block.
+fn parse_cidr(text: &[u8]) -> Option<(IpAddr, u8)> {
+    let text = std::str::from_utf8(text).ok()?;
+    let mut split = text.splitn(2, '/');
+    let addr: IpAddr = split.next()?.parse().ok()?;
+    let bits: u8 = split.next()?.parse().ok()?;
+    let max = match addr { IpAddr::V4(_) => 32, IpAddr::V6(_) => 128 };
+    if bits > max { None } else { Some((addr, bits)) }
+}
+
+/// IPv4 addresses reach us as IPv4-mapped IPv6 addresses on IPv6 listeners.
+/// Turns those back into IPv4, so masks treat them the same either way.
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        x => x,
+    }
+}
+
+/// Does `ip` lie within the CIDR block `net/bits`?
+fn cidr_contains(net: IpAddr, bits: u8, ip: IpAddr) -> bool {
+    match (net, unmap(ip)) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        },
+        _ => false,
+    }
+}
+
+/// A compiled `nick!user@host` mask.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Mask {
+    text: Vec<u8>,
+    nick: Glob,
+    user: Glob,
+    host: HostPattern,
+}
+
+impl Mask {
+    /// Turn a partial mask into a full `nick!user@host` one:
+    ///
+    /// - `nick` becomes `nick!*@*`
+    /// - `nick!user` becomes `nick!user@*`
+    /// - `user@host` becomes `*!user@host`
+    /// - `host.name` (anything with a `.` or `:`) becomes `*!*@host.name`
+    ///
+    /// Empty parts become `*`.
+    pub fn normalize(mask: &[u8]) -> Vec<u8> {
+        let bang = mask.iter().position(|x| *x == b'!');
+        let at = mask.iter().rposition(|x| *x == b'@');
+        let (nick, user, host) = match (bang, at) {
+            (Some(bang), Some(at)) if bang < at
+                => (&mask[..bang], &mask[bang+1..at], &mask[at+1..]),
+            (Some(bang), _)
+                => (&mask[..bang], &mask[bang+1..], &b"*"[..]),
+            (None, Some(at)) => (&b"*"[..], &mask[..at], &mask[at+1..]),
+            (None, None) if mask.iter().any(|x| *x == b'.' || *x == b':')
+                => (&b"*"[..], &b"*"[..], mask),
+            (None, None) => (mask, &b"*"[..], &b"*"[..]),
+        };
+        let or_star = |x: &'_ [u8]| if x.is_empty() { b"*".to_vec() }
+                                    else { x.to_vec() };
+        let mut ret = or_star(nick);
+        ret.push(b'!');
+        ret.extend_from_slice(&or_star(user));
+        ret.push(b'@');
+        ret.extend_from_slice(&or_star(host));
+        ret
+    }
+    /// Normalize and compile a mask.
+    pub fn parse(mask: &[u8], mapping: CaseMapping)
+                 -> Result<Mask, &'static str> {
+        if mask.iter().any(|x| is_nulcrlfspace(*x)) {
+            return Err("invalid character in mask")
+        }
+        let text = Mask::normalize(mask);
+        let bang = text.iter().position(|x| *x == b'!').unwrap();
+        let at = text.iter().rposition(|x| *x == b'@').unwrap();
+        let host = &text[at+1..];
+        let host = match parse_cidr(host) {
+            Some((addr, bits)) => HostPattern::Cidr(addr, bits),
+            None => {
+                let glob = Glob::compile(host, CaseMapping::Ascii);
+                let may_be_ip = glob.literals_within(b"0123456789abcdef.:");
+                HostPattern::Glob { glob, may_be_ip }
+            },
+        };
+        Ok(Mask {
+            nick: Glob::compile(&text[..bang], mapping),
+            user: Glob::compile(&text[bang+1..at], mapping),
+            host,
+            text,
+        })
+    }
+    /// The normalized form of this mask, as it should be shown in ban lists
+    /// and compared for duplicates.
+    pub fn as_bytes(&self) -> &[u8] { &self.text[..] }
+    /// Does this mask match a client? `ip` is the client's real address, if
+    /// known. A glob host part is tried against both the visible host and the
+    /// textual form of `ip`; a CIDR host part only against `ip`.
+    pub fn matches(&self, source: &Source, ip: Option<IpAddr>) -> bool {
+        let (nick, user, host) = match *source {
+            Source::Client { nick, user, host } => (nick, user, host),
+            Source::Server { .. } => return false,
+        };
+        if !self.nick.matches(nick)
+        || !self.user.matches(user.unwrap_or(b"")) {
+            return false
+        }
+        match &self.host {
+            HostPattern::Glob { glob, may_be_ip } => glob.matches(host)
+                || (*may_be_ip && ip.map(|ip| {
+                    glob.matches(unmap(ip).to_string().as_bytes())
+                }).unwrap_or(false)),
+            HostPattern::Cidr(net, bits) => ip
+                .map(|ip| cidr_contains(*net, *bits, ip))
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn globs() {
+        let cases: &[(&[u8], &[u8], bool)] = &[
+            (b"*", b"", true),
+            (b"*", b"anything", true),
+            (b"a*c", b"abbbc", true),
+            (b"a*c", b"abbbd", false),
+            (b"a?c", b"abc", true),
+            (b"a?c", b"ac", false),
+            (b"*.example.com", b"foo.EXAMPLE.com", true),
+            (b"*.example.com", b"example.com", false),
+            (b"a\\*c", b"a*c", true),
+            (b"a\\*c", b"abc", false),
+            (b"a\\?", b"a?", true),
+            (b"a\\\\b", b"a\\b", true),
+            (b"*a*a*a*", b"banana", true),
+            (b"*a*a*a*a*", b"banana", false),
+            (b"fo\\o", b"fo\\o", true),
+        ];
+        for &(pattern, text, expected) in cases {
+            assert_eq!(Glob::compile(pattern, CaseMapping::Ascii)
+                       .matches(text), expected,
+                       "{:?} vs {:?}", String::from_utf8_lossy(pattern),
+                       String::from_utf8_lossy(text));
+        }
+        assert!(Glob::compile(b"***", CaseMapping::Ascii).is_universal());
+        let glob = Glob::compile(b"fox[\\?]", CaseMapping::Rfc1459);
+        assert!(glob.matches(b"FOX{?}"));
+        assert!(!glob.matches(b"FOX{a}"));
+        let glob = Glob::compile("f?x*".as_bytes(), CaseMapping::Precis);
+        assert!(glob.matches("FÖX".as_bytes()));
+    }
+    #[test]
+    fn normalize() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"foo", b"foo!*@*"),
+            (b"foo!bar", b"foo!bar@*"),
+            (b"bar@baz", b"*!bar@baz"),
+            (b"baz.example.com", b"*!*@baz.example.com"),
+            (b"2001:db8::/32", b"*!*@2001:db8::/32"),
+            (b"a!b@c", b"a!b@c"),
+            (b"!@", b"*!*@*"),
+        ];
+        for &(mask, expected) in cases {
+            assert_eq!(Mask::normalize(mask), expected);
+        }
+    }
+    #[test]
+    fn masks() {
+        let client = Source::Client { nick: b"Fox", user: Some(b"~fox"),
+                                      host: b"den.example.com" };
+        let v4: IpAddr = "192.0.2.77".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let cases: &[(&[u8], Option<IpAddr>, bool)] = &[
+            (b"fox", None, true),
+            (b"wolf", None, false),
+            (b"*!~fox@*.example.com", None, true),
+            (b"*!fox@*", None, false),
+            (b"*@192.0.2.0/24", Some(v4), true),
+            (b"*@192.0.2.0/24", Some(mapped), true),
+            (b"*@192.0.3.0/24", Some(v4), false),
+            (b"*@192.0.2.0/24", None, false),
+            (b"*@0.0.0.0/0", Some(v4), true),
+            (b"*@2001:db8::/32", Some(v6), true),
+            (b"*@2001:db8::/32", Some(v4), false),
+            (b"*@192.0.2.*", Some(v4), true),
+            (b"*@192.0.2.*", Some(mapped), true),
+            (b"*@2001:DB8:*", Some(v6), true),
+            (b"*@*.example.org", Some(v4), false),
+        ];
+        for &(mask, ip, expected) in cases {
+            assert_eq!(Mask::parse(mask, CaseMapping::Rfc1459).unwrap()
+                       .matches(&client, ip),
+                       expected, "{:?}", String::from_utf8_lossy(mask));
+        }
+        assert!(!Mask::parse(b"*", CaseMapping::Ascii).unwrap()
+                .matches(&Source::Server { name: b"irc.example.com" }, None));
+        assert!(Mask::parse(b"bad mask", CaseMapping::Ascii).is_err());
+        let may_be_ip = |mask: &[u8]| {
+            match Mask::parse(mask, CaseMapping::Ascii).unwrap().host {
+                HostPattern::Glob { may_be_ip, .. } => may_be_ip,
+                HostPattern::Cidr(..) => panic!("not a glob"),
+            }
+        };
+        assert!(may_be_ip(b"*@192.0.2.?"));
+        assert!(may_be_ip(b"*@*"));
+        assert!(!may_be_ip(b"*@*.example.com"));
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Extended bans, or extbans: list mode entries that match something other
+//! than a hostmask. An extban is `$`, then optionally `~` to invert it, then
+//! a type letter, then (for most types) `:` and an argument.
+//!
+//! - `$a` matches clients logged in to any account, and `$a:GLOB` those
+//!   logged in to an account whose name matches `GLOB`.
+//! - `$r:GLOB` matches clients whose real name matches `GLOB`.
+//! - `$z` matches clients connected with TLS.
+//! - `$j:#chan` matches members of `#chan`.
+//! - `$m:MASK` is a mute. It matches whatever `MASK` (a hostmask or another
+//!   extban) does, but in a ban list it only stops clients from speaking,
+//!   not from joining.
+
+use super::*;
+
+/// What every extban starts with.
+pub const EXTBAN_PREFIX: u8 = b'$';
+/// The extban types we support.
+pub const EXTBAN_TYPES: &[u8] = b"ajmrz";
+
+/// Add the `EXTBAN` token to `RPL_ISUPPORT`.
+pub fn advertise(isupport: &mut ISupport) {
+    let mut value = vec![EXTBAN_PREFIX, b','];
+    value.extend_from_slice(EXTBAN_TYPES);
+    isupport.set("EXTBAN", Some(&value));
+}
+
+/// What a `BanMask` can be matched against: a client, and whatever else
+/// extbans need to know about it.
+pub trait BanTarget {
+    /// The client's `nick!user@host`.
+    fn source(&self) -> Source<'_>;
+    /// The client's real address, if known.
+    fn ip(&self) -> Option<IpAddr>;
+    /// The account the client is logged in to, if any.
+    fn account(&self) -> Option<&[u8]>;
+    fn realname(&self) -> &[u8];
+    fn is_tls(&self) -> bool;
+    /// Is the client a member of this channel?
+    fn is_in_channel(&self, channel: &[u8]) -> bool;
+}
+
+/// What an extban matches.
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum Criterion {
+    /// `$a` or `$a:GLOB`
+    Account(Option<Glob>),
+    /// `$r:GLOB`
+    Realname(Glob),
+    /// `$z`
+    Tls,
+    /// `$j:#chan`
+    Channel(Vec<u8>),
+    /// `$m:MASK`
+    Mute(Box<BanMask>),
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum BanKind {
+    Host(Mask),
+    Ext { inverted: bool, criterion: Criterion },
+}
+
+/// An entry in a ban, exception or invite list: a hostmask, or an extban.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct BanMask {
+    text: Vec<u8>,
+    kind: BanKind,
+}
+
+impl BanMask {
+    /// Parse a hostmask (normalizing it, as `Mask::parse` does) or an
+    /// extban.
+    pub fn parse(text: &[u8], mapping: CaseMapping)
+                 -> Result<BanMask, &'static str> {
+        if text.first() != Some(&EXTBAN_PREFIX) {
+            let mask = Mask::parse(text, mapping)?;
+            return Ok(BanMask { text: mask.as_bytes().to_vec(),
+                                kind: BanKind::Host(mask) })
+        }
+        if text.iter().any(|x| is_nulcrlfspace(*x)) {
+            return Err("invalid character in mask")
+        }
+        let mut rest = &text[1..];
+        let inverted = rest.first() == Some(&b'~');
+        if inverted { rest = &rest[1..] }
+        let (letter, arg) = match rest.split_first() {
+            Some((letter, [])) => (*letter, None),
+            Some((letter, [b':', arg @ ..])) => (*letter, Some(arg)),
+            _ => return Err("malformed extban"),
+        };
+        let mut text = text.to_vec();
+        let criterion = match (letter, arg) {
+            (b'a', None) => Criterion::Account(None),
+            (b'a', Some(glob)) => {
+                Criterion::Account(Some(Glob::compile(glob, mapping)))
+            },
+            (b'r', Some(glob)) => {
+                Criterion::Realname(Glob::compile(glob, mapping))
+            },
+            (b'z', None) => Criterion::Tls,
+            (b'j', Some(channel)) => {
+                names::prepare_channel(channel, mapping)?;
+                Criterion::Channel(channel.to_vec())
+            },
+            (b'm', Some(mask)) if !inverted => {
+                let mask = BanMask::parse(mask, mapping)?;
+                if mask.is_mute() { return Err("a mute can't be muted") }
+                text = b"$m:".to_vec();
+                text.extend_from_slice(mask.as_bytes());
+                Criterion::Mute(Box::new(mask))
+            },
+            _ if !EXTBAN_TYPES.contains(&letter) => {
+                return Err("unknown extban type")
+            },
+            _ => return Err("malformed extban"),
+        };
+        Ok(BanMask { text, kind: BanKind::Ext { inverted, criterion } })
+    }
+    /// The mask as it should be shown in lists and compared for duplicates.
+    pub fn as_bytes(&self) -> &[u8] { &self.text }
+    /// Is this a mute (`$m:`)?
+    pub fn is_mute(&self) -> bool {
+        matches!(self.kind, BanKind::Ext { criterion: Criterion::Mute(_),
+                                           .. })
+    }
+    /// Does this match the client?
+    pub fn matches(&self, target: &dyn BanTarget) -> bool {
+        let (inverted, criterion) = match &self.kind {
+            BanKind::Host(mask) => {
+                return mask.matches(&target.source(), target.ip())
+            },
+            BanKind::Ext { inverted, criterion } => (*inverted, criterion),
+        };
+        let matched = match criterion {
+            Criterion::Account(None) => target.account().is_some(),
+            Criterion::Account(Some(glob)) => {
+                target.account().map(|x| glob.matches(x)).unwrap_or(false)
+            },
+            Criterion::Realname(glob) => glob.matches(target.realname()),
+            Criterion::Tls => target.is_tls(),
+            Criterion::Channel(channel) => target.is_in_channel(channel),
+            Criterion::Mute(mask) => mask.matches(target),
+        };
+        matched != inverted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    struct Client {
+        account: Option<&'static [u8]>,
+        tls: bool,
+    }
+    impl BanTarget for Client {
+        fn source(&self) -> Source<'_> {
+            Source::Client { nick: b"Fox", user: Some(b"~fox"),
+                             host: b"den.example.com" }
+        }
+        fn ip(&self) -> Option<IpAddr> { None }
+        fn account(&self) -> Option<&[u8]> { self.account }
+        fn realname(&self) -> &[u8] { b"Fennec Fox" }
+        fn is_tls(&self) -> bool { self.tls }
+        fn is_in_channel(&self, channel: &[u8]) -> bool {
+            channel.eq_ignore_ascii_case(b"#den")
+        }
+    }
+    #[test]
+    fn extbans() {
+        let guest = Client { account: None, tls: false };
+        let member = Client { account: Some(b"Foxxo"), tls: true };
+        let cases: &[(&[u8], bool, bool)] = &[
+            (b"fox", true, true),
+            (b"$a", false, true),
+            (b"$~a", true, false),
+            (b"$a:fox*", false, true),
+            (b"$a:wolf", false, false),
+            (b"$r:*fox", true, true),
+            (b"$r:wolf*", false, false),
+            (b"$z", false, true),
+            (b"$j:#DEN", true, true),
+            (b"$~j:#den", false, false),
+            (b"$j:#lair", false, false),
+            (b"$m:$~a", true, false),
+            (b"$m:*!*@*.example.com", true, true),
+        ];
+        for &(text, for_guest, for_member) in cases {
+            let mask = BanMask::parse(text, CaseMapping::Rfc1459).unwrap();
+            assert_eq!((mask.matches(&guest), mask.matches(&member)),
+                       (for_guest, for_member),
+                       "{:?}", String::from_utf8_lossy(text));
+            assert_eq!(mask.is_mute(), text.starts_with(b"$m"));
+        }
+        assert_eq!(BanMask::parse(b"fox", CaseMapping::Ascii).unwrap()
+                   .as_bytes(), b"fox!*@*");
+        assert_eq!(BanMask::parse(b"$m:fox", CaseMapping::Ascii).unwrap()
+                   .as_bytes(), b"$m:fox!*@*");
+        for bad in [&b"$r"[..], b"$z:x", b"$x", b"$j:lair", b"$m:$m:fox",
+                    b"$~m:fox", b"$", b"$a:a b"].iter() {
+            assert!(BanMask::parse(bad, CaseMapping::Ascii).is_err(),
+                    "{:?}", String::from_utf8_lossy(bad));
+        }
+    }
+}
@@ -25,7 +25,28 @@ use arrayref::array_ref;
 use crate::*;
 
 mod parse;
+pub(crate) use parse::{is_nulcrlfspace, validate_param};
 use parse::*;
+mod split;
+pub use split::MAX_MESSAGE_LEN;
+mod borrowed;
+pub use borrowed::MessageRef;
+
+/// How forgiving `Message::parse` should be of malformed input.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ParseMode {
+    /// Accept only messages that follow the grammar to the letter. Useful for
+    /// conformance testing.
+    Strict,
+    /// Accept the deviations that real-world clients commonly produce: runs
+    /// of spaces between components, trailing whitespace, a lone LF as a line
+    /// terminator, lowercase command names, and more than 15 parameters.
+    Lenient,
+}
+
+/// The most parameters a message may have, according to RFC 1459. Only
+/// enforced in `Strict` mode.
+const MAX_STRICT_PARAMS: usize = 15;
 
 /// Copy some bytes into a buffer, and return the `Range` occupied.
 ///
@@ -44,13 +65,35 @@ fn extract_bytes<'a>(buf: &'a [u8], range: &Range<u32>) -> &'a [u8] {
     &buf[range.start as usize .. range.end as usize]
 }
 
+/// Find the `Range` that `part`, which must have been borrowed from `line`,
+/// occupies within `line`.
+fn range_within(line: &[u8], part: &[u8]) -> Range<u32> {
+    let start = part.as_ptr() as usize - line.as_ptr() as usize;
+    debug_assert!(start + part.len() <= line.len());
+    start as u32 .. (start + part.len()) as u32
+}
+
 /// The internal version of `Source`. Refers to its data by `Range`.
+#[derive(Clone)]
 enum IntSource {
     Server { name: Range<u32> },
     Client { nick: Range<u32>, user: Option<Range<u32>>, host: Range<u32> },
 }
 
 impl IntSource {
+    /// Find where a `Source` borrowed from `line` lies within it.
+    fn locate(source: &Source, line: &[u8]) -> IntSource {
+        match *source {
+            Source::Server { name } => IntSource::Server {
+                name: range_within(line, name),
+            },
+            Source::Client { nick, user, host } => IntSource::Client {
+                nick: range_within(line, nick),
+                user: user.map(|x| range_within(line, x)),
+                host: range_within(line, host),
+            },
+        }
+    }
     /// Borrow this `IntSource` for outside use.
     fn extract<'a>(&self, buf: &'a[u8]) -> Source<'a> {
         match self {
@@ -79,11 +122,11 @@ impl<'a> Source<'a> {
     /// The number of bytes this Source would require to encode into a message,
     /// including leading colon and trailing space.
     fn raw_len(&self) -> usize {
-        match self {
-            &Source::Server { name } => name.len() + 2,
-            &Source::Client { nick, user: None, host }
+        match *self {
+            Source::Server { name } => name.len() + 2,
+            Source::Client { nick, user: None, host }
             => nick.len() + host.len() + 3,
-            &Source::Client { nick, user: Some(user), host }
+            Source::Client { nick, user: Some(user), host }
             => nick.len() + user.len() + host.len() + 4,
         }
     }
@@ -91,13 +134,13 @@ impl<'a> Source<'a> {
     /// equivalent.
     fn inter(&self, buf: &mut Vec<u8>) -> IntSource {
         buf.push(b':');
-        match self {
-            &Source::Server { name } => {
+        match *self {
+            Source::Server { name } => {
                 let name = inter_bytes(buf, name);
                 buf.push(b' ');
                 IntSource::Server { name }
             },
-            &Source::Client { nick, user, host } => {
+            Source::Client { nick, user, host } => {
                 let nick = inter_bytes(buf, nick);
                 let user = user.map(|user| {
                     buf.push(b'!');
@@ -144,11 +187,12 @@ impl<'a> Source<'a> {
     }
     /// Parse part of a raw message into a `Source`, or determine that it lacks
     /// a `Source`.
-    fn parse(line: &[u8]) -> Option<(Option<Source>, &[u8])> {
+    fn parse(line: &[u8], mode: ParseMode)
+             -> Option<(Option<Source<'_>>, &[u8])> {
         if line.is_empty() || line[0] != b':' { Some((None, line)) }
         else {
             let split = find_idx_of_space_or_end(line)?;
-            let rest = skip_leading_space(&line[split..])?;
+            let rest = skip_separator(&line[split..], mode)?;
             let (first, finale, line)
                 = parse_source_name_or_nick(&line[1..split])?;
             let (second, finale, line) = match finale {
@@ -199,16 +243,24 @@ impl<'a> Debug for Source<'a> {
 }
 
 /// The internal version of `Command`. Refers to its data by `Range`.
+#[derive(Clone)]
 enum IntCommand {
-    Numeric(u32, Range<u32>),
+    Numeric(u32),
     Textual(Range<u32>),
 }
 
 impl IntCommand {
+    /// Find where a `Command` borrowed from `line` lies within it.
+    fn locate(command: &Command, line: &[u8]) -> IntCommand {
+        match *command {
+            Command::Numeric(x) => IntCommand::Numeric(x),
+            Command::Textual(x) => IntCommand::Textual(range_within(line, x)),
+        }
+    }
     /// Borrow this `IntCommand` for outside use.
     fn extract<'a>(&self, buf: &'a[u8]) -> Command<'a> {
         match self {
-            IntCommand::Numeric(x, _) => Command::Numeric(*x),
+            IntCommand::Numeric(x) => Command::Numeric(*x),
             IntCommand::Textual(x) => Command::Textual(extract_bytes(buf, x)),
         }
     }
@@ -227,11 +279,11 @@ impl<'a> Command<'a> {
     /// Encodes this Source into a buffer. Intermediate step before `inter`
     /// can be called. Folds case and checks validity.
     fn bufferize(&self) -> Result<Vec<u8>, &'static str> {
-        match self {
-            &Command::Numeric(x) if x == 0 || x > 999
+        match *self {
+            Command::Numeric(x) if x == 0 || x > 999
                 => Err("Invalid command number"),
-            &Command::Numeric(x) => Ok(format!("{:03}",x).into_bytes()),
-            &Command::Textual(x) => Ok({
+            Command::Numeric(x) => Ok(format!("{:03}",x).into_bytes()),
+            Command::Textual(x) => Ok({
                 if x.iter().find(|x| is_nulcrlfspace(**x)).is_some() {
                     Err("invalid character in command name")?
                 }
@@ -248,28 +300,34 @@ impl<'a> Command<'a> {
     /// and bufferized into the provided buffer.
     fn inter(&self, me_buf: Vec<u8>, out_buf: &mut Vec<u8>) -> IntCommand {
         let range = inter_bytes(out_buf, &me_buf[..]);
-        match self {
-            &Command::Numeric(x) =>
-                IntCommand::Numeric(x, range),
-            &Command::Textual(_) =>
+        match *self {
+            Command::Numeric(x) =>
+                IntCommand::Numeric(x),
+            Command::Textual(_) =>
                 IntCommand::Textual(range),
         }
     }
     /// Parse part of a raw message into a `Command`.
-    fn parse(line: &[u8]) -> Option<(Command, &[u8])> {
+    ///
+    /// In `Strict` mode, the command must be three digits or consist only of
+    /// uppercase letters. In `Lenient` mode, anything goes; it will be folded
+    /// to uppercase later.
+    fn parse(line: &[u8], mode: ParseMode) -> Option<(Command<'_>, &[u8])> {
         if line.is_empty() { None }
         else {
             let split = find_idx_of_space_or_end(line)?;
-            let rest = skip_leading_space(&line[split..])?;
+            let rest = skip_separator(&line[split..], mode)?;
             let line = &line[..split];
+            if line.is_empty()
+            || (mode == ParseMode::Strict && !is_strict_command(line)) {
+                return None
+            }
             if line.len() == 3 {
                 let (a,b,c) = (parse_digit(line[0]),
                                parse_digit(line[1]),
                                parse_digit(line[2]));
-                match (a,b,c) {
-                    (Some(a), Some(b), Some(c)) =>
-                        return Some((Command::Numeric(a*100+b*10+c), rest)),
-                    _ => (),
+                if let (Some(a), Some(b), Some(c)) = (a,b,c) {
+                    return Some((Command::Numeric(a*100+b*10+c), rest))
                 }
             }
             Some((Command::Textual(line), rest))
@@ -291,8 +349,91 @@ impl<'a> Debug for Command<'a> {
     }
 }
 
+/// Where each component of a line lies within it. Produced by
+/// `Layout::parse`, which does all the validation `Message::assemble` would,
+/// except for folding the command's case.
+struct Layout {
+    source: Option<IntSource>,
+    command: IntCommand,
+    params: Range<u32>,
+    param_count: u32,
+    trailer: bool,
+}
+
+impl Layout {
+    fn parse(line: &[u8], mode: ParseMode) -> Option<Layout> {
+        let (_, rest) = parse_tags(line, mode)?; // TODO: tags? D:
+        let (source, mut rest) = Source::parse(rest, mode)?;
+        if let Some(source) = source.as_ref() { source.validate().ok()? }
+        if mode == ParseMode::Lenient {
+            rest = skip_leading_space(rest)?;
+            if !has_trailing_param(rest) {
+                rest = trim_trailing_whitespace(rest);
+            }
+        }
+        let (command, mut rest) = Command::parse(rest, mode)?;
+        if command == Command::Numeric(0) { return None }
+        let params = range_within(line, rest);
+        let mut param_count = 0;
+        let mut trailer = false;
+        while !rest.is_empty() {
+            param_count += 1;
+            if rest[0] == b':' {
+                validate_trailing_param(&rest[1..]).ok()?;
+                trailer = true;
+                break
+            }
+            let split = find_idx_of_space_or_end(rest)?;
+            rest = skip_separator(&rest[split..], mode)?;
+        }
+        if mode == ParseMode::Strict
+        && param_count as usize > MAX_STRICT_PARAMS {
+            return None
+        }
+        Some(Layout {
+            source: source.map(|x| IntSource::locate(&x, line)),
+            command: IntCommand::locate(&command, line),
+            params, param_count, trailer,
+        })
+    }
+    /// Returns an iterator over the parameters of `line`, which must be the
+    /// line this `Layout` was parsed from.
+    fn params<'a>(&self, line: &'a [u8]) -> Params<'a> {
+        Params { rest: extract_bytes(line, &self.params) }
+    }
+}
+
+/// Iterates over the parameters of an already-validated line.
+struct Params<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.rest.is_empty() { None }
+        else if self.rest[0] == b':' {
+            let ret = &self.rest[1..];
+            self.rest = &[];
+            Some(ret)
+        }
+        else {
+            let split = self.rest.iter().position(|x| *x == b' ')
+                .unwrap_or(self.rest.len());
+            let ret = &self.rest[..split];
+            self.rest = &self.rest[split..];
+            while self.rest.first() == Some(&b' ') {
+                self.rest = &self.rest[1..];
+            }
+            Some(ret)
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Message {
     buf: Vec<u8>,
+    tags: Option<Range<u32>>,
     source: Option<IntSource>,
     command: IntCommand,
     param_data_range: Range<u32>,
@@ -305,32 +446,61 @@ pub struct Message {
 /// to individual components of the message, or a slice to the message as it
 /// should be sent over the wire.
 impl Message {
+    /// Find the first complete line in `buf`. Returns `None` if there isn't
+    /// one yet. Otherwise, returns the line (terminator stripped) and the
+    /// number of bytes of `buf` it occupied (terminator included).
+    ///
+    /// In `Strict` mode, only `"\r\n"` terminates a line; a lone LF remains
+    /// part of the line, and `parse` will reject it. In `Lenient` mode, a lone
+    /// LF also terminates a line.
+    pub fn next_line(buf: &[u8], mode: ParseMode) -> Option<(&[u8], usize)> {
+        match mode {
+            ParseMode::Strict => {
+                let end = buf.windows(2).position(|x| x == b"\r\n")?;
+                Some((&buf[..end], end + 2))
+            },
+            ParseMode::Lenient => {
+                let end = buf.iter().position(|x| *x == b'\n')?;
+                let line = &buf[..end];
+                let line = match line.last() {
+                    Some(b'\r') => &line[..line.len()-1],
+                    _ => line,
+                };
+                Some((line, end + 1))
+            },
+        }
+    }
     /// Parse an input line into a `Message`. The line must have had its
     /// newline stripped, as well as its optional carriage return. The caller
     /// must detect and skip an empty message.
-    pub fn parse(line: &[u8]) -> Option<Message> {
-        let (_, line) = parse_tags(line)?; // TODO: tags? D:
-        let (source, line) = Source::parse(line)?;
-        let (command, mut line) = Command::parse(line)?;
-        let mut params = Vec::new();
-        let mut trailer = false;
-        while !line.is_empty() {
-            if line[0] == b':' {
-                params.push(&line[1..]);
-                trailer = true;
-                break
-            }
-            let split = find_idx_of_space_or_end(line)?;
-            params.push(&line[..split]);
-            line = skip_leading_space(&line[split..])?;
-        }
-        Some(Message::assemble(source.as_ref(), &command, &params[..], trailer)
-             .unwrap())
+    ///
+    /// If the message will only be looked at and then discarded,
+    /// `MessageRef::parse` avoids copying it.
+    pub fn parse(line: &[u8], mode: ParseMode) -> Option<Message> {
+        let layout = Layout::parse(line, mode)?;
+        let params: Vec<&[u8]> = layout.params(line).collect();
+        Message::assemble(layout.source.map(|x| x.extract(line)).as_ref(),
+                          &layout.command.extract(line), &params[..],
+                          layout.trailer).ok()
     }
     /// Makes a new `Message` from provided component parts.
     pub fn assemble(source: Option<&Source>, command: &Command,
                     params: &[&[u8]], trailer: bool)
                     -> Result<Message, &'static str> {
+        Message::assemble_tagged(None, source, command, params, trailer)
+    }
+    /// Makes a new `Message` from provided component parts, with message
+    /// tags. `tags` is everything between the `@` and the space, already
+    /// escaped, such as `bot;msgid=123`.
+    pub fn assemble_tagged(tags: Option<&[u8]>, source: Option<&Source>,
+                           command: &Command, params: &[&[u8]],
+                           trailer: bool)
+                           -> Result<Message, &'static str> {
+        if let Some(tags) = tags {
+            if tags.is_empty() || tags.iter().any(|x| is_nulcrlfspace(*x)) {
+                return Err("invalid message tags")
+            }
+        }
         // At runtime, if this assertion doesn't hold, our calculated message
         // length will be one byte too long. Since this costs at most 8 bytes,
         // and we're already wasting up to 7 bytes on a message that has no
@@ -341,9 +511,10 @@ impl Message {
         }
         let command_buf = command.bufferize()?;
         let message_len =
-            source.map(|x| x.raw_len()).unwrap_or(0)
+            tags.map(|x| x.len() + 2).unwrap_or(0)
+            + source.map(|x| x.raw_len()).unwrap_or(0)
             + command_buf.len()
-            + params.iter().map(|x| x.len() + 1).fold(0, |a,b| a+b)
+            + params.iter().map(|x| x.len() + 1).sum::<usize>()
             + if trailer { 3 } else { 2 };
         let param_base = (message_len + 7) & !7;
         let buf_len = param_base + params.len() * 8;
@@ -352,6 +523,12 @@ impl Message {
             Err(_) => panic!("Message over 4GiB long! Absurd!"),
         };
         let mut buf = Vec::with_capacity(buf_len);
+        let interred_tags = tags.map(|x| {
+            buf.push(b'@');
+            let range = inter_bytes(&mut buf, x);
+            buf.push(b' ');
+            range
+        });
         let interred_source = source.map(|x| x.inter(&mut buf));
         let interred_command = command.inter(command_buf, &mut buf);
         let mut interred_params = Vec::with_capacity(params.len());
@@ -377,7 +554,8 @@ impl Message {
         }
         assert_eq!(buf.len(), buf_len);
         Ok(Message {
-            buf: buf,
+            buf,
+            tags: interred_tags,
             source: interred_source,
             command: interred_command,
             param_data_range: param_base as u32 .. buf_len as u32,
@@ -390,12 +568,16 @@ impl Message {
     pub fn get_raw(&self) -> &[u8] {
         &self.buf[.. self.raw_message_len as usize]
     }
+    /// Returns the message tags the message was assembled with, if any.
+    pub fn get_tags(&self) -> Option<&[u8]> {
+        self.tags.as_ref().map(|x| extract_bytes(&self.buf[..], x))
+    }
     /// Returns the source (AKA prefix) specification of the message, if any.
-    pub fn get_source(&self) -> Option<Source> {
+    pub fn get_source(&self) -> Option<Source<'_>> {
         self.source.as_ref().map(|x| x.extract(&self.buf[..]))
     }
     /// Returns the command for this message.
-    pub fn get_command(&self) -> Command {
+    pub fn get_command(&self) -> Command<'_> {
         self.command.extract(&self.buf[..])
     }
     /// Returns the number of additional parameters in this message.
@@ -426,7 +608,7 @@ impl Hash for Message {
     fn hash<H: Hasher>(&self, h: &mut H) {
         // Only hashing this part of `buf` is required, since it fully
         // specifies the message.
-        (&self.buf[.. self.raw_message_len as usize]).hash(h);
+        self.buf[.. self.raw_message_len as usize].hash(h);
     }
 }
 
@@ -582,6 +764,134 @@ mod tests {
             trailer: false,
         },
     ];
+    /// Lines that only `Lenient` mode will accept, paired with what they
+    /// should parse into. `raw` here excludes the line terminator.
+    const LENIENT_ONLY: &[Test] = &[
+        Test {
+            name: "Double Spaces",
+            raw: b"PRIVMSG  #chan  :hello  there",
+            source: None,
+            command: Command::Textual(b"PRIVMSG"),
+            params: &[
+                b"#chan",
+                b"hello  there",
+            ],
+            trailer: true,
+        },
+        Test {
+            name: "Spaces After Source",
+            raw: b":nick!user@host   JOIN #chan",
+            source: Some(Source::Client { nick: b"nick",
+                                          user: Some(b"user"),
+                                          host: b"host" }),
+            command: Command::Textual(b"JOIN"),
+            params: &[
+                b"#chan",
+            ],
+            trailer: false,
+        },
+        Test {
+            name: "Trailing Whitespace",
+            raw: b"MODE #chan +o bob \t ",
+            source: None,
+            command: Command::Textual(b"MODE"),
+            params: &[
+                b"#chan",
+                b"+o",
+                b"bob",
+            ],
+            trailer: false,
+        },
+        Test {
+            name: "Lowercase Command",
+            raw: b"privmsg #chan :hi",
+            source: None,
+            command: Command::Textual(b"PRIVMSG"),
+            params: &[
+                b"#chan",
+                b"hi",
+            ],
+            trailer: true,
+        },
+        Test {
+            name: "Sixteen Params",
+            raw: b"FOO 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 :16",
+            source: None,
+            command: Command::Textual(b"FOO"),
+            params: &[
+                b"1", b"2", b"3", b"4", b"5", b"6", b"7", b"8", b"9", b"10",
+                b"11", b"12", b"13", b"14", b"15", b"16",
+            ],
+            trailer: true,
+        },
+    ];
+    /// Lines that neither mode will accept.
+    const UNPARSEABLE: &[&[u8]] = &[
+        b"",
+        b":source.only",
+        b"FOO :trailer with a \r in it",
+        b"FOO middle\nparam",
+        b"FOO bar\0baz",
+    ];
+    /// Compare a `Message` against a `Test`. If they disagree, dump both and
+    /// panic.
+    fn check(kind: &str, test: &Test, message: &Message) {
+        let mut problems =
+            (if test.source == message.get_source() { 0 }
+             else {
+                 eprintln!("Sources don't match!");
+                 1
+             }) +
+            (if test.command == message.get_command() { 0 }
+             else {
+                 eprintln!("Commands don't match!");
+                 1
+             }) +
+            (if test.trailer == message.has_trailer() { 0 }
+             else {
+                 eprintln!("has_trailer doesn't match!");
+                 1
+             });
+        if message.get_param_count() as usize != test.params.len() {
+            problems += 1;
+            eprintln!("Wrong number of params!");
+        }
+        else {
+            for n in 0 .. test.params.len() {
+                if message.get_nth_param(n as u32).unwrap() != test.params[n] {
+                    eprintln!("Wrong param!");
+                    problems += 1;
+                }
+            }
+            assert!(message.get_nth_param(message.get_param_count())
+                    .is_none());
+        }
+        if problems > 0 {
+            eprintln!("Expected:");
+            eprintln!("\traw: {:?}", String::from_utf8_lossy(test.raw));
+            eprintln!("\tsource: {:?}", test.source);
+            eprintln!("\tcommand: {:?}", test.command);
+            for n in 0..test.params.len() {
+                if n == (test.params.len()-1) && test.trailer {
+                    eprintln!("\t\t(trailer)");
+                }
+                eprintln!("\tparams[{}]: {:?}", n,
+                          String::from_utf8_lossy(test.params[n]));
+            }
+            eprintln!("Got:");
+            eprintln!("\traw: {:?}", String::from_utf8_lossy(message.get_raw()));
+            eprintln!("\tsource: {:?}", message.get_source());
+            eprintln!("\tcommand: {:?}", message.get_command());
+            for n in 0..message.get_param_count() {
+                if n == (message.get_param_count()-1) && message.has_trailer() {
+                    eprintln!("\t\t(trailer)");
+                }
+                eprintln!("\tparams[{}]: {:?}", n,
+                          String::from_utf8_lossy(message.get_nth_param(n).unwrap()));
+            }
+            panic!("{} test {:?} failed!", kind, test.name);
+        }
+    }
     #[test]
     pub fn assemble() {
         for test in TESTS {
@@ -670,65 +980,69 @@ mod tests {
         }
     }
     #[test]
+    pub fn tagged_assembly() {
+        let source = Source::Client { nick: b"Fox", user: None, host: b"den" };
+        let message = Message::assemble_tagged(
+            Some(b"bot"), Some(&source), &Command::Textual(b"PRIVMSG"),
+            &[b"#den", b"hi there"], true).unwrap();
+        assert_eq!(message.get_raw(),
+                   &b"@bot :Fox@den PRIVMSG #den :hi there\r\n"[..]);
+        assert_eq!(message.get_tags(), Some(&b"bot"[..]));
+        assert_eq!(message.get_source(), Some(source));
+        assert_eq!(message.get_nth_param(1), Some(&b"hi there"[..]));
+        assert_eq!(Message::assemble(None, &Command::Textual(b"PING"), &[],
+                                     false).unwrap().get_tags(), None);
+        for bad in [&b""[..], b"a b", b"a\r"].iter() {
+            assert!(Message::assemble_tagged(Some(bad), None,
+                                             &Command::Textual(b"PING"),
+                                             &[], false).is_err());
+        }
+    }
+    #[test]
     pub fn parse() {
-        for test in TESTS {
-            let message = Message::parse(&test.raw[..test.raw.len()-2])
-                .unwrap();
-            let mut problems =
-                (if test.source == message.get_source() { 0 }
-                 else {
-                     eprintln!("Sources don't match!");
-                     1
-                 }) +
-                (if test.command == message.get_command() { 0 }
-                 else {
-                     eprintln!("Commands don't match!");
-                     1
-                 }) +
-                (if test.trailer == message.has_trailer() { 0 }
-                 else {
-                     eprintln!("has_trailer doesn't match!");
-                     1
-                 });
-            if message.get_param_count() as usize != test.params.len() {
-                problems += 1;
-                eprintln!("Wrong number of params!");
+        for mode in &[ParseMode::Strict, ParseMode::Lenient] {
+            for test in TESTS {
+                let message = Message::parse(&test.raw[..test.raw.len()-2],
+                                             *mode).unwrap();
+                check("Parse", test, &message);
             }
-            else {
-                for n in 0 .. test.params.len() {
-                    if message.get_nth_param(n as u32).unwrap() != test.params[n] {
-                        eprintln!("Wrong param!");
-                        problems += 1;
-                    }
-                }
-                assert!(message.get_nth_param(message.get_param_count())
-                        .is_none());
+        }
+    }
+    #[test]
+    pub fn lenient_parse() {
+        for test in LENIENT_ONLY {
+            if Message::parse(test.raw, ParseMode::Strict).is_some() {
+                panic!("Strict mode accepted {:?}!", test.name);
             }
-            if problems > 0 {
-                eprintln!("Expected:");
-                eprintln!("\traw: {:?}", String::from_utf8_lossy(test.raw));
-                eprintln!("\tsource: {:?}", test.source);
-                eprintln!("\tcommand: {:?}", test.command);
-                for n in 0..test.params.len() {
-                    if n == (test.params.len()-1) && test.trailer {
-                        eprintln!("\t\t(trailer)");
-                    }
-                    eprintln!("\tparams[{}]: {:?}", n,
-                              String::from_utf8_lossy(test.params[n]));
-                }
-                eprintln!("Got:");
-                eprintln!("\traw: {:?}", String::from_utf8_lossy(message.get_raw()));
-                eprintln!("\tsource: {:?}", message.get_source());
-                eprintln!("\tcommand: {:?}", message.get_command());
-                for n in 0..message.get_param_count() {
-                    if n == (message.get_param_count()-1) && message.has_trailer() {
-                        eprintln!("\t\t(trailer)");
-                    }
-                    eprintln!("\tparams[{}]: {:?}", n,
-                              String::from_utf8_lossy(message.get_nth_param(n).unwrap()));
-                }
-                panic!("Parse test {:?} failed!", test.name);
+            let message = Message::parse(test.raw, ParseMode::Lenient)
+                .unwrap_or_else(|| panic!("Lenient mode rejected {:?}!",
+                                          test.name));
+            check("Lenient parse", test, &message);
+        }
+    }
+    #[test]
+    pub fn unparseable() {
+        for mode in &[ParseMode::Strict, ParseMode::Lenient] {
+            for raw in UNPARSEABLE {
+                assert!(Message::parse(raw, *mode).is_none(),
+                        "{:?} parsed {:?}", mode,
+                        String::from_utf8_lossy(raw));
             }
         }
     }
+    #[test]
+    pub fn next_line() {
+        let buf = b"PING a\r\nPING b\nPING c";
+        assert_eq!(Message::next_line(buf, ParseMode::Lenient),
+                   Some((&b"PING a"[..], 8)));
+        assert_eq!(Message::next_line(&buf[8..], ParseMode::Lenient),
+                   Some((&b"PING b"[..], 7)));
+        assert_eq!(Message::next_line(&buf[15..], ParseMode::Lenient), None);
+        // strict mode doesn't treat the lone LF as a terminator
+        assert_eq!(Message::next_line(&buf[8..], ParseMode::Strict), None);
+        let (line, _) = Message::next_line(buf, ParseMode::Strict).unwrap();
+        assert_eq!(line, b"PING a");
+        assert!(Message::parse(b"PING b\nPING c", ParseMode::Strict)
+                .is_none());
+    }
 }
@@ -0,0 +1,137 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A borrowed counterpart to `Message`, for the input path. Most incoming
+//! lines are looked at once and thrown away, so there is no point copying
+//! them into a fresh buffer first.
+
+use super::*;
+
+/// A message parsed in place, borrowing from the buffer it was read into.
+/// Offers the same accessors as `Message`. Use `to_message` to get a
+/// `Message` that can be stored or relayed.
+pub struct MessageRef<'a> {
+    line: &'a [u8],
+    layout: Layout,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parse an input line in place. The same rules apply as for
+    /// `Message::parse`, and the same lines are accepted.
+    ///
+    /// The line is borrowed mutably because a textual command is folded to
+    /// uppercase in place. Nothing else in the line is touched.
+    pub fn parse(line: &'a mut [u8], mode: ParseMode)
+                 -> Option<MessageRef<'a>> {
+        let layout = Layout::parse(line, mode)?;
+        if let IntCommand::Textual(range) = &layout.command {
+            for b in line[range.start as usize .. range.end as usize]
+                .iter_mut() {
+                *b = upcase(*b);
+            }
+        }
+        Some(MessageRef { line, layout })
+    }
+    /// Returns the source (AKA prefix) specification of the message, if any.
+    pub fn get_source(&self) -> Option<Source<'a>> {
+        self.layout.source.as_ref().map(|x| x.extract(self.line))
+    }
+    /// Returns the command for this message.
+    pub fn get_command(&self) -> Command<'a> {
+        self.layout.command.extract(self.line)
+    }
+    /// Returns the number of additional parameters in this message.
+    pub fn get_param_count(&self) -> u32 {
+        self.layout.param_count
+    }
+    /// Returns the nth parameter.
+    ///
+    /// Unlike `Message::get_nth_param`, this has to walk the line to find
+    /// it. Parameters are few and short, so this is still cheap.
+    pub fn get_nth_param(&self, n: u32) -> Option<&'a [u8]> {
+        self.layout.params(self.line).nth(n as usize)
+    }
+    /// Returns whether the last parameter in this message follows a colon.
+    /// **YOU MUST NOT USE THIS INFORMATION TO CHANGE HOW YOU HANDLE AN
+    /// INCOMING MESSAGE!**
+    pub fn has_trailer(&self) -> bool {
+        self.layout.trailer
+    }
+    /// Copy this message into an owned `Message`.
+    pub fn to_message(&self) -> Message {
+        let params: Vec<&[u8]> = self.layout.params(self.line).collect();
+        // can't fail; `Layout::parse` already checked everything `assemble`
+        // would
+        Message::assemble(self.get_source().as_ref(), &self.get_command(),
+                          &params[..], self.layout.trailer).unwrap()
+    }
+}
+
+impl<'a> Debug for MessageRef<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        fmt.write_str("MessageRef(")?;
+        Debug::fmt(&String::from_utf8_lossy(self.line), fmt)?;
+        fmt.write_str(")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const LINES: &[&[u8]] = &[
+        b"PING",
+        b"314 TestDood :This is a simple test",
+        b":irc.example.com 005 nick CASEMAPPING=ascii :are supported",
+        b":nickName!user@HostName PRIVMSG #not-invalid:name :Eek, a colon!",
+        b"@time=now :nick@host privmsg  #chan  :double  spaced ",
+        b"MODE #chan +o bob \t ",
+        b"FOO 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 :16",
+        b"",
+        b"000 zero",
+        b":bad!source!twice FOO",
+        b"FOO :trailer with a \r in it",
+    ];
+    /// `MessageRef` must accept exactly what `Message` does, and see the
+    /// same thing in it.
+    #[test]
+    fn matches_message() {
+        for mode in &[ParseMode::Strict, ParseMode::Lenient] {
+            for line in LINES {
+                let owned = Message::parse(line, *mode);
+                let mut buf = line.to_vec();
+                let borrowed = MessageRef::parse(&mut buf, *mode);
+                let (owned, borrowed) = match (owned, borrowed) {
+                    (None, None) => continue,
+                    (Some(a), Some(b)) => (a, b),
+                    (a, b) => panic!("{:?} disagreement on {:?}: {:?} vs \
+                                      {:?}", mode,
+                                     String::from_utf8_lossy(line), a, b),
+                };
+                assert_eq!(owned.get_source(), borrowed.get_source());
+                assert_eq!(owned.get_command(), borrowed.get_command());
+                assert_eq!(owned.get_param_count(),
+                           borrowed.get_param_count());
+                assert_eq!(owned.has_trailer(), borrowed.has_trailer());
+                for n in 0 ..= owned.get_param_count() {
+                    assert_eq!(owned.get_nth_param(n),
+                               borrowed.get_nth_param(n));
+                }
+                assert_eq!(owned.get_raw(), borrowed.to_message().get_raw());
+            }
+        }
+    }
+}
@@ -22,12 +22,15 @@
 //! as the message tags, can also return `Some` or `None` *within* the outer
 //! `Some`.
 
+use super::ParseMode;
+
 pub fn is_nulcrlf(x: u8) -> bool { x == 0 || x == b'\r' || x == b'\n' }
 pub fn is_nulcrlfspace(x: u8) -> bool { x == 0 || x == b'\r' || x == b'\n'
                                         || x == b' '}
 pub fn is_nulcrlfspaceatbang(x: u8) -> bool { x == 0 || x == b'\r'
                                               || x == b'\n' || x == b' '
                                               || x == b'@' || x == b'!'}
+pub fn is_space_or_tab(x: u8) -> bool { x == b' ' || x == b'\t' }
 
 pub fn validate_param(param: &[u8]) -> Result<(), &'static str> {
     if param.is_empty() { Err("Invalid empty param") }
@@ -48,9 +51,9 @@ pub fn validate_trailing_param(param: &[u8]) -> Result<(), &'static str> {
 }
 
 pub fn find_idx_of_space_or_end(line: &[u8]) -> Option<usize> {
-    for n in 0 .. line.len() {
-        match line[n] {
-            b'\r' | 0 => return None,
+    for (n, &b) in line.iter().enumerate() {
+        match b {
+            b'\r' | b'\n' | 0 => return None,
             b' ' => return Some(n),
             _ => (),
         }
@@ -59,61 +62,101 @@ pub fn find_idx_of_space_or_end(line: &[u8]) -> Option<usize> {
 }
 
 pub fn skip_leading_space(line: &[u8]) -> Option<&[u8]> {
-    for n in 0 .. line.len() {
-        match line[n] {
-            b'\r' | 0 => return None,
+    for (n, &b) in line.iter().enumerate() {
+        match b {
+            b'\r' | b'\n' | 0 => return None,
             b' ' => (),
             _ => return Some(&line[n..])
         }
     }
-    Some(&[])
+    // (not `&[]`; callers may need to know where in the line we ended up)
+    Some(&line[line.len()..])
+}
+
+/// Skip the space that separates two components of a message. In `Strict`
+/// mode, this must be exactly one space, and it must be followed by
+/// something. In `Lenient` mode, any number of spaces is accepted.
+pub fn skip_separator(line: &[u8], mode: ParseMode) -> Option<&[u8]> {
+    match mode {
+        ParseMode::Strict => {
+            if line.is_empty() { Some(line) }
+            else if line[0] != b' ' || line.len() == 1 || line[1] == b' '
+            { None }
+            else { Some(&line[1..]) }
+        },
+        ParseMode::Lenient => skip_leading_space(line),
+    }
 }
 
-pub fn parse_tags(line: &[u8]) -> Option<(Option<&[u8]>, &[u8])> {
+/// Strip any trailing spaces and tabs from a line. Only used in `Lenient`
+/// mode, and only when the line has no trailing parameter.
+pub fn trim_trailing_whitespace(line: &[u8]) -> &[u8] {
+    let end = line.iter().rposition(|x| !is_space_or_tab(*x))
+        .map(|x| x + 1).unwrap_or(0);
+    &line[..end]
+}
+
+pub fn parse_tags(line: &[u8], mode: ParseMode)
+                  -> Option<(Option<&[u8]>, &[u8])> {
     if line.is_empty() || line[0] != b'@' { Some((None, line)) }
     else {
         let split = find_idx_of_space_or_end(line)?;
-        Some((Some(&line[1..split]), skip_leading_space(line)?))
+        let rest = skip_separator(&line[split..], mode)?;
+        Some((Some(&line[1..split]), rest))
     }
 }
 
 pub fn parse_source_name_or_nick(line: &[u8]) -> Option<(&[u8], u8, &[u8])> {
-    for i in 0..line.len() {
-        match line[i] {
+    for (i, &b) in line.iter().enumerate() {
+        match b {
             b'\r' | 0 => return None,
-            b'@' | b'!' => return Some((&line[..i], line[i],
-                                        &line[i+1..])),
+            b'@' | b'!' => return Some((&line[..i], b, &line[i+1..])),
             b' ' => unreachable!(), // space should not have made it this far
             _ => (),
         }
     }
-    Some((&line[..], b' ', &[]))
+    Some((line, b' ', &[]))
 }
 
 pub fn parse_source_user(line: &[u8]) -> Option<(&[u8], u8, &[u8])> {
-    for i in 0..line.len() {
-        match line[i] {
+    for (i, &b) in line.iter().enumerate() {
+        match b {
             b'\r' | 0 | b'!' => return None,
-            b'@' => return Some((&line[..i], line[i], &line[i+1..])),
+            b'@' => return Some((&line[..i], b, &line[i+1..])),
             b' ' => unreachable!(), // space should not have made it this far
             _ => (),
         }
     }
-    Some((&line[..], b' ', &[]))
+    Some((line, b' ', &[]))
 }
 
 pub fn parse_source_host(line: &[u8]) -> Option<(&[u8], &[u8])> {
-    for i in 0..line.len() {
-        match line[i] {
+    for &b in line.iter() {
+        match b {
             b'\r' | 0 | b'!' | b'@' => return None,
             b' ' => unreachable!(), // space should not have made it this far
             _ => (),
         }
     }
-    return Some((line, &[]))
+    Some((line, &[]))
 }
 
 pub fn parse_digit(digit: u8) -> Option<u32> {
-    if digit >= b'0' && digit <= b'9' { Some((digit - b'0') as u32) }
+    if digit.is_ascii_digit() { Some((digit - b'0') as u32) }
     else { None }
 }
+
+/// Is this a command name the RFC would accept? That is, either three digits
+/// or one or more uppercase letters.
+pub fn is_strict_command(command: &[u8]) -> bool {
+    (command.len() == 3 && command.iter().all(|x| x.is_ascii_digit()))
+        || (!command.is_empty()
+            && command.iter().all(|x| x.is_ascii_uppercase()))
+}
+
+/// Does this line (starting from the first parameter) have a trailing
+/// parameter? Whitespace at the end of a line that does is part of that
+/// parameter, not junk.
+pub fn has_trailing_param(line: &[u8]) -> bool {
+    line.first() == Some(&b':') || line.windows(2).any(|x| x == b" :")
+}
@@ -0,0 +1,239 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Utilities for breaking up text that won't fit into a single message.
+//!
+//! The line limit applies to the message *as the recipient sees it*, which
+//! includes the source. A client that sends a 500-byte `PRIVMSG` has no idea
+//! how long its `nick!user@host` is, so when we relay it, it may no longer
+//! fit. These functions take the source into account.
+
+use super::*;
+
+/// The longest a message may be on the wire, including the trailing
+/// `"\r\n"`, but not including any tags.
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// Returns the number of bytes a message with these components would take up
+/// on the wire, not counting any parameters other than `leading`.
+fn overhead(source: Option<&Source>, command: &Command, leading: &[&[u8]])
+            -> usize {
+    let command_len = match command {
+        Command::Numeric(_) => 3,
+        Command::Textual(x) => x.len(),
+    };
+    source.map(|x| x.raw_len()).unwrap_or(0)
+        + command_len
+        + leading.iter().map(|x| x.len() + 1).sum::<usize>()
+        + 2
+}
+
+/// Returns the ranges of `text` that are occupied by formatting codes that
+/// take arguments. A split inside one of these would change the meaning of
+/// both halves.
+fn formatting_spans(text: &[u8]) -> Vec<Range<usize>> {
+    formatting::tokens(text).filter_map(|(range, token)| match token {
+        formatting::Token::Color { .. } | formatting::Token::HexColor { .. }
+        if range.len() > 1 => Some(range),
+        _ => None,
+    }).collect()
+}
+
+/// Returns true if `text` can be split immediately before byte `at` without
+/// breaking a UTF-8 sequence or a formatting code.
+fn is_safe_split(text: &[u8], spans: &[Range<usize>], at: usize) -> bool {
+    if at >= text.len() { return true }
+    if text[at] & 0xC0 == 0x80 { return false }
+    !spans.iter().any(|x| x.start < at && at < x.end)
+}
+
+/// Breaks `text` into pieces of at most `budget` bytes, preferring to break
+/// at spaces. The space at which a break occurs is dropped. Returns `None` if
+/// `budget` is too small to make progress.
+fn split_bytes(text: &[u8], budget: usize) -> Option<Vec<&[u8]>> {
+    let spans = formatting_spans(text);
+    let mut ret = Vec::new();
+    let mut base = 0;
+    while text.len() - base > budget {
+        let window = &text[base ..= base + budget];
+        let space = window.iter().rposition(|x| *x == b' ')
+            .filter(|x| *x > 0);
+        match space {
+            Some(x) => {
+                ret.push(&text[base .. base + x]);
+                base += x + 1;
+            },
+            None => {
+                let mut cut = base + budget;
+                while cut > base && !is_safe_split(text, &spans, cut) {
+                    cut -= 1;
+                }
+                if cut == base { return None }
+                ret.push(&text[base .. cut]);
+                base = cut;
+            },
+        }
+    }
+    ret.push(&text[base..]);
+    Some(ret)
+}
+
+impl Message {
+    /// Makes as many `Message`s as needed to send `text` as the trailing
+    /// parameter, such that each one fits within `MAX_MESSAGE_LEN` bytes
+    /// once `source` is included. `leading` are the parameters that come
+    /// before the text, e.g. the target of a `PRIVMSG`, or the nick, channel
+    /// type and channel of an `RPL_NAMREPLY`.
+    ///
+    /// Splits at spaces where possible. Never splits inside a UTF-8 sequence
+    /// or a color code.
+    pub fn split_text(source: Option<&Source>, command: &Command,
+                      leading: &[&[u8]], text: &[u8])
+                      -> Result<Vec<Message>, &'static str> {
+        let budget = MAX_MESSAGE_LEN
+            .checked_sub(overhead(source, command, leading) + 2)
+            .ok_or("no room for text in message")?;
+        let pieces = split_bytes(text, budget)
+            .ok_or("no room for text in message")?;
+        let mut params = leading.to_vec();
+        params.push(b"");
+        pieces.into_iter().map(|piece| {
+            *params.last_mut().unwrap() = piece;
+            Message::assemble(source, command, &params[..], true)
+        }).collect()
+    }
+    /// Makes as many `Message`s as needed to send every one of `items` as a
+    /// middle parameter, such that each one fits within `MAX_MESSAGE_LEN`
+    /// bytes and has no more than `max_items` items. `leading` are the
+    /// parameters that come before the items, and `trailer`, if any, is
+    /// appended to every message. This is the shape of `RPL_ISUPPORT`.
+    pub fn pack_params(source: Option<&Source>, command: &Command,
+                       leading: &[&[u8]], items: &[&[u8]],
+                       trailer: Option<&[u8]>, max_items: usize)
+                       -> Result<Vec<Message>, &'static str> {
+        let budget = MAX_MESSAGE_LEN
+            .checked_sub(overhead(source, command, leading)
+                         + trailer.map(|x| x.len() + 2).unwrap_or(0))
+            .ok_or("no room for parameters in message")?;
+        let mut ret = Vec::new();
+        let mut rest = items;
+        while !rest.is_empty() {
+            let mut used = 0;
+            let mut count = 0;
+            for item in rest.iter().take(max_items) {
+                if used + item.len() + 1 > budget { break }
+                used += item.len() + 1;
+                count += 1;
+            }
+            if count == 0 {
+                return Err("parameter too long to fit in a message")
+            }
+            let mut params = leading.to_vec();
+            params.extend_from_slice(&rest[..count]);
+            if let Some(trailer) = trailer { params.push(trailer) }
+            ret.push(Message::assemble(source, command, &params[..],
+                                       trailer.is_some())?);
+            rest = &rest[count..];
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const SOURCE: Source<'static> = Source::Client {
+        nick: b"SomeoneWithALongNick",
+        user: Some(b"username"),
+        host: b"a.rather.long.hostname.example.com",
+    };
+    fn trailers(messages: &[Message]) -> Vec<&[u8]> {
+        messages.iter().map(|x| {
+            assert!(x.get_raw().len() <= MAX_MESSAGE_LEN);
+            x.get_nth_param(x.get_param_count() - 1).unwrap()
+        }).collect()
+    }
+    #[test]
+    fn short_text_is_untouched() {
+        let messages = Message::split_text(Some(&SOURCE),
+                                           &Command::Textual(b"PRIVMSG"),
+                                           &[b"#chan"], b"hello world")
+            .unwrap();
+        assert_eq!(trailers(&messages), vec![&b"hello world"[..]]);
+    }
+    #[test]
+    fn splits_on_words() {
+        let text = "word ".repeat(200);
+        let text = text.trim_end().as_bytes();
+        let messages = Message::split_text(Some(&SOURCE),
+                                           &Command::Textual(b"PRIVMSG"),
+                                           &[b"#chan"], text).unwrap();
+        assert!(messages.len() > 1);
+        let pieces = trailers(&messages);
+        for piece in pieces.iter() {
+            assert!(!piece.starts_with(b" ") && !piece.ends_with(b" "));
+            assert!(piece.split(|x| *x == b' ').all(|x| x == b"word"));
+        }
+        assert_eq!(pieces.join(&b' '), text);
+    }
+    #[test]
+    fn never_splits_utf8() {
+        let text = "ü".repeat(600);
+        let messages = Message::split_text(Some(&SOURCE),
+                                           &Command::Textual(b"NOTICE"),
+                                           &[b"someone"], text.as_bytes())
+            .unwrap();
+        let pieces = trailers(&messages);
+        for piece in pieces.iter() {
+            assert!(std::str::from_utf8(piece).is_ok());
+        }
+        assert_eq!(pieces.concat(), text.as_bytes());
+    }
+    #[test]
+    fn never_splits_color_codes() {
+        let text = "\x0312,04x".repeat(200);
+        let messages = Message::split_text(Some(&SOURCE),
+                                           &Command::Textual(b"PRIVMSG"),
+                                           &[b"#chan"], text.as_bytes())
+            .unwrap();
+        let pieces = trailers(&messages);
+        for piece in pieces[1..].iter() {
+            assert!(!piece[0].is_ascii_digit() && piece[0] != b',');
+        }
+        assert_eq!(pieces.concat(), text.as_bytes());
+    }
+    #[test]
+    fn packs_params() {
+        let items: Vec<Vec<u8>> = (0..40)
+            .map(|x| format!("TOKEN{}=value", x).into_bytes()).collect();
+        let items: Vec<&[u8]> = items.iter().map(|x| &x[..]).collect();
+        let messages = Message::pack_params(
+            Some(&Source::Server { name: b"irc.example.com" }),
+            &Command::Numeric(5), &[b"nick"], &items[..],
+            Some(b"are supported by this server"), 13).unwrap();
+        assert_eq!(messages.len(), 4);
+        let mut seen = Vec::new();
+        for message in messages.iter() {
+            assert!(message.get_param_count() <= 15);
+            assert!(message.has_trailer());
+            for n in 1 .. message.get_param_count() - 1 {
+                seen.push(message.get_nth_param(n).unwrap());
+            }
+        }
+        assert_eq!(seen, items);
+    }
+}
@@ -0,0 +1,228 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! What nicknames and channel names may be.
+//!
+//! Under the byte-oriented case mappings, names are ASCII and follow the
+//! traditional rules. Under `CaseMapping::Precis`, they may be UTF-8, and are
+//! prepared the way PRECIS prepares identifiers: fullwidth forms are
+//! narrowed, the result is put into Normalization Form C, and code points
+//! that Unicode doesn't recommend for identifiers are refused.
+//!
+//! Unicode also makes impersonation easy; `раul`, with a Cyrillic `р` and
+//! `а`, looks just like `paul`. Each name therefore also has a *skeleton*,
+//! per Unicode Technical Standard #39, and a `ConfusableIndex` refuses a name
+//! whose skeleton matches that of a different name already in use.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::GeneralSecurityProfile;
+
+use crate::*;
+
+/// The longest nickname we allow, in bytes.
+pub const MAX_NICK_LEN: usize = 30;
+/// The longest channel name we allow, in bytes, including the prefix.
+pub const MAX_CHANNEL_LEN: usize = 50;
+/// The characters that can start a channel name.
+pub const CHANNEL_PREFIXES: &[u8] = b"#&";
+
+/// Add the tokens describing our name rules to `RPL_ISUPPORT`.
+pub fn advertise(isupport: &mut ISupport) {
+    isupport.set("NICKLEN", Some(MAX_NICK_LEN.to_string().as_bytes()));
+    isupport.set("CHANNELLEN", Some(MAX_CHANNEL_LEN.to_string().as_bytes()));
+    isupport.set("CHANTYPES", Some(CHANNEL_PREFIXES));
+}
+
+/// The punctuation RFC 2812 allows in nicknames.
+fn is_nick_special(b: u8) -> bool {
+    matches!(b, b'[' | b']' | b'\\' | b'`' | b'_' | b'^' | b'{' | b'|' | b'}')
+}
+
+/// Narrow and normalize a UTF-8 name. Borrows if nothing changed.
+fn precis_prepare(name: &[u8]) -> Result<Cow<'_, [u8]>, &'static str> {
+    if name.is_ascii() { return Ok(Cow::Borrowed(name)) }
+    let name = std::str::from_utf8(name).map_err(|_| "name is not UTF-8")?;
+    let prepared: String = name.chars().map(narrow).nfc().collect();
+    if prepared == name { Ok(Cow::Borrowed(name.as_bytes())) }
+    else { Ok(Cow::Owned(prepared.into_bytes())) }
+}
+
+/// Is this non-ASCII character acceptable in a name?
+fn is_allowed_unicode(c: char) -> bool {
+    c.identifier_allowed() && !c.is_whitespace() && !c.is_control()
+}
+
+/// Check a nickname, and return it in prepared form.
+pub fn prepare_nick(nick: &[u8], mapping: CaseMapping)
+                    -> Result<Cow<'_, [u8]>, &'static str> {
+    let nick = match mapping {
+        CaseMapping::Precis => precis_prepare(nick)?,
+        _ if !nick.is_ascii() => return Err("nickname must be ASCII"),
+        _ => Cow::Borrowed(nick),
+    };
+    if nick.is_empty() { return Err("nickname is empty") }
+    if nick.len() > MAX_NICK_LEN { return Err("nickname is too long") }
+    if nick[0].is_ascii_digit() || nick[0] == b'-' {
+        return Err("nickname may not start with a digit or hyphen")
+    }
+    let ascii_ok = |b: u8| b.is_ascii_alphanumeric() || is_nick_special(b)
+        || b == b'-';
+    let ok = match std::str::from_utf8(&nick) {
+        Ok(x) if !x.is_ascii() => x.chars().all(|c| {
+            if c.is_ascii() { ascii_ok(c as u8) } else { is_allowed_unicode(c) }
+        }),
+        _ => nick.iter().all(|x| ascii_ok(*x)),
+    };
+    if ok { Ok(nick) } else { Err("invalid character in nickname") }
+}
+
+/// Check a channel name, and return it in prepared form.
+pub fn prepare_channel(name: &[u8], mapping: CaseMapping)
+                       -> Result<Cow<'_, [u8]>, &'static str> {
+    let name = match mapping {
+        CaseMapping::Precis => precis_prepare(name)?,
+        _ => Cow::Borrowed(name),
+    };
+    if name.len() < 2 || !CHANNEL_PREFIXES.contains(&name[0]) {
+        return Err("not a channel name")
+    }
+    if name.len() > MAX_CHANNEL_LEN { return Err("channel name is too long") }
+    let ascii_ok = |b: u8| b > b' ' && b != b',' && b != 0x7F;
+    let ok = match (mapping, std::str::from_utf8(&name)) {
+        (CaseMapping::Precis, Ok(x)) => x.chars().all(|c| {
+            if c.is_ascii() { ascii_ok(c as u8) } else { is_allowed_unicode(c) }
+        }),
+        // Other mappings don't care what non-ASCII bytes mean, but they still
+        // mustn't be spaces, commas or controls.
+        _ => name.iter().all(|x| !x.is_ascii() || ascii_ok(*x)),
+    };
+    if ok { Ok(name) } else { Err("invalid character in channel name") }
+}
+
+/// Compute the key under which a name is checked for confusables: its
+/// UTS #39 skeleton, folded. Two names with the same key look alike.
+pub fn skeleton(name: &[u8], mapping: CaseMapping) -> Vec<u8> {
+    match std::str::from_utf8(name) {
+        Ok(x) => {
+            let skeleton: String = unicode_security::skeleton(x).collect();
+            mapping.fold(skeleton.as_bytes()).into_owned()
+        },
+        Err(_) => mapping.fold(name).into_owned(),
+    }
+}
+
+/// An index of the names in use, by skeleton, for finding names that could be
+/// mistaken for one another.
+///
+/// Only pairs where at least one name is non-ASCII count as confusable.
+/// The ASCII confusables (`rn` and `m`, `I` and `l`) have been with IRC
+/// forever, and refusing them would break far more than it protected.
+pub struct ConfusableIndex {
+    mapping: CaseMapping,
+    skeletons: HashMap<Vec<u8>, Vec<Name>>,
+}
+
+impl ConfusableIndex {
+    pub fn new(mapping: CaseMapping) -> ConfusableIndex {
+        ConfusableIndex { mapping, skeletons: HashMap::new() }
+    }
+    /// Note that a name is in use.
+    pub fn insert(&mut self, name: &Name) {
+        let names = self.skeletons
+            .entry(skeleton(name.as_bytes(), self.mapping))
+            .or_default();
+        if !names.contains(name) { names.push(name.clone()) }
+    }
+    /// Note that a name is no longer in use.
+    pub fn remove(&mut self, name: &Name) {
+        let key = skeleton(name.as_bytes(), self.mapping);
+        if let Some(names) = self.skeletons.get_mut(&key) {
+            names.retain(|x| x != name);
+            if names.is_empty() { self.skeletons.remove(&key); }
+        }
+    }
+    /// If `name` could be mistaken for a *different* name already in use,
+    /// returns that name.
+    pub fn find_confusable(&self, name: &Name) -> Option<&Name> {
+        let key = skeleton(name.as_bytes(), self.mapping);
+        self.skeletons.get(&key)?.iter().find(|x| {
+            *x != name
+                && !(x.as_bytes().is_ascii() && name.as_bytes().is_ascii())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn nicks() {
+        use CaseMapping::*;
+        let cases: &[(CaseMapping, &str, Option<&str>)] = &[
+            (Ascii, "Fox", Some("Fox")),
+            (Ascii, "[Fox]-1", Some("[Fox]-1")),
+            (Ascii, "1fox", None),
+            (Ascii, "fox!", None),
+            (Ascii, "Füchsin", None),
+            (Precis, "Füchsin", Some("Füchsin")),
+            // combining diaeresis gets composed
+            (Precis, "Fu\u{308}chsin", Some("Füchsin")),
+            (Precis, "ＦＯＸ", Some("FOX")),
+            (Precis, "fox\u{200B}", None),
+            (Precis, "fox bar", None),
+            (Precis, "狐", Some("狐")),
+            (Precis, "", None),
+        ];
+        for &(mapping, nick, expected) in cases {
+            let got = prepare_nick(nick.as_bytes(), mapping).ok();
+            assert_eq!(got.as_deref(), expected.map(|x| x.as_bytes()),
+                       "{:?}: {:?}", mapping, nick);
+        }
+    }
+    #[test]
+    fn channels() {
+        use CaseMapping::*;
+        assert!(prepare_channel(b"#foxy-ircd", Ascii).is_ok());
+        assert!(prepare_channel(b"&local", Ascii).is_ok());
+        assert!(prepare_channel(b"foxy", Ascii).is_err());
+        assert!(prepare_channel(b"#", Ascii).is_err());
+        assert!(prepare_channel(b"#a,b", Ascii).is_err());
+        assert!(prepare_channel(b"#a\x07", Ascii).is_err());
+        assert_eq!(&prepare_channel("#ｆｏｘ".as_bytes(), Precis).unwrap()[..],
+                   b"#fox");
+        assert!(prepare_channel("#fox\u{200D}".as_bytes(), Precis).is_err());
+    }
+    #[test]
+    fn confusables() {
+        let mapping = CaseMapping::Precis;
+        let mut index = ConfusableIndex::new(mapping);
+        let paul = Name::new(b"paul", mapping);
+        index.insert(&paul);
+        let cyrillic = Name::new("раul".as_bytes(), mapping);
+        assert_eq!(index.find_confusable(&cyrillic), Some(&paul));
+        // the same name (in any case) isn't a confusable, just a duplicate
+        assert_eq!(index.find_confusable(&Name::new(b"PAUL", mapping)), None);
+        // ASCII-only lookalikes are tolerated
+        assert_eq!(index.find_confusable(&Name::new(b"pauI", mapping)), None);
+        index.remove(&paul);
+        assert_eq!(index.find_confusable(&cyrillic), None);
+    }
+}
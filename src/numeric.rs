@@ -0,0 +1,82 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Numeric replies, under their conventional names.
+
+use crate::*;
+
+pub const RPL_MYINFO: u32 = 4;
+pub const RPL_ISUPPORT: u32 = 5;
+pub const RPL_UMODEIS: u32 = 221;
+pub const RPL_ACCEPTLIST: u32 = 281;
+pub const RPL_ENDOFACCEPT: u32 = 282;
+pub const RPL_ENDOFWHO: u32 = 315;
+pub const RPL_LISTSTART: u32 = 321;
+pub const RPL_LIST: u32 = 322;
+pub const RPL_LISTEND: u32 = 323;
+pub const RPL_CHANNELMODEIS: u32 = 324;
+pub const RPL_CREATIONTIME: u32 = 329;
+pub const RPL_NOTOPIC: u32 = 331;
+pub const RPL_TOPIC: u32 = 332;
+pub const RPL_TOPICWHOTIME: u32 = 333;
+pub const RPL_WHOISBOT: u32 = 335;
+pub const RPL_INVITELIST: u32 = 346;
+pub const RPL_ENDOFINVITELIST: u32 = 347;
+pub const RPL_EXCEPTLIST: u32 = 348;
+pub const RPL_ENDOFEXCEPTLIST: u32 = 349;
+pub const RPL_WHOREPLY: u32 = 352;
+pub const RPL_NAMREPLY: u32 = 353;
+pub const RPL_ENDOFNAMES: u32 = 366;
+pub const RPL_BANLIST: u32 = 367;
+pub const RPL_ENDOFBANLIST: u32 = 368;
+pub const ERR_NOSUCHCHANNEL: u32 = 403;
+pub const ERR_CANNOTSENDTOCHAN: u32 = 404;
+pub const ERR_NORECIPIENT: u32 = 411;
+pub const ERR_NOTEXTTOSEND: u32 = 412;
+pub const ERR_USERNOTINCHANNEL: u32 = 441;
+pub const ERR_NOTONCHANNEL: u32 = 442;
+pub const ERR_ACCEPTFULL: u32 = 456;
+pub const ERR_ACCEPTEXIST: u32 = 457;
+pub const ERR_ACCEPTNOT: u32 = 458;
+pub const ERR_NEEDMOREPARAMS: u32 = 461;
+pub const ERR_CHANNELISFULL: u32 = 471;
+pub const ERR_UNKNOWNMODE: u32 = 472;
+pub const ERR_INVITEONLYCHAN: u32 = 473;
+pub const ERR_BANNEDFROMCHAN: u32 = 474;
+pub const ERR_BADCHANNELKEY: u32 = 475;
+pub const ERR_BADCHANMASK: u32 = 476;
+pub const ERR_BANLISTFULL: u32 = 478;
+pub const ERR_NOPRIVILEGES: u32 = 481;
+pub const ERR_CHANOPRIVSNEEDED: u32 = 482;
+pub const ERR_NONONREG: u32 = 486;
+pub const ERR_UMODEUNKNOWNFLAG: u32 = 501;
+pub const ERR_USERSDONTMATCH: u32 = 502;
+pub const ERR_TARGUMODEG: u32 = 716;
+pub const RPL_TARGNOTIFY: u32 = 717;
+pub const RPL_UMODEGMSG: u32 = 718;
+
+/// Makes a numeric reply from `server` to `nick`. The last of `params`, if
+/// there are any, is sent as a trailing parameter.
+pub fn reply(server: &[u8], numeric: u32, nick: &[u8], params: &[&[u8]])
+             -> Result<Message, &'static str> {
+    let mut all = Vec::with_capacity(params.len() + 1);
+    all.push(nick);
+    all.extend_from_slice(params);
+    Message::assemble(Some(&Source::Server { name: server }),
+                      &Command::Numeric(numeric), &all[..],
+                      !params.is_empty())
+}
@@ -0,0 +1,83 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! Who a client is, as other clients see it.
+
+use std::net::IpAddr;
+
+use crate::*;
+
+mod mode;
+pub use mode::*;
+mod oper;
+
+/// The identity of a registered client: what goes into the source of the
+/// messages it sends, and what masks are matched against.
+#[derive(Clone,Debug)]
+pub struct User {
+    pub nick: Name,
+    pub user: Vec<u8>,
+    pub host: Vec<u8>,
+    /// The client's real address, if known, for matching masks.
+    pub ip: Option<IpAddr>,
+    /// The "real name" the client gave in `USER`.
+    pub realname: Vec<u8>,
+    /// The account the client is logged in to, if any.
+    pub account: Option<Vec<u8>>,
+    /// Is the client connected with TLS?
+    pub tls: bool,
+    pub modes: UserModes,
+    /// Has the client negotiated the `multi-prefix` capability, so that it
+    /// wants to see every status a member has in `NAMES` and `WHO`?
+    pub multi_prefix: bool,
+    /// Has the client negotiated the `message-tags` capability? Clients that
+    /// haven't must never be sent tags.
+    pub message_tags: bool,
+}
+
+impl User {
+    pub fn new(nick: &[u8], user: &[u8], host: &[u8], mapping: CaseMapping)
+               -> User {
+        User {
+            nick: Name::new(nick, mapping),
+            user: user.to_vec(),
+            host: host.to_vec(),
+            ip: None,
+            realname: Vec::new(),
+            account: None,
+            tls: false,
+            modes: UserModes::default(),
+            multi_prefix: false,
+            message_tags: false,
+        }
+    }
+    /// The source to put on messages from this client.
+    pub fn source(&self) -> Source<'_> {
+        Source::Client { nick: self.nick.as_bytes(), user: Some(&self.user),
+                         host: &self.host }
+    }
+    /// `nick!user@host`, as shown in e.g. `RPL_TOPICWHOTIME`.
+    pub fn hostmask(&self) -> Vec<u8> {
+        let mut ret = self.nick.as_bytes().to_vec();
+        ret.push(b'!');
+        ret.extend_from_slice(&self.user);
+        ret.push(b'@');
+        ret.extend_from_slice(&self.host);
+        ret
+    }
+}
@@ -0,0 +1,397 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+//! User modes, and what they change about how a client is treated.
+//!
+//! - `+i` (invisible) hides the client from `NAMES` and `WHO` on channels,
+//!   except for the channel's other members.
+//! - `+w` (wallops) asks for `WALLOPS`.
+//! - `+o` (operator) is only given by `OPER`, though a client may drop it.
+//! - `+B` (bot) marks the client as a bot. Its messages carry the `bot` tag,
+//!   and `WHOIS` shows `RPL_WHOISBOT`.
+//! - `+R` (registered only) refuses private messages from clients that
+//!   aren't logged in to an account, unless they're on the accept list.
+//! - `+D` (deaf) stops channel messages from being delivered to the client.
+//! - `+g` (caller ID) refuses private messages from anyone not on the accept
+//!   list, which `ACCEPT` edits. Whoever is refused is told so, and the
+//!   client is told who tried, at most once every
+//!   `CALLER_ID_NOTICE_INTERVAL` seconds.
+
+use std::collections::BTreeSet;
+
+use super::*;
+use crate::channel::{queue_reply, Outbox, Prefixes};
+use numeric::*;
+
+/// Every user mode we support, for `RPL_MYINFO`.
+pub const USER_MODES: &[u8] = b"BDRgiow";
+/// The most nicks an accept list may hold.
+pub const MAX_ACCEPT_LEN: usize = 100;
+/// How often, in seconds, a `+g` client is told someone is messaging it.
+pub const CALLER_ID_NOTICE_INTERVAL: u64 = 60;
+
+/// Add the tokens describing our user modes to `RPL_ISUPPORT`.
+pub fn advertise(isupport: &mut ISupport) {
+    isupport.set("CALLERID", Some(b"g"));
+    isupport.set("DEAF", Some(b"D"));
+}
+
+/// Makes `RPL_MYINFO`, which tells a newly registered client our name and
+/// version, and which user and channel modes we support.
+pub fn myinfo(server: &[u8], nick: &[u8], version: &[u8],
+              prefixes: &Prefixes) -> Result<Message, &'static str> {
+    let (channel_modes, with_param) = channel::mode_letters(prefixes);
+    Message::assemble(Some(&Source::Server { name: server }),
+                      &Command::Numeric(RPL_MYINFO),
+                      &[nick, server, version, USER_MODES, &channel_modes,
+                        &with_param], false)
+}
+
+/// A client's user modes, and its accept list.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct UserModes {
+    flags: BTreeSet<u8>,
+    accept: Vec<Name>,
+    /// When the client was last told that `+g` refused someone.
+    last_caller_id_notice: Option<u64>,
+}
+
+impl UserModes {
+    pub fn is_set(&self, letter: u8) -> bool {
+        self.flags.contains(&letter)
+    }
+    /// Is this nick on the accept list?
+    pub fn accepts(&self, nick: &Name) -> bool {
+        self.accept.contains(nick)
+    }
+    pub fn accept_list(&self) -> &[Name] { &self.accept }
+    /// The mode string for `RPL_UMODEIS`.
+    fn describe(&self) -> Vec<u8> {
+        let mut ret = b"+".to_vec();
+        ret.extend(self.flags.iter());
+        ret
+    }
+}
+
+impl User {
+    /// The message tags to put on messages from this client, for those
+    /// recipients that have negotiated `message-tags`.
+    pub fn tags(&self) -> Option<&'static [u8]> {
+        if self.modes.is_set(b'B') { Some(b"bot") } else { None }
+    }
+    /// Makes a message from this client, tagged if `recipient` may be sent
+    /// tags.
+    pub fn message_to(&self, recipient: &User, command: &[u8],
+                      params: &[&[u8]]) -> Result<Message, &'static str> {
+        let tags = if recipient.message_tags { self.tags() } else { None };
+        Message::assemble_tagged(tags, Some(&self.source()),
+                                 &Command::Textual(command), params, true)
+    }
+    /// The `MODE` message telling this client its modes changed.
+    fn mode_message(&self, changes: &[(bool, u8)])
+                    -> Result<Message, &'static str> {
+        let mut modes = Vec::new();
+        let mut sign = None;
+        for &(adding, letter) in changes.iter() {
+            if sign != Some(adding) {
+                modes.push(if adding { b'+' } else { b'-' });
+                sign = Some(adding);
+            }
+            modes.push(letter);
+        }
+        Message::assemble(Some(&self.source()), &Command::Textual(b"MODE"),
+                          &[self.nick.as_bytes(), &modes], true)
+    }
+    /// `MODE nick [modes]`. A client may only look at or change its own
+    /// modes.
+    pub fn mode(&mut self, server: &[u8], mapping: CaseMapping,
+                target: &[u8], modes: Option<&[u8]>)
+                -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        if Name::new(target, mapping) != self.nick {
+            queue_reply(server, self, ERR_USERSDONTMATCH,
+                        &[b"Can't change mode for other users"],
+                        &mut outbox)?;
+            return Ok(outbox)
+        }
+        let modes = match modes {
+            Some(x) => x,
+            None => {
+                queue_reply(server, self, RPL_UMODEIS,
+                            &[&self.modes.describe()], &mut outbox)?;
+                return Ok(outbox)
+            },
+        };
+        let mut adding = true;
+        let mut changes = Vec::new();
+        let mut unknown = false;
+        for &letter in modes.iter() {
+            match letter {
+                b'+' => adding = true,
+                b'-' => adding = false,
+                // only OPER gives this
+                b'o' if adding => (),
+                x if USER_MODES.contains(&x) => {
+                    let changed = if adding { self.modes.flags.insert(x) }
+                    else { self.modes.flags.remove(&x) };
+                    if changed { changes.push((adding, x)) }
+                },
+                _ => unknown = true,
+            }
+        }
+        if unknown {
+            queue_reply(server, self, ERR_UMODEUNKNOWNFLAG,
+                        &[b"Unknown MODE flag"], &mut outbox)?;
+        }
+        if !changes.is_empty() {
+            outbox.push((self.nick.clone(), self.mode_message(&changes)?));
+        }
+        Ok(outbox)
+    }
+    /// Gives the client `+o`, for `OPER` to call once it has checked the
+    /// client's credentials. Returns the `MODE` message to send the client,
+    /// or `None` if it was already an operator.
+    pub fn oper_up(&mut self) -> Result<Option<Message>, &'static str> {
+        if !self.modes.flags.insert(b'o') { return Ok(None) }
+        self.mode_message(&[(true, b'o')]).map(Some)
+    }
+    /// `ACCEPT nick,-nick,*`. A nick is added to the accept list; `-nick`
+    /// is removed from it; `*` lists it.
+    pub fn accept(&mut self, server: &[u8], mapping: CaseMapping,
+                  targets: &[u8]) -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        for target in targets.split(|x| *x == b',') {
+            if target.is_empty() { continue }
+            if target == b"*" {
+                for nick in self.modes.accept.iter() {
+                    queue_reply(server, self, RPL_ACCEPTLIST,
+                                &[nick.as_bytes()], &mut outbox)?;
+                }
+                queue_reply(server, self, RPL_ENDOFACCEPT,
+                            &[b"End of /ACCEPT list"], &mut outbox)?;
+                continue
+            }
+            let (removing, nick) = match target.split_first() {
+                Some((b'-', nick)) => (true, nick),
+                _ => (false, target),
+            };
+            let nick = match names::prepare_nick(nick, mapping) {
+                Ok(x) => Name::new(&x, mapping),
+                Err(_) => continue,
+            };
+            let existing = self.modes.accept.iter().position(|x| *x == nick);
+            let error: Option<(u32, &[u8])> = match (removing, existing) {
+                (true, Some(n)) => {
+                    self.modes.accept.remove(n);
+                    None
+                },
+                (true, None) => {
+                    Some((ERR_ACCEPTNOT, b"is not on your accept list"))
+                },
+                (false, Some(_)) => {
+                    Some((ERR_ACCEPTEXIST, b"is already on your accept list"))
+                },
+                (false, None) if self.modes.accept.len() >= MAX_ACCEPT_LEN => {
+                    Some((ERR_ACCEPTFULL, b"Accept list is full"))
+                },
+                (false, None) => {
+                    self.modes.accept.push(nick.clone());
+                    None
+                },
+            };
+            if let Some((numeric, text)) = error {
+                let params = if numeric == ERR_ACCEPTFULL { vec![text] }
+                else { vec![nick.as_bytes(), text] };
+                queue_reply(server, self, numeric, &params, &mut outbox)?;
+            }
+        }
+        Ok(outbox)
+    }
+    /// `RPL_WHOISBOT`, for `asker`'s `WHOIS` of this client, if it's a bot.
+    pub fn whois_bot_reply(&self, server: &[u8], asker: &User)
+                           -> Result<Option<Message>, &'static str> {
+        if !self.modes.is_set(b'B') { return Ok(None) }
+        reply(server, RPL_WHOISBOT, asker.nick.as_bytes(),
+              &[self.nick.as_bytes(), b"is a bot"]).map(Some)
+    }
+}
+
+/// Delivers a `PRIVMSG` or `NOTICE` from `sender` to `recipient`, unless the
+/// recipient's `+R` or `+g` refuses it. `NOTICE`s are refused silently,
+/// since they must never cause automatic replies.
+pub fn private_message(server: &[u8], sender: &User, recipient: &mut User,
+                       command: &[u8], text: &[u8], now: u64)
+                       -> Result<Outbox, &'static str> {
+    let mut outbox = Vec::new();
+    let notice = command == b"NOTICE";
+    let accepted = sender.nick == recipient.nick
+        || recipient.modes.accepts(&sender.nick);
+    if !accepted && recipient.modes.is_set(b'R') && sender.account.is_none() {
+        if !notice {
+            queue_reply(server, sender, ERR_NONONREG,
+                        &[recipient.nick.as_bytes(),
+                          b"You must log in to an account to message this \
+                            user"], &mut outbox)?;
+        }
+        return Ok(outbox)
+    }
+    if !accepted && recipient.modes.is_set(b'g') {
+        if notice { return Ok(outbox) }
+        queue_reply(server, sender, ERR_TARGUMODEG,
+                    &[recipient.nick.as_bytes(),
+                      b"is in +g mode (server-side ignore)"], &mut outbox)?;
+        let due = recipient.modes.last_caller_id_notice
+            .map(|x| now >= x + CALLER_ID_NOTICE_INTERVAL).unwrap_or(true);
+        if due {
+            recipient.modes.last_caller_id_notice = Some(now);
+            let mut userhost = sender.user.clone();
+            userhost.push(b'@');
+            userhost.extend_from_slice(&sender.host);
+            queue_reply(server, recipient, RPL_UMODEGMSG,
+                        &[sender.nick.as_bytes(), &userhost,
+                          b"is messaging you, and you have umode +g"],
+                        &mut outbox)?;
+            queue_reply(server, sender, RPL_TARGNOTIFY,
+                        &[recipient.nick.as_bytes(),
+                          b"has been informed that you messaged them"],
+                        &mut outbox)?;
+        }
+        return Ok(outbox)
+    }
+    let message = sender.message_to(recipient, command,
+                                    &[recipient.nick.as_bytes(), text])?;
+    outbox.push((recipient.nick.clone(), message));
+    Ok(outbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::fixtures::*;
+    const MAPPING: CaseMapping = CaseMapping::Rfc1459;
+    #[test]
+    fn advertising() {
+        let mut isupport = ISupport::new();
+        advertise(&mut isupport);
+        assert_eq!(isupport.get("CALLERID"), Some(Some(&b"g"[..])));
+        assert_eq!(isupport.get("DEAF"), Some(Some(&b"D"[..])));
+        let message = myinfo(SERVER, b"Fox", b"foxy-0.1",
+                             &Prefixes::parse("ov").unwrap()).unwrap();
+        assert_eq!(message.get_raw(),
+                   &b":irc.example.com 004 Fox irc.example.com foxy-0.1 \
+                      BDRgiow CIbeiklmnopstv Ibeklov\r\n"[..]);
+    }
+    #[test]
+    fn modes() {
+        let mut fox = user("Fox");
+        let mode = |fox: &mut User, target: &str, modes: Option<&str>| {
+            lines(fox.mode(SERVER, MAPPING, target.as_bytes(),
+                           modes.map(|x| x.as_bytes())).unwrap())
+        };
+        assert_eq!(mode(&mut fox, "fox", Some("+iwoX-R+B")), vec![
+            "Fox: :irc.example.com 501 Fox :Unknown MODE flag",
+            "Fox: :Fox!~u@host MODE Fox :+iwB",
+        ]);
+        assert_eq!(mode(&mut fox, "Fox", None), vec![
+            "Fox: :irc.example.com 221 Fox :+Biw",
+        ]);
+        assert_eq!(mode(&mut fox, "Vixen", Some("+i")), vec![
+            "Fox: :irc.example.com 502 Fox \
+             :Can't change mode for other users",
+        ]);
+        assert_eq!(fox.tags(), Some(&b"bot"[..]));
+        assert_eq!(fox.whois_bot_reply(SERVER, &user("Vixen")).unwrap()
+                   .unwrap().get_raw(),
+                   &b":irc.example.com 335 Vixen Fox :is a bot\r\n"[..]);
+        let oper = fox.oper_up().unwrap().unwrap();
+        assert_eq!(oper.get_raw(), &b":Fox!~u@host MODE Fox :+o\r\n"[..]);
+        assert!(fox.oper_up().unwrap().is_none());
+        assert_eq!(mode(&mut fox, "Fox", Some("-oB+i")), vec![
+            "Fox: :Fox!~u@host MODE Fox :-oB",
+        ]);
+        assert_eq!(fox.tags(), None);
+        assert!(fox.whois_bot_reply(SERVER, &fox).unwrap().is_none());
+    }
+    #[test]
+    fn accept_list() {
+        let mut fox = user("Fox");
+        let mut accept = |targets: &str| {
+            lines(fox.accept(SERVER, MAPPING, targets.as_bytes()).unwrap())
+        };
+        assert!(accept("Vixen,Wolf,-wolf").is_empty());
+        assert_eq!(accept("vixen,-Wolf,*"), vec![
+            "Fox: :irc.example.com 457 Fox vixen \
+             :is already on your accept list",
+            "Fox: :irc.example.com 458 Fox Wolf :is not on your accept list",
+            "Fox: :irc.example.com 281 Fox :Vixen",
+            "Fox: :irc.example.com 282 Fox :End of /ACCEPT list",
+        ]);
+        for n in 1 .. MAX_ACCEPT_LEN {
+            accept(&format!("fox{}", n));
+        }
+        assert_eq!(accept("Wolf"), vec![
+            "Fox: :irc.example.com 456 Fox :Accept list is full",
+        ]);
+        assert!(fox.modes.accepts(&Name::new(b"VIXEN", MAPPING)));
+    }
+    #[test]
+    fn private_messages() {
+        let (mut fox, mut vixen, wolf) = (user("Fox"), user("Vixen"),
+                                          user("Wolf"));
+        let send = |from: &User, to: &mut User, command: &str, now| {
+            lines(private_message(SERVER, from, to, command.as_bytes(),
+                                  b"hi", now).unwrap())
+        };
+        vixen.mode(SERVER, MAPPING, b"Vixen", Some(b"+B")).unwrap();
+        assert_eq!(send(&vixen, &mut fox, "PRIVMSG", 100), vec![
+            "Fox: :Vixen!~u@host PRIVMSG Fox :hi",
+        ]);
+        fox.message_tags = true;
+        assert_eq!(send(&vixen, &mut fox, "PRIVMSG", 100), vec![
+            "Fox: @bot :Vixen!~u@host PRIVMSG Fox :hi",
+        ]);
+        fox.mode(SERVER, MAPPING, b"Fox", Some(b"+R")).unwrap();
+        assert_eq!(send(&vixen, &mut fox, "PRIVMSG", 100), vec![
+            "Vixen: :irc.example.com 486 Vixen Fox \
+             :You must log in to an account to message this user",
+        ]);
+        assert!(send(&vixen, &mut fox, "NOTICE", 100).is_empty());
+        vixen.account = Some(b"vixen".to_vec());
+        assert_eq!(send(&vixen, &mut fox, "NOTICE", 100).len(), 1);
+        fox.mode(SERVER, MAPPING, b"Fox", Some(b"-R+g")).unwrap();
+        assert_eq!(send(&wolf, &mut fox, "PRIVMSG", 100), vec![
+            "Wolf: :irc.example.com 716 Wolf Fox \
+             :is in +g mode (server-side ignore)",
+            "Fox: :irc.example.com 718 Fox Wolf ~u@host \
+             :is messaging you, and you have umode +g",
+            "Wolf: :irc.example.com 717 Wolf Fox \
+             :has been informed that you messaged them",
+        ]);
+        // fox isn't told again for a while
+        assert_eq!(send(&vixen, &mut fox, "PRIVMSG", 130).len(), 1);
+        assert!(send(&wolf, &mut fox, "NOTICE", 130).is_empty());
+        assert_eq!(send(&wolf, &mut fox, "PRIVMSG", 160).len(), 3);
+        fox.accept(SERVER, MAPPING, b"wolf").unwrap();
+        assert_eq!(send(&wolf, &mut fox, "PRIVMSG", 160), vec![
+            "Fox: :Wolf!~u@host PRIVMSG Fox :hi",
+        ]);
+        // clients can always message themselves
+        let mut me = fox.clone();
+        assert_eq!(send(&me.clone(), &mut me, "PRIVMSG", 160).len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * This file is part of Foxy IRCd, copyright ©2020 Solra Bizna.
+ *
+ * Foxy IRCd is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free
+ * Software Foundation, either version 3 of the License, or (at your option)
+ * any later version.
+ *
+ * Foxy IRCd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * Foxy IRCd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Commands only operators (`+o`) may use.
+
+use std::path::Path;
+
+use super::*;
+use crate::channel::{queue_reply, Outbox};
+use numeric::*;
+
+impl User {
+    /// `SNAPSHOT`. Exports a consistent snapshot of the database to `path`,
+    /// as `db export` does, without stopping the server. Which file is up to
+    /// the server's configuration, not the operator; `None` means there
+    /// isn't one. The operator is told how it went.
+    pub async fn snapshot(&self, server: &[u8], db: &Db, path: Option<&Path>)
+                          -> Result<Outbox, &'static str> {
+        let mut outbox = Vec::new();
+        if !self.modes.is_set(b'o') {
+            queue_reply(server, self, ERR_NOPRIVILEGES,
+                        &[b"Permission Denied- You're not an IRC operator"],
+                        &mut outbox)?;
+            return Ok(outbox)
+        }
+        let text = match path {
+            None => "No snapshot file is configured".to_owned(),
+            Some(path) => {
+                match db.export(path, ArchiveFormat::from_path(path)).await {
+                    Ok(count) => format!("Wrote {} data to {:?}", count,
+                                         path),
+                    Err(x) => format!("Snapshot failed: {}", x),
+                }
+            },
+        };
+        outbox.push((self.nick.clone(), Message::assemble(
+            Some(&Source::Server { name: server }),
+            &Command::Textual(b"NOTICE"),
+            &[self.nick.as_bytes(), text.as_bytes()], true)?));
+        Ok(outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::fixtures::*;
+    use crate::db::tests::test_dir;
+    use serde_json::json;
+    #[tokio::test]
+    async fn snapshot() {
+        let dir = test_dir("oper_snapshot");
+        let db = Db::new(vec![dir.join("db")]);
+        db.insert("motd", json!("Welcome!")).await.unwrap();
+        let path = dir.join("backup.jsonl");
+        let mut fox = user("Fox");
+        assert_eq!(lines(fox.snapshot(SERVER, &db, Some(&path)).await
+                         .unwrap()), vec![
+            "Fox: :irc.example.com 481 Fox \
+             :Permission Denied- You're not an IRC operator",
+        ]);
+        assert!(!path.exists());
+        fox.oper_up().unwrap();
+        assert_eq!(lines(fox.snapshot(SERVER, &db, None).await.unwrap()),
+                   vec!["Fox: :irc.example.com NOTICE Fox \
+                         :No snapshot file is configured"]);
+        assert_eq!(lines(fox.snapshot(SERVER, &db, Some(&path)).await
+                         .unwrap()),
+                   vec![format!("Fox: :irc.example.com NOTICE Fox \
+                                 :Wrote 1 data to {:?}", path)]);
+        let data = decode_archive(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(data, vec![("motd".to_owned(), json!("Welcome!"))]);
+    }
+}